@@ -0,0 +1,263 @@
+use std::ops::Range;
+
+use crate::ledmap::{LedMap, LedPosition, LedStatus};
+
+/// How many robust standard deviations above the median a gap between neighbours may be.
+/// 3.5 is the usual cut-off for modified z-scores.
+pub const DEFAULT_OUTLIER_THRESHOLD: f64 = 3.5;
+
+/// An LED that is much further from both of its neighbours on the strip than LEDs usually are
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outlier {
+    pub index: usize,
+    /// Distance to the closest neighbour, per LED of index difference
+    pub spacing: f64,
+    /// What the LED was marked as
+    pub status: LedStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ContinuityReport {
+    /// Typical distance between neighbouring LEDs
+    pub median_spacing: f64,
+    /// Spacing above which a gap counts as an outlier
+    pub limit: f64,
+    pub outliers: Vec<Outlier>,
+}
+
+/// Indices of each of `num_strips` equally long strips, the last one taking any remainder
+pub fn strip_ranges(num_led: usize, num_strips: usize) -> Vec<Range<usize>> {
+    let num_strips = num_strips.clamp(1, num_led.max(1));
+    let leds_per_strip = num_led / num_strips;
+
+    (0..num_strips)
+        .map(|strip| {
+            let start = strip * leds_per_strip;
+            let end = if strip == num_strips - 1 {
+                num_led
+            } else {
+                start + leds_per_strip
+            };
+            start..end
+        })
+        .collect()
+}
+
+/// Checks the distance between neighbouring LEDs on each strip, and marks LEDs that are far from both of
+/// their neighbours for recalibration. LEDs that need recalibrating are skipped, with the gap to the next
+/// located LED spread over the LEDs in between. LEDs placed by hand are never marked.
+///
+/// Outliers in the front view are marked `RECALIBRATE-XY`. LEDs that only stand out once depth is included
+/// are marked `RECALIBRATE-Z`.
+pub fn check_continuity(
+    led_pos: &mut LedMap,
+    num_strips: usize,
+    threshold: f64,
+) -> ContinuityReport {
+    let strips = strip_ranges(led_pos.len(), num_strips);
+
+    let front =
+        |a: &LedPosition, b: &LedPosition| ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt();
+    let mut report = find_outliers(led_pos, &strips, threshold, LedStatus::has_xy, front);
+    for outlier in report.outliers.iter_mut() {
+        outlier.status = LedStatus::RecalibrateXy;
+        led_pos.leds[outlier.index].status = LedStatus::RecalibrateXy;
+    }
+
+    let depth = |a: &LedPosition, b: &LedPosition| {
+        ((a.x - b.x).powi(2) + (a.y - b.y).powi(2) + (a.z - b.z).powi(2)).sqrt()
+    };
+    let depth_report = find_outliers(led_pos, &strips, threshold, LedStatus::is_located, depth);
+    for mut outlier in depth_report.outliers {
+        // Without depth data the front view already said everything
+        if led_pos.leds[outlier.index].status != LedStatus::SuccessZ {
+            continue;
+        }
+        outlier.status = LedStatus::RecalibrateZ;
+        led_pos.leds[outlier.index].status = LedStatus::RecalibrateZ;
+        report.outliers.push(outlier);
+    }
+
+    report.outliers.sort_by_key(|outlier| outlier.index);
+    report
+}
+
+/// Estimates the position of LEDs that need recalibrating from the closest measured LEDs on either side of them
+/// on the strip, spacing them evenly by index. Only the missing axes are filled in, so `RECALIBRATE-Z` LEDs keep
+/// their x and y. Files without any depth only get x and y, and those LEDs are marked `RECALIBRATE-Z` instead of
+/// `INTERPOLATED`. LEDs at the end of a strip have nothing to interpolate between and are left alone.
+///
+/// Returns the indices that were estimated.
+pub fn interpolate_missing(led_pos: &mut LedMap, num_strips: usize) -> Vec<usize> {
+    let has_depth = led_pos.leds.iter().any(|led| led.status.has_z());
+    let mut interpolated = Vec::new();
+
+    for strip in strip_ranges(led_pos.len(), num_strips) {
+        // Estimates are never used to make more estimates
+        let measured = |led: &LedPosition, axis_found: fn(&LedStatus) -> bool| {
+            led.status != LedStatus::Interpolated && axis_found(&led.status)
+        };
+        let neighbours = |index: usize, axis_found: fn(&LedStatus) -> bool| {
+            let before = (strip.start..index)
+                .rev()
+                .find(|&i| measured(&led_pos.leds[i], axis_found))?;
+            let after = (index + 1..strip.end).find(|&i| measured(&led_pos.leds[i], axis_found))?;
+            Some((before, after))
+        };
+
+        let mut estimates = Vec::new();
+        for index in strip.clone() {
+            let status = led_pos.leds[index].status;
+            if status.is_located() {
+                continue;
+            }
+
+            let xy = if status.has_xy() {
+                None
+            } else {
+                match neighbours(index, LedStatus::has_xy) {
+                    Some(neighbours) => Some(neighbours),
+                    None => continue,
+                }
+            };
+            let z = if has_depth {
+                match neighbours(index, LedStatus::has_z) {
+                    Some(neighbours) => Some(neighbours),
+                    None => continue,
+                }
+            } else {
+                None
+            };
+
+            if xy.is_none() && z.is_none() {
+                continue;
+            }
+            estimates.push((index, xy, z));
+        }
+
+        for (index, xy, z) in estimates {
+            let mut confidence = f64::MAX;
+            let mut lerp = |(before, after): (usize, usize), axis: fn(&LedPosition) -> f64| {
+                let (a, b) = (&led_pos.leds[before], &led_pos.leds[after]);
+                let t = (index - before) as f64 / (after - before) as f64;
+                // Longer gaps are less certain
+                confidence =
+                    confidence.min(a.confidence.min(b.confidence) / (after - before) as f64);
+                axis(a) + (axis(b) - axis(a)) * t
+            };
+
+            let x = xy.map(|neighbours| lerp(neighbours, |led| led.x));
+            let y = xy.map(|neighbours| lerp(neighbours, |led| led.y));
+            let z = z.map(|neighbours| lerp(neighbours, |led| led.z));
+
+            let led = &mut led_pos.leds[index];
+            if let (Some(x), Some(y)) = (x, y) {
+                led.x = x;
+                led.y = y;
+            }
+            // Without depth only x and y were estimated, so z still needs a scan from the side
+            led.status = match z {
+                Some(z) => {
+                    led.z = z;
+                    LedStatus::Interpolated
+                }
+                None => LedStatus::RecalibrateZ,
+            };
+            led.confidence = if confidence == f64::MAX {
+                0.0
+            } else {
+                confidence
+            };
+            interpolated.push(index);
+        }
+    }
+
+    interpolated
+}
+
+fn find_outliers(
+    led_pos: &LedMap,
+    strips: &[Range<usize>],
+    threshold: f64,
+    usable: fn(&LedStatus) -> bool,
+    distance: impl Fn(&LedPosition, &LedPosition) -> f64,
+) -> ContinuityReport {
+    // (first LED, second LED, distance per LED between them)
+    let mut gaps = Vec::new();
+    for strip in strips {
+        let located: Vec<usize> = strip
+            .clone()
+            .filter(|&i| usable(&led_pos.leds[i].status))
+            .collect();
+
+        for pair in located.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            gaps.push((
+                a,
+                b,
+                distance(&led_pos.leds[a], &led_pos.leds[b]) / (b - a) as f64,
+            ));
+        }
+    }
+
+    if gaps.is_empty() {
+        return ContinuityReport::default();
+    }
+
+    let mut spacings: Vec<f64> = gaps.iter().map(|(_, _, spacing)| *spacing).collect();
+    let median_spacing = median(&mut spacings);
+    let mut deviations: Vec<f64> = spacings
+        .iter()
+        .map(|spacing| (spacing - median_spacing).abs())
+        .collect();
+    // The median absolute deviation is scaled to match a standard deviation. Perfectly even layouts have no
+    // deviation at all, so allow at least a tenth of the spacing.
+    let sigma = (1.4826 * median(&mut deviations)).max(median_spacing * 0.1);
+    let limit = median_spacing + threshold * sigma;
+
+    // Closest neighbour of each LED, and whether any of its gaps were short enough
+    let mut closest = vec![(f64::MAX, false); led_pos.len()];
+    for (a, b, spacing) in &gaps {
+        for led in [*a, *b] {
+            closest[led].0 = closest[led].0.min(*spacing);
+            closest[led].1 |= *spacing <= limit;
+        }
+    }
+
+    let outliers = closest
+        .iter()
+        .enumerate()
+        .filter(|(index, (spacing, has_close_neighbour))| {
+            *spacing != f64::MAX
+                && !has_close_neighbour
+                && !matches!(
+                    led_pos.leds[*index].status,
+                    LedStatus::ManualXy | LedStatus::ManualZ
+                )
+        })
+        .map(|(index, (spacing, _))| Outlier {
+            index,
+            spacing: *spacing,
+            status: led_pos.leds[index].status,
+        })
+        .collect();
+
+    ContinuityReport {
+        median_spacing,
+        limit,
+        outliers,
+    }
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let middle = values.len() / 2;
+
+    if values.is_empty() {
+        0.0
+    } else if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    }
+}
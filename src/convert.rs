@@ -0,0 +1,374 @@
+use std::{error::Error, ffi::OsStr, fmt::Write, fs, path::Path, str::FromStr};
+
+use log::warn;
+
+use crate::ledmap::LedMap;
+
+/// Magic bytes at the start of `ConvertTarget::Binary` files
+pub const BINARY_MAGIC: &[u8; 4] = b"SVLP";
+pub const BINARY_VERSION: u8 = 1;
+
+/// Firmware formats `svled convert-ledpos` can write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConvertTarget {
+    /// `static constexpr std::array<std::array<int16_t, 3>, N>`
+    Cpp,
+    /// A struct array kept in flash with `PROGMEM`
+    Arduino,
+    /// A FastLED-style `XYZ(x, y, z)` function returning the LED at a grid position
+    FastLed,
+    /// WLED's 2D `ledmap.json`, looking at the front of the container
+    Wled,
+    /// `BINARY_MAGIC`, `BINARY_VERSION`, a little endian u16 count and then x, y and z as little endian i16s
+    Binary,
+}
+
+impl ConvertTarget {
+    /// Targets that can't be printed to the terminal
+    pub fn is_binary(&self) -> bool {
+        *self == ConvertTarget::Binary
+    }
+
+    /// Picks the target from the extension of `path`, where it's unambiguous
+    pub fn from_path(path: &Path) -> Option<ConvertTarget> {
+        match path
+            .extension()
+            .and_then(OsStr::to_str)?
+            .to_lowercase()
+            .as_str()
+        {
+            "json" => Some(ConvertTarget::Wled),
+            "bin" => Some(ConvertTarget::Binary),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ConvertTarget {
+    type Err = String;
+
+    fn from_str(target: &str) -> Result<ConvertTarget, String> {
+        match target.to_lowercase().as_str() {
+            "cpp" => Ok(ConvertTarget::Cpp),
+            "arduino" => Ok(ConvertTarget::Arduino),
+            "fastled" => Ok(ConvertTarget::FastLed),
+            "wled" => Ok(ConvertTarget::Wled),
+            "binary" => Ok(ConvertTarget::Binary),
+            target => Err(format!(
+                "Unknown target {target}, expected cpp, arduino, fastled, wled or binary"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConvertOptions {
+    /// Scale every coordinate into this range, keeping the proportions of the container
+    pub normalize: Option<(i32, i32)>,
+    /// Name of the generated constant or function
+    pub name: String,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> ConvertOptions {
+        ConvertOptions {
+            normalize: None,
+            name: "coords".to_string(),
+        }
+    }
+}
+
+/// Parses a range like `0..255`
+pub fn parse_range(range: &str) -> Result<(i32, i32), String> {
+    let Some((min, max)) = range.split_once("..") else {
+        return Err(format!(
+            "Invalid range {range}, expected MIN..MAX, e.g. 0..255"
+        ));
+    };
+
+    let parse = |value: &str| {
+        value
+            .trim()
+            .parse::<i32>()
+            .map_err(|e| format!("Invalid range {range}: {e}"))
+    };
+    let (min, max) = (parse(min)?, parse(max)?);
+
+    if min >= max {
+        return Err(format!("Invalid range {range}, MIN has to be below MAX"));
+    }
+
+    Ok((min, max))
+}
+
+/// Rounds every position to whole numbers, optionally scaling them into `normalize` first. The longest side of
+/// the located LEDs spans the whole range, and the other axes are scaled by the same amount. LEDs that need
+/// recalibrating are clamped into the range.
+pub fn integer_positions(led_pos: &LedMap, normalize: Option<(i32, i32)>) -> Vec<[i32; 3]> {
+    let points = led_pos.leds.iter().map(|led| [led.x, led.y, led.z]);
+
+    let Some((min, max)) = normalize else {
+        return points
+            .map(|point| point.map(|value| value.round() as i32))
+            .collect();
+    };
+
+    let Some(bounds) = led_pos.bounds() else {
+        return vec![[min; 3]; led_pos.len()];
+    };
+    let longest = bounds.size().into_iter().fold(0.0, f64::max);
+    let scale = if longest > 0.0 {
+        (max - min) as f64 / longest
+    } else {
+        0.0
+    };
+
+    points
+        .map(|point| {
+            [0, 1, 2].map(|axis| {
+                let value = min as f64 + (point[axis] - bounds.min[axis]) * scale;
+                (value.round() as i32).clamp(min, max)
+            })
+        })
+        .collect()
+}
+
+/// Writes `led_pos` for `target`. Text targets are returned as UTF-8.
+pub fn convert_positions(
+    led_pos: &LedMap,
+    target: ConvertTarget,
+    options: &ConvertOptions,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let positions = integer_positions(led_pos, options.normalize);
+
+    if matches!(
+        target,
+        ConvertTarget::Cpp | ConvertTarget::Arduino | ConvertTarget::Binary
+    ) && let Some(position) = positions
+        .iter()
+        .flatten()
+        .find(|value| i16::try_from(**value).is_err())
+    {
+        return Err(format!(
+            "{position} does not fit in an int16_t, use --normalize to scale the positions into a smaller range"
+        )
+        .into());
+    }
+
+    let converted = match target {
+        ConvertTarget::Cpp => to_cpp(&positions, &options.name).into_bytes(),
+        ConvertTarget::Arduino => to_arduino(&positions, &options.name).into_bytes(),
+        ConvertTarget::FastLed => to_fastled(&positions, led_pos, &options.name)?.into_bytes(),
+        ConvertTarget::Wled => to_wled(&positions, led_pos, &options.name)?.into_bytes(),
+        ConvertTarget::Binary => to_binary(&positions)?,
+    };
+
+    Ok(converted)
+}
+
+pub fn write_convert_file(
+    led_pos: &LedMap,
+    target: ConvertTarget,
+    options: &ConvertOptions,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    fs::write(path, convert_positions(led_pos, target, options)?)
+        .map_err(|e| format!("Could not write {}: {e}", path.display()))?;
+
+    Ok(())
+}
+
+fn to_cpp(positions: &[[i32; 3]], name: &str) -> String {
+    let mut output = String::new();
+
+    output.push_str("#pragma once\n\n#include <array>\n#include <cstdint>\n\n");
+    let _ = writeln!(
+        output,
+        "static constexpr std::array<std::array<int16_t, 3>, {}> {name} = {{{{",
+        positions.len()
+    );
+    for [x, y, z] in positions {
+        let _ = writeln!(output, "    {{{x}, {y}, {z}}},");
+    }
+    output.push_str("}};\n");
+
+    output
+}
+
+fn to_arduino(positions: &[[i32; 3]], name: &str) -> String {
+    let mut output = String::new();
+
+    output.push_str("#pragma once\n\n#include <Arduino.h>\n\n");
+    output.push_str("struct LedCoord {\n    int16_t x;\n    int16_t y;\n    int16_t z;\n};\n\n");
+    let _ = writeln!(output, "const uint16_t {name}Count = {};", positions.len());
+    let _ = writeln!(output, "const LedCoord {name}[] PROGMEM = {{");
+    for [x, y, z] in positions {
+        let _ = writeln!(output, "    {{{x}, {y}, {z}}},");
+    }
+    output.push_str("};\n\n");
+    let _ = writeln!(
+        output,
+        "// Read entries with memcpy_P(&coord, &{name}[i], sizeof(LedCoord))"
+    );
+
+    output
+}
+
+/// Cells are capped so a forgotten `--normalize` doesn't produce a multi-gigabyte table
+const MAX_GRID_CELLS: usize = 1 << 20;
+
+/// Size of a grid along each axis, and the LED in each of its cells
+type Grid<const N: usize> = ([usize; N], Vec<Option<usize>>);
+
+/// Places the LEDs for which `used` is true on a grid starting at the smallest position of each of `axes`,
+/// returning its size and cells holding the index of the LED there, if any. When LEDs share a cell the first one
+/// wins.
+fn grid<const N: usize>(
+    positions: &[[i32; 3]],
+    used: impl Fn(usize) -> bool,
+    axes: [usize; N],
+) -> Result<Grid<N>, Box<dyn Error>> {
+    let placed: Vec<(usize, [i32; 3])> = positions
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|(index, _)| used(*index))
+        .collect();
+
+    let min = axes.map(|axis| {
+        placed
+            .iter()
+            .map(|(_, position)| position[axis])
+            .min()
+            .unwrap_or(0)
+    });
+    let max = axes.map(|axis| {
+        placed
+            .iter()
+            .map(|(_, position)| position[axis])
+            .max()
+            .unwrap_or(0)
+    });
+    let too_large = || -> Box<dyn Error> {
+        format!(
+            "A grid from {min:?} to {max:?} is too large, pass --normalize to scale the positions down, e.g. --normalize 0..15"
+        )
+        .into()
+    };
+
+    let mut size = [0; N];
+    for i in 0..N {
+        size[i] = max[i]
+            .checked_sub(min[i])
+            .and_then(|side| usize::try_from(side).ok())
+            .and_then(|side| side.checked_add(1))
+            .ok_or_else(too_large)?;
+    }
+    let cells = size
+        .iter()
+        .try_fold(1usize, |cells, side| cells.checked_mul(*side))
+        .filter(|cells| *cells <= MAX_GRID_CELLS)
+        .ok_or_else(too_large)?;
+
+    let mut grid = vec![None; cells];
+    let mut collisions = 0;
+    for (index, position) in placed {
+        let cell = (0..N).rev().fold(0, |cell, i| {
+            cell * size[i] + (position[axes[i]] - min[i]) as usize
+        });
+
+        match grid[cell] {
+            Some(_) => collisions += 1,
+            None => grid[cell] = Some(index),
+        }
+    }
+
+    if collisions > 0 {
+        warn!(
+            "{collisions} LEDs share a grid cell with another LED and were left out, try a larger --normalize range"
+        );
+    }
+
+    Ok((size, grid))
+}
+
+fn to_fastled(
+    positions: &[[i32; 3]],
+    led_pos: &LedMap,
+    name: &str,
+) -> Result<String, Box<dyn Error>> {
+    let located = |index: usize| led_pos.leds[index].status.is_located();
+    let ([width, height, depth], grid) = grid(positions, located, [0, 1, 2])?;
+    let count = positions.len();
+    let table = format!("{name}Table");
+
+    let mut output = String::new();
+
+    output.push_str("#pragma once\n\n#include <Arduino.h>\n\n");
+    let _ = writeln!(output, "#define XYZ_WIDTH {width}");
+    let _ = writeln!(output, "#define XYZ_HEIGHT {height}");
+    let _ = writeln!(output, "#define XYZ_DEPTH {depth}");
+    let _ = writeln!(output, "#define XYZ_NUM_LEDS {count}\n");
+    output.push_str(
+        "// LED at each grid cell, x first, then y, then z. XYZ_NUM_LEDS marks empty cells.\n",
+    );
+    let _ = writeln!(output, "const uint16_t {table}[] PROGMEM = {{");
+    for row in grid.chunks(width) {
+        let row: Vec<String> = row
+            .iter()
+            .map(|cell| cell.unwrap_or(count).to_string())
+            .collect();
+        let _ = writeln!(output, "    {},", row.join(", "));
+    }
+    output.push_str("};\n\n");
+
+    output.push_str("// Returns the LED at a grid position, or XYZ_NUM_LEDS when there is none\n");
+    output.push_str("inline uint16_t XYZ(uint16_t x, uint16_t y, uint16_t z) {\n");
+    output.push_str("    if (x >= XYZ_WIDTH || y >= XYZ_HEIGHT || z >= XYZ_DEPTH) {\n");
+    output.push_str("        return XYZ_NUM_LEDS;\n    }\n");
+    let _ = writeln!(
+        output,
+        "    return pgm_read_word(&{table}[((uint32_t)z * XYZ_HEIGHT + y) * XYZ_WIDTH + x]);"
+    );
+    output.push_str("}\n");
+
+    Ok(output)
+}
+
+fn to_wled(positions: &[[i32; 3]], led_pos: &LedMap, name: &str) -> Result<String, Box<dyn Error>> {
+    // The front view doesn't need depth
+    let located = |index: usize| led_pos.leds[index].status.has_xy();
+    let ([width, height], grid) = grid(positions, located, [0, 1])?;
+
+    // WLED counts rows from the top, while y points up
+    let map: Vec<i64> = grid
+        .chunks(width)
+        .rev()
+        .flatten()
+        .map(|cell| cell.map_or(-1, |index| index as i64))
+        .collect();
+
+    let json = serde_json::json!({
+        "n": name,
+        "width": width,
+        "height": height,
+        "map": map,
+    });
+
+    Ok(serde_json::to_string(&json)?)
+}
+
+fn to_binary(positions: &[[i32; 3]]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let count =
+        u16::try_from(positions.len()).map_err(|_| "The binary format holds at most 65535 LEDs")?;
+
+    let mut data = Vec::with_capacity(7 + positions.len() * 6);
+    data.extend_from_slice(BINARY_MAGIC);
+    data.push(BINARY_VERSION);
+    data.extend_from_slice(&count.to_le_bytes());
+    for value in positions.iter().flatten() {
+        data.extend_from_slice(&(*value as i16).to_le_bytes());
+    }
+
+    Ok(data)
+}
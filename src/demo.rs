@@ -7,9 +7,14 @@ use std::{
 use image::{GenericImageView, Pixel};
 use log::error;
 
-use crate::{ManagerData, PosEntry, led_manager, set_color};
-type LedPos = [(String, (i32, i32), (i32, i32))];
-type FilteredLeds<'a> = Vec<(usize, &'a (String, (i32, i32), (i32, i32)))>;
+use crate::{
+    ManagerData, led_manager,
+    ledmap::{LedMap, LedPosition},
+    spatial::SpatialIndex,
+    vled::Rgb,
+    voxel::{Sampling, VoxelGrid, VoxelMapping},
+};
+type FilteredLeds<'a> = Vec<(usize, &'a LedPosition)>;
 
 #[derive(Clone, Copy)]
 pub enum Axis {
@@ -18,29 +23,75 @@ pub enum Axis {
     Z,
 }
 
+/// Where an effect sends its colors, and the clock it waits on between steps
+pub trait LedOutput {
+    fn set_color(&mut self, n: u16, r: u8, g: u8, b: u8);
+
+    /// Waits before the next step of the effect. Returns false once the effect should stop.
+    fn wait(&mut self, delay: Duration) -> bool;
+}
+
+/// Drives the LED controller in real time
+pub struct LiveOutput<'a> {
+    pub manager: &'a Arc<Mutex<ManagerData>>,
+}
+
+impl LedOutput for LiveOutput<'_> {
+    fn set_color(&mut self, n: u16, r: u8, g: u8, b: u8) {
+        led_manager::set_color(self.manager, n, r, g, b);
+    }
+
+    fn wait(&mut self, delay: Duration) -> bool {
+        sleep(delay);
+        true
+    }
+}
+
 pub fn rainbow(
     manager: &Arc<Mutex<ManagerData>>,
-    led_pos: &PosEntry,
+    led_pos: &LedMap,
     step: i32,
     fuzz: i32,
     flip: bool,
     axis: Axis,
     clear: bool,
 ) {
+    rainbow_to(
+        &mut LiveOutput { manager },
+        led_pos,
+        step,
+        fuzz,
+        flip,
+        axis,
+        clear,
+    );
+}
+
+/// Same as `rainbow`, but drawing onto any output. Returns false if the output stopped the effect early.
+pub fn rainbow_to(
+    output: &mut impl LedOutput,
+    led_pos: &LedMap,
+    step: i32,
+    fuzz: i32,
+    flip: bool,
+    axis: Axis,
+    clear: bool,
+) -> bool {
     // Axis value accessor closure
-    let get_axis_value = |entry: &(String, (i32, i32), (i32, i32))| -> i32 {
+    let get_axis_value = |entry: &LedPosition| -> i32 {
         match axis {
-            Axis::X => entry.1.0,
-            Axis::Y => entry.1.1,
-            Axis::Z => entry.2.0,
+            Axis::X => entry.x,
+            Axis::Y => entry.y,
+            Axis::Z => entry.z,
         }
+        .round() as i32
     };
 
     // Determine highest and lowest bounds for the axis
     let mut highest = i32::MIN;
     let mut lowest = i32::MAX;
 
-    for entry in led_pos.iter().take(led_pos.len().saturating_sub(1)) {
+    for entry in led_pos.leds.iter().take(led_pos.len().saturating_sub(1)) {
         let val = get_axis_value(entry);
         if val > highest {
             highest = val;
@@ -56,18 +107,30 @@ pub fn rainbow(
         (lowest, highest, step)
     };
 
+    // LEDs whose rounded axis value is within fuzz of j, found without checking every LED each step
+    let index = SpatialIndex::new(led_pos);
+    let band = |j: i32| {
+        let (mut min, mut max) = ([f64::MIN; 3], [f64::MAX; 3]);
+        let axis = axis as usize;
+        min[axis] = (j - fuzz) as f64 - 0.5;
+        max[axis] = (j + fuzz) as f64 + 0.5;
+        index.within_box(min, max)
+    };
+
     // === RAINBOW FILL SWEEP ===
     while if z > 0 { j < end } else { j > end } {
-        for (i, entry) in led_pos.iter().enumerate() {
-            let val = get_axis_value(entry);
+        for i in band(j) {
+            let val = get_axis_value(&led_pos.leds[i]);
             if (j - fuzz) <= val && val <= (j + fuzz) {
                 let hue_pos = (val - lowest) as f32 / (highest - lowest) as f32;
                 let (r, g, b) = hsv_to_rgb(hue_pos, 1.0, 1.0);
-                led_manager::set_color(manager, i.try_into().unwrap(), r, g, b);
+                output.set_color(i.try_into().unwrap(), r, g, b);
             }
         }
 
-        sleep(Duration::from_millis(20));
+        if !output.wait(Duration::from_millis(20)) {
+            return false;
+        }
         j += z;
     }
 
@@ -84,26 +147,36 @@ pub fn rainbow(
         } else {
             j_clear > end_clear
         } {
-            for (i, entry) in led_pos.iter().enumerate() {
-                let val = get_axis_value(entry);
+            for i in band(j_clear) {
+                let val = get_axis_value(&led_pos.leds[i]);
                 if (j_clear - fuzz) <= val && val <= (j_clear + fuzz) {
-                    led_manager::set_color(manager, i.try_into().unwrap(), 0, 0, 0);
+                    output.set_color(i.try_into().unwrap(), 0, 0, 0);
                 }
             }
 
-            sleep(Duration::from_millis(20));
+            if !output.wait(Duration::from_millis(20)) {
+                return false;
+            }
             j_clear += z_clear;
         }
     }
+
+    true
 }
 
-pub fn rainbow_fill(manager: &Arc<Mutex<ManagerData>>, led_pos: &LedPos, axis: Axis, offset: i32) {
-    let get_axis_value = |entry: &(String, (i32, i32), (i32, i32))| -> i32 {
+pub fn rainbow_fill(
+    manager: &Arc<Mutex<ManagerData>>,
+    led_pos: &[LedPosition],
+    axis: Axis,
+    offset: i32,
+) {
+    let get_axis_value = |entry: &LedPosition| -> i32 {
         match axis {
-            Axis::X => entry.1.0,
-            Axis::Y => entry.1.1,
-            Axis::Z => entry.2.0,
+            Axis::X => entry.x,
+            Axis::Y => entry.y,
+            Axis::Z => entry.z,
         }
+        .round() as i32
     };
 
     let mut highest = i32::MIN;
@@ -129,6 +202,40 @@ pub fn rainbow_fill(manager: &Arc<Mutex<ManagerData>>, led_pos: &LedPos, axis: A
     }
 }
 
+/// Voxels along each axis of the plasma, fine enough for a smooth result once sampled trilinearly
+const PLASMA_GRID: [usize; 3] = [16, 16, 16];
+
+pub fn plasma(manager: &Arc<Mutex<ManagerData>>, led_pos: &LedMap) {
+    plasma_to(&mut LiveOutput { manager }, led_pos);
+}
+
+/// Flowing color drawn on a voxel grid stretched over the container and sampled onto the LEDs, until the
+/// output stops it
+pub fn plasma_to(output: &mut impl LedOutput, led_pos: &LedMap) {
+    let mapping = VoxelMapping::new(led_pos, PLASMA_GRID, Sampling::Trilinear);
+    let frame_interval = Duration::from_millis(33);
+    let mut time = 0.0_f32;
+
+    loop {
+        let grid: VoxelGrid<Rgb> = VoxelGrid::from_fn(PLASMA_GRID, |[x, y, z]| {
+            let [x, y, z] = [x, y, z].map(|value| value as f32 / PLASMA_GRID[0] as f32);
+            let value = (x * 6.0 + time).sin()
+                + (y * 5.0 - time * 1.3).sin()
+                + ((x + y + z) * 4.0 + time * 0.7).sin();
+            hsv_to_rgb(value / 6.0 + 0.5, 1.0, 1.0)
+        });
+
+        for (i, (r, g, b)) in mapping.sample(&grid).into_iter().enumerate() {
+            output.set_color(i.try_into().unwrap(), r, g, b);
+        }
+
+        if !output.wait(frame_interval) {
+            return;
+        }
+        time += frame_interval.as_secs_f32();
+    }
+}
+
 fn hsv_to_rgb(h: f32, s: f32, v: f32) -> (u8, u8, u8) {
     let h = h.fract() * 6.0; // keep hue in [0,6)
     let i = h.floor() as u32;
@@ -156,9 +263,24 @@ fn hsv_to_rgb(h: f32, s: f32, v: f32) -> (u8, u8, u8) {
 
 pub fn render_jpg_onto_leds(
     image_path: &str,
-    led_positions: &PosEntry,
+    led_positions: &LedMap,
     manager: &Arc<Mutex<ManagerData>>,
     z_range: Option<std::ops::RangeInclusive<i32>>, // ← now a range
+) {
+    render_jpg_to(
+        &mut LiveOutput { manager },
+        image_path,
+        led_positions,
+        z_range,
+    );
+}
+
+/// Same as `render_jpg_onto_leds`, but drawing onto any output
+pub fn render_jpg_to(
+    output: &mut impl LedOutput,
+    image_path: &str,
+    led_positions: &LedMap,
+    z_range: Option<std::ops::RangeInclusive<i32>>,
 ) {
     // Load JPG image
     let img = match image::ImageReader::open(image_path) {
@@ -180,10 +302,11 @@ pub fn render_jpg_onto_leds(
 
     // Filter positions based on z_range
     let filtered: FilteredLeds = led_positions
+        .leds
         .iter()
         .enumerate()
-        .filter(|(_, (_, _, (z, _)))| match &z_range {
-            Some(range) => range.contains(z),
+        .filter(|(_, led)| match &z_range {
+            Some(range) => range.contains(&(led.z.round() as i32)),
             None => true,
         })
         .collect();
@@ -195,23 +318,28 @@ pub fn render_jpg_onto_leds(
 
     // Compute 2D bounds from filtered LEDs
     let (min_x, max_x, min_y, max_y) = filtered.iter().fold(
-        (i32::MAX, i32::MIN, i32::MAX, i32::MIN),
-        |(min_x, max_x, min_y, max_y), &(_, (_, (x, y), _))| {
-            (min_x.min(*x), max_x.max(*x), min_y.min(*y), max_y.max(*y))
+        (f64::MAX, f64::MIN, f64::MAX, f64::MIN),
+        |(min_x, max_x, min_y, max_y), (_, led)| {
+            (
+                min_x.min(led.x),
+                max_x.max(led.x),
+                min_y.min(led.y),
+                max_y.max(led.y),
+            )
         },
     );
 
-    let width_range = (max_x - min_x).max(1) as f32;
-    let height_range = (max_y - min_y).max(1) as f32;
+    let width_range = (max_x - min_x).max(1.0) as f32;
+    let height_range = (max_y - min_y).max(1.0) as f32;
 
-    for (i, (_name, (x, y), _z)) in filtered.into_iter() {
-        let norm_x = ((*x - min_x) as f32 / width_range * (img_width - 1.0)).round() as u32;
-        let norm_y = ((*y - min_y) as f32 / height_range * (img_height - 1.0)).round() as u32;
+    for (i, led) in filtered.into_iter() {
+        let norm_x = ((led.x - min_x) as f32 / width_range * (img_width - 1.0)).round() as u32;
+        let norm_y = ((led.y - min_y) as f32 / height_range * (img_height - 1.0)).round() as u32;
 
         if norm_x < img.width() && norm_y < img.height() {
             let pixel = img.get_pixel(norm_x, norm_y).to_rgb();
             let [r, g, b] = pixel.0;
-            set_color(manager, i as u16, r, g, b);
+            output.set_color(i as u16, r, g, b);
         }
     }
 }
@@ -219,7 +347,7 @@ pub fn render_jpg_onto_leds(
 // pub fn render_jpg_sequence(
 //     dir_path: &str,
 //     prefix: &str, // e.g. "somename_"
-//     led_positions: &PosEntry,
+//     led_positions: &LedMap,
 //     manager: &Arc<Mutex<ManagerData>>,
 //     z_range: Option<std::ops::RangeInclusive<i32>>,
 // ) {
@@ -0,0 +1,212 @@
+use std::{error::Error, path::Path};
+
+use image::{Rgba, RgbaImage};
+
+use crate::{
+    ledmap::{LedMap, LedPosition, LedStatus},
+    merge::{self, Alignment},
+    preview::{self, BACKGROUND, OFF_COLOR, PreviewView},
+    transform,
+};
+
+/// How one LED differs between two position files
+#[derive(Debug, Clone, PartialEq)]
+pub struct LedDiff {
+    pub index: usize,
+    pub old_status: LedStatus,
+    pub new_status: LedStatus,
+    /// Distance the LED moved, if it was located in both files
+    pub displacement: Option<f64>,
+    /// Distance the LED moved once the movement of the whole container is taken out, if it was located in both
+    pub drift: Option<f64>,
+}
+
+impl LedDiff {
+    pub fn status_changed(&self) -> bool {
+        self.old_status != self.new_status
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionDiff {
+    /// Moves the new positions onto the old ones
+    pub alignment: Alignment,
+    /// How far the middle of the LEDs located in both files moved
+    pub offset: [f64; 3],
+    /// How far the container turned, in degrees
+    pub rotation: f64,
+    pub leds: Vec<LedDiff>,
+}
+
+impl PositionDiff {
+    pub fn status_changes(&self) -> impl Iterator<Item = &LedDiff> {
+        self.leds.iter().filter(|led| led.status_changed())
+    }
+
+    /// LEDs located in one file but not the other, as (found, lost)
+    pub fn located_changes(&self) -> (usize, usize) {
+        self.leds.iter().fold((0, 0), |(found, lost), led| {
+            match (led.old_status.has_xy(), led.new_status.has_xy()) {
+                (false, true) => (found + 1, lost),
+                (true, false) => (found, lost + 1),
+                _ => (found, lost),
+            }
+        })
+    }
+
+    pub fn max_drift(&self) -> f64 {
+        self.leds
+            .iter()
+            .filter_map(|led| led.drift)
+            .fold(0.0, f64::max)
+    }
+
+    pub fn mean_drift(&self) -> f64 {
+        let drifts: Vec<f64> = self.leds.iter().filter_map(|led| led.drift).collect();
+        if drifts.is_empty() {
+            0.0
+        } else {
+            drifts.iter().sum::<f64>() / drifts.len() as f64
+        }
+    }
+
+    /// Counts of drift in `bins` equal steps from 0 to the largest drift, as (from, to, count)
+    pub fn histogram(&self, bins: usize) -> Vec<(f64, f64, usize)> {
+        let bins = bins.max(1);
+        let width = self.max_drift() / bins as f64;
+
+        let mut counts = vec![0; bins];
+        for drift in self.leds.iter().filter_map(|led| led.drift) {
+            let bin = if width > 0.0 {
+                ((drift / width) as usize).min(bins - 1)
+            } else {
+                0
+            };
+            counts[bin] += 1;
+        }
+
+        counts
+            .into_iter()
+            .enumerate()
+            .map(|(bin, count)| (bin as f64 * width, (bin + 1) as f64 * width, count))
+            .collect()
+    }
+}
+
+/// Compares two position files of the same container. The new positions are lined up with the old ones the same
+/// way scans are merged, so a bumped container shows up as an offset and rotation rather than as drift.
+pub fn diff_positions(old: &LedMap, new: &LedMap) -> Result<PositionDiff, String> {
+    if old.len() != new.len() {
+        return Err(format!(
+            "Position files have to be of the same container, found {} and {} LEDs",
+            old.len(),
+            new.len()
+        ));
+    }
+    if old.metadata.units != new.metadata.units {
+        return Err(format!(
+            "Position files have to be in the same units, found {} and {}",
+            old.metadata.units, new.metadata.units
+        ));
+    }
+
+    let alignment = merge::align(new, old);
+
+    let mut leds = Vec::with_capacity(old.len());
+    let mut moved = [0.0; 3];
+    let mut shared = 0;
+    for (index, (a, b)) in old.leds.iter().zip(&new.leds).enumerate() {
+        let located = a.status.has_xy() && b.status.has_xy();
+        if located {
+            for (axis, value) in moved.iter_mut().enumerate() {
+                *value += [b.x, b.y, b.z][axis] - [a.x, a.y, a.z][axis];
+            }
+            shared += 1;
+        }
+
+        let aligned = transform::apply(&alignment.matrix, [b.x, b.y, b.z]);
+        leds.push(LedDiff {
+            index,
+            old_status: a.status,
+            new_status: b.status,
+            displacement: located.then(|| distance(a, b, [b.x, b.y, b.z])),
+            drift: located.then(|| distance(a, b, aligned)),
+        });
+    }
+
+    let matrix = alignment.matrix;
+    let trace = matrix[0][0] + matrix[1][1] + matrix[2][2];
+
+    Ok(PositionDiff {
+        offset: moved.map(|value| {
+            if shared > 0 {
+                value / shared as f64
+            } else {
+                0.0
+            }
+        }),
+        rotation: ((trace - 1.0) / 2.0).clamp(-1.0, 1.0).acos().to_degrees(),
+        alignment,
+        leds,
+    })
+}
+
+/// Draws the new positions from the front, green where an LED stayed put and red for the largest drift. LEDs
+/// missing from the old file are drawn grey, and ones the new file didn't locate are left out.
+pub fn write_diff_image(
+    new: &LedMap,
+    diff: &PositionDiff,
+    size: u32,
+    dot_radius: u32,
+    output: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let positions: Vec<(usize, [f64; 3])> = new
+        .located()
+        .map(|(index, led)| (index, [led.x, led.y, led.z]))
+        .collect();
+    if positions.is_empty() {
+        return Err("The new position file has no located LEDs to draw".into());
+    }
+
+    let size = size.max(16);
+    let max_drift = diff.max_drift();
+    let mut image = RgbaImage::from_pixel(size, size, BACKGROUND);
+
+    let mut dots = preview::project(&positions, PreviewView::Front, 0.0);
+    // Draw far away LEDs first so closer ones cover them
+    dots.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    for (index, _, (u, v)) in dots {
+        let (x, y) = (u * size as f64, v * size as f64);
+        match diff.leds[index].drift {
+            Some(drift) => {
+                let t = if max_drift > 0.0 {
+                    drift / max_drift
+                } else {
+                    0.0
+                };
+                let color = Rgba([(255.0 * t) as u8, (255.0 * (1.0 - t)) as u8, 0, 255]);
+                preview::draw_dot(&mut image, x, y, dot_radius, color);
+            }
+            None => preview::draw_dot(&mut image, x, y, 1, OFF_COLOR),
+        }
+    }
+
+    image.save(output)?;
+
+    Ok(())
+}
+
+/// Distance between `old` and `moved`, ignoring depth unless both files saw it
+fn distance(old: &LedPosition, new: &LedPosition, moved: [f64; 3]) -> f64 {
+    let axes = if old.status.has_z() && new.status.has_z() {
+        3
+    } else {
+        2
+    };
+
+    (0..axes)
+        .map(|axis| (moved[axis] - [old.x, old.y, old.z][axis]).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
@@ -0,0 +1,265 @@
+use std::{error::Error, ffi::OsStr, fmt::Write, fs, path::Path, str::FromStr};
+
+use crate::ledmap::{LedMap, LedPosition, LedStatus};
+
+/// Formats `svled export-pos` can write a position file to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One row per LED with a header, for spreadsheets and scripts
+    Csv,
+    /// ASCII PLY point cloud, e.g. for MeshLab or CloudCompare
+    Ply,
+    /// Wavefront OBJ with only vertices
+    Obj,
+    /// Houdini ASCII geometry
+    Geo,
+    /// A plain list of points, easy to read from Blender's Python console
+    Json,
+}
+
+impl ExportFormat {
+    /// Picks the format from the extension of `path`
+    pub fn from_path(path: &Path) -> Option<ExportFormat> {
+        path.extension()
+            .and_then(OsStr::to_str)
+            .and_then(|extension| extension.parse().ok())
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<ExportFormat, String> {
+        match format.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "ply" => Ok(ExportFormat::Ply),
+            "obj" => Ok(ExportFormat::Obj),
+            "geo" => Ok(ExportFormat::Geo),
+            "json" => Ok(ExportFormat::Json),
+            format => Err(format!(
+                "Unknown export format {format}, expected csv, ply, obj, geo or json"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExportOptions {
+    /// Add each LED's index on the strip as an attribute
+    pub index: bool,
+    /// Add each LED's scan status as an attribute
+    pub status: bool,
+    /// Leave out LEDs missing a coordinate instead of exporting them at their placeholder position
+    pub located_only: bool,
+}
+
+/// Every status in the order of `status_code`, for formats that can't store strings
+const STATUS_CODES: [LedStatus; 9] = [
+    LedStatus::Uncalibrated,
+    LedStatus::SuccessXy,
+    LedStatus::SuccessZ,
+    LedStatus::RecalibrateXy,
+    LedStatus::RecalibrateZ,
+    LedStatus::ManualXy,
+    LedStatus::ManualZ,
+    LedStatus::Interpolated,
+    LedStatus::Imported,
+];
+
+fn status_code(status: LedStatus) -> usize {
+    STATUS_CODES
+        .iter()
+        .position(|code| *code == status)
+        .unwrap_or(0)
+}
+
+/// Writes `led_pos` in `format`, one point per LED in strip order
+pub fn export_positions(led_pos: &LedMap, format: ExportFormat, options: &ExportOptions) -> String {
+    let points: Vec<(usize, &LedPosition)> = led_pos
+        .leds
+        .iter()
+        .enumerate()
+        .filter(|(_, led)| !options.located_only || led.status.is_located())
+        .collect();
+
+    match format {
+        ExportFormat::Csv => export_csv(&points, options),
+        ExportFormat::Ply => export_ply(&points, options),
+        ExportFormat::Obj => export_obj(&points, options),
+        ExportFormat::Geo => export_geo(&points, options),
+        ExportFormat::Json => export_json(led_pos, &points, options),
+    }
+}
+
+pub fn write_export_file(
+    led_pos: &LedMap,
+    format: ExportFormat,
+    options: &ExportOptions,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    fs::write(path, export_positions(led_pos, format, options))
+        .map_err(|e| format!("Could not write {}: {e}", path.display()))?;
+
+    Ok(())
+}
+
+fn export_csv(points: &[(usize, &LedPosition)], options: &ExportOptions) -> String {
+    let mut output = String::new();
+
+    let mut header = vec!["x", "y", "z"];
+    if options.index {
+        header.insert(0, "index");
+    }
+    if options.status {
+        header.push("status");
+    }
+    output.push_str(&header.join(","));
+    output.push('\n');
+
+    for (index, led) in points {
+        if options.index {
+            let _ = write!(output, "{index},");
+        }
+        let _ = write!(output, "{},{},{}", led.x, led.y, led.z);
+        if options.status {
+            let _ = write!(output, ",{}", led.status.as_str());
+        }
+        output.push('\n');
+    }
+
+    output
+}
+
+fn export_ply(points: &[(usize, &LedPosition)], options: &ExportOptions) -> String {
+    let mut output = String::new();
+
+    output.push_str("ply\nformat ascii 1.0\n");
+    let _ = writeln!(output, "comment svled {}", env!("CARGO_PKG_VERSION"));
+    if options.status {
+        // PLY has no string properties, so statuses are stored as their position in this list
+        let codes: Vec<String> = STATUS_CODES
+            .iter()
+            .enumerate()
+            .map(|(code, status)| format!("{code}={}", status.as_str()))
+            .collect();
+        let _ = writeln!(output, "comment status {}", codes.join(" "));
+    }
+    let _ = writeln!(output, "element vertex {}", points.len());
+    output.push_str("property double x\nproperty double y\nproperty double z\n");
+    if options.index {
+        output.push_str("property int index\n");
+    }
+    if options.status {
+        output.push_str("property uchar status\n");
+    }
+    output.push_str("end_header\n");
+
+    for (index, led) in points {
+        let _ = write!(output, "{} {} {}", led.x, led.y, led.z);
+        if options.index {
+            let _ = write!(output, " {index}");
+        }
+        if options.status {
+            let _ = write!(output, " {}", status_code(led.status));
+        }
+        output.push('\n');
+    }
+
+    output
+}
+
+fn export_obj(points: &[(usize, &LedPosition)], options: &ExportOptions) -> String {
+    let mut output = String::new();
+
+    let _ = writeln!(output, "# svled {}", env!("CARGO_PKG_VERSION"));
+    for (index, led) in points {
+        // OBJ vertices can't hold attributes, so they go in a comment above each one
+        let mut attributes = Vec::new();
+        if options.index {
+            attributes.push(format!("index {index}"));
+        }
+        if options.status {
+            attributes.push(format!("status {}", led.status.as_str()));
+        }
+        if !attributes.is_empty() {
+            let _ = writeln!(output, "# {}", attributes.join(" "));
+        }
+
+        let _ = writeln!(output, "v {} {} {}", led.x, led.y, led.z);
+    }
+
+    output
+}
+
+fn export_geo(points: &[(usize, &LedPosition)], options: &ExportOptions) -> String {
+    let mut output = String::new();
+
+    let attribute_count = options.index as usize + options.status as usize;
+
+    output.push_str("PGEOMETRY V5\n");
+    let _ = writeln!(output, "NPoints {} NPrims 0", points.len());
+    output.push_str("NPointGroups 0 NPrimGroups 0\n");
+    let _ = writeln!(
+        output,
+        "NPointAttrib {attribute_count} NVertexAttrib 0 NPrimAttrib 0 NAttrib 0"
+    );
+
+    if attribute_count > 0 {
+        output.push_str("PointAttrib\n");
+        if options.index {
+            output.push_str("index 1 int 0\n");
+        }
+        if options.status {
+            // Index attributes store a table of strings, and each point refers to one by position
+            let names: Vec<&str> = STATUS_CODES.iter().map(LedStatus::as_str).collect();
+            let _ = writeln!(output, "status 1 index {} {}", names.len(), names.join(" "));
+        }
+    }
+
+    for (index, led) in points {
+        let _ = write!(output, "{} {} {} 1", led.x, led.y, led.z);
+
+        if attribute_count > 0 {
+            let mut attributes = Vec::new();
+            if options.index {
+                attributes.push(index.to_string());
+            }
+            if options.status {
+                attributes.push(status_code(led.status).to_string());
+            }
+            let _ = write!(output, " ({})", attributes.join("\t"));
+        }
+        output.push('\n');
+    }
+
+    output.push_str("beginExtra\nendExtra\n");
+
+    output
+}
+
+fn export_json(
+    led_pos: &LedMap,
+    points: &[(usize, &LedPosition)],
+    options: &ExportOptions,
+) -> String {
+    let points: Vec<serde_json::Value> = points
+        .iter()
+        .map(|(index, led)| {
+            let mut point = serde_json::json!({ "position": [led.x, led.y, led.z] });
+            if options.index {
+                point["index"] = serde_json::json!(index);
+            }
+            if options.status {
+                point["status"] = serde_json::json!(led.status);
+            }
+            point
+        })
+        .collect();
+
+    let json = serde_json::json!({
+        "units": led_pos.metadata.units,
+        "points": points,
+    });
+
+    serde_json::to_string_pretty(&json).expect("Unable to serialize positions!")
+}
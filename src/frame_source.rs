@@ -0,0 +1,264 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use opencv::{
+    core::{self, CV_8UC3, Point, Scalar, Size},
+    imgproc::{self, FILLED, LINE_8},
+    prelude::*,
+    videoio::{CAP_PROP_FRAME_HEIGHT, CAP_PROP_FRAME_WIDTH, VideoCapture},
+};
+
+use crate::{led_manager::VirtualStrip, ledmap::LedMap, vled::Rgb};
+
+/// Where a scan gets its frames from. Live cameras, RTSP streams and video files are all `VideoCapture`s, while
+/// `ImageDirectory` and `SyntheticFrames` show whatever a `VirtualStrip` has lit, so scans can run without hardware.
+pub trait FrameSource: Send {
+    /// Reads the next frame into `frame`, returning false when there was none
+    fn read(&mut self, frame: &mut Mat) -> opencv::Result<bool>;
+
+    fn is_opened(&self) -> opencv::Result<bool>;
+
+    /// Width and height of the frames in pixels
+    fn frame_size(&self) -> opencv::Result<(i32, i32)>;
+
+    /// Asks for frames of a different size. Sources that can't change it keep their own.
+    fn set_frame_size(&mut self, _width: f64, _height: f64) -> opencv::Result<()> {
+        Ok(())
+    }
+
+    fn release(&mut self) -> opencv::Result<()> {
+        Ok(())
+    }
+}
+
+/// A frame source shared with the background thread that keeps it drained
+pub type SharedFrameSource = Arc<Mutex<Box<dyn FrameSource>>>;
+
+/// Opens the first camera, or the second one when `second_cam` is set
+pub type CameraOpener<'a> = &'a dyn Fn(bool) -> Result<Box<dyn FrameSource>, Box<dyn Error>>;
+
+impl FrameSource for VideoCapture {
+    fn read(&mut self, frame: &mut Mat) -> opencv::Result<bool> {
+        VideoCaptureTrait::read(self, frame)
+    }
+
+    fn is_opened(&self) -> opencv::Result<bool> {
+        VideoCaptureTraitConst::is_opened(self)
+    }
+
+    fn frame_size(&self) -> opencv::Result<(i32, i32)> {
+        Ok((
+            VideoCaptureTraitConst::get(self, CAP_PROP_FRAME_WIDTH)? as i32,
+            VideoCaptureTraitConst::get(self, CAP_PROP_FRAME_HEIGHT)? as i32,
+        ))
+    }
+
+    fn set_frame_size(&mut self, width: f64, height: f64) -> opencv::Result<()> {
+        VideoCaptureTrait::set(self, CAP_PROP_FRAME_WIDTH, width)?;
+        VideoCaptureTrait::set(self, CAP_PROP_FRAME_HEIGHT, height)?;
+        Ok(())
+    }
+
+    fn release(&mut self) -> opencv::Result<()> {
+        VideoCaptureTrait::release(self)
+    }
+}
+
+/// Frames recorded with one LED lit at a time, replayed for whichever LEDs `strip` has lit. The directory holds
+/// `dark.png`, taken with every LED off, and `<index>.png` for each LED, missing ones staying dark. When several
+/// LEDs are lit their frames are combined by keeping the brightest value of each pixel.
+pub struct ImageDirectory {
+    directory: PathBuf,
+    strip: Arc<VirtualStrip>,
+    dark: Mat,
+    /// The last frame and the LEDs it was made for, as decoding is far slower than scanning wants
+    shown: Option<(Vec<usize>, Mat)>,
+}
+
+impl ImageDirectory {
+    pub fn new(
+        directory: &Path,
+        strip: Arc<VirtualStrip>,
+    ) -> Result<ImageDirectory, Box<dyn Error>> {
+        let dark_path = directory.join("dark.png");
+        if !dark_path.is_file() {
+            return Err(format!("{} has no dark.png", directory.display()).into());
+        }
+
+        Ok(ImageDirectory {
+            directory: directory.to_path_buf(),
+            strip,
+            dark: read_image(&dark_path)?,
+            shown: None,
+        })
+    }
+
+    fn render(&self, lit: &[usize]) -> Result<Mat, Box<dyn Error>> {
+        let mut frame = self.dark.try_clone()?;
+        for index in lit {
+            let path = self.directory.join(format!("{index}.png"));
+            if !path.is_file() {
+                continue;
+            }
+
+            let led_frame = read_image(&path)?;
+            if led_frame.size()? != frame.size()? {
+                return Err(format!("{} is not the size of dark.png", path.display()).into());
+            }
+            let mut combined = Mat::default();
+            core::max(&frame, &led_frame, &mut combined)?;
+            frame = combined;
+        }
+        Ok(frame)
+    }
+}
+
+impl FrameSource for ImageDirectory {
+    fn read(&mut self, frame: &mut Mat) -> opencv::Result<bool> {
+        let lit: Vec<usize> = self
+            .strip
+            .lit()
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+
+        if !matches!(self.shown, Some((ref shown, _)) if *shown == lit) {
+            let rendered = self
+                .render(&lit)
+                .map_err(|e| opencv::Error::new(core::StsError, e.to_string()))?;
+            self.shown = Some((lit, rendered));
+        }
+
+        if let Some((_, ref shown)) = self.shown {
+            shown.copy_to(frame)?;
+        }
+        Ok(true)
+    }
+
+    fn is_opened(&self) -> opencv::Result<bool> {
+        Ok(true)
+    }
+
+    fn frame_size(&self) -> opencv::Result<(i32, i32)> {
+        Ok((self.dark.cols(), self.dark.rows()))
+    }
+}
+
+/// Draws every lit LED of `strip` as a soft dot of its color on a black frame, at the pixel a camera would see it
+/// at. Far from a real camera, but enough to check that a scan finds each LED where it should.
+pub struct SyntheticFrames {
+    strip: Arc<VirtualStrip>,
+    /// Where each LED shows up, None for LEDs the camera can't see
+    pixels: Vec<Option<(i32, i32)>>,
+    size: (i32, i32),
+    radius: i32,
+}
+
+impl SyntheticFrames {
+    pub fn new(
+        strip: Arc<VirtualStrip>,
+        pixels: Vec<Option<(i32, i32)>>,
+        size: (i32, i32),
+        radius: i32,
+    ) -> SyntheticFrames {
+        SyntheticFrames {
+            strip,
+            pixels,
+            size,
+            radius: radius.max(1),
+        }
+    }
+
+    /// Shows the LEDs where an unprocessed scan found them, at x and y for the first camera or z and y for the
+    /// second, so scanning the frames again should give back the same positions
+    pub fn from_scan(
+        strip: Arc<VirtualStrip>,
+        led_pos: &LedMap,
+        size: (i32, i32),
+        second_cam: bool,
+    ) -> SyntheticFrames {
+        let pixels = led_pos
+            .leds
+            .iter()
+            .map(|led| {
+                let found = if second_cam {
+                    led.status.has_z()
+                } else {
+                    led.status.has_xy()
+                };
+                let x = if second_cam { led.z } else { led.x };
+                found.then_some((x.round() as i32, led.y.round() as i32))
+            })
+            .collect();
+
+        SyntheticFrames::new(strip, pixels, size, 6)
+    }
+}
+
+impl FrameSource for SyntheticFrames {
+    fn read(&mut self, frame: &mut Mat) -> opencv::Result<bool> {
+        let mut drawn =
+            Mat::new_rows_cols_with_default(self.size.1, self.size.0, CV_8UC3, Scalar::all(0.0))?;
+
+        for (index, (r, g, b)) in self.strip.lit() {
+            if let Some(Some((x, y))) = self.pixels.get(index) {
+                imgproc::circle(
+                    &mut drawn,
+                    Point::new(*x, *y),
+                    self.radius,
+                    Scalar::new(b as f64, g as f64, r as f64, 255.0),
+                    FILLED,
+                    LINE_8,
+                    0,
+                )?;
+            }
+        }
+
+        let kernel = self.radius * 2 + 1;
+        imgproc::gaussian_blur(
+            &drawn,
+            frame,
+            Size::new(kernel, kernel),
+            0.0,
+            0.0,
+            core::BORDER_DEFAULT,
+            core::get_default_algorithm_hint()?,
+        )?;
+
+        Ok(true)
+    }
+
+    fn is_opened(&self) -> opencv::Result<bool> {
+        Ok(true)
+    }
+
+    fn frame_size(&self) -> opencv::Result<(i32, i32)> {
+        Ok(self.size)
+    }
+}
+
+/// Reads an image into a BGR frame, the way cameras deliver them
+pub fn read_image(path: &Path) -> Result<Mat, Box<dyn Error>> {
+    let image = image::open(path)
+        .map_err(|e| format!("Could not read {}: {e}", path.display()))?
+        .to_rgb8();
+
+    let mut frame = Mat::new_rows_cols_with_default(
+        image.height() as i32,
+        image.width() as i32,
+        CV_8UC3,
+        Scalar::all(0.0),
+    )?;
+    for (bgr, rgb) in frame
+        .data_bytes_mut()?
+        .chunks_exact_mut(3)
+        .zip(image.pixels())
+    {
+        let rgb: Rgb = (rgb[0], rgb[1], rgb[2]);
+        bgr.copy_from_slice(&[rgb.2, rgb.1, rgb.0]);
+    }
+    Ok(frame)
+}
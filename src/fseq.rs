@@ -0,0 +1,280 @@
+use std::{
+    error::Error,
+    fs,
+    io::Read,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use flate2::read::ZlibDecoder;
+use log::debug;
+
+use crate::{demo::LedOutput, render::FrameRenderer, vled::Vled};
+
+const FSEQ_MAGIC: &[u8; 4] = b"PSEQ";
+const FSEQ_HEADER_LEN: usize = 32;
+
+const COMPRESSION_NONE: u8 = 0;
+const COMPRESSION_ZSTD: u8 = 1;
+const COMPRESSION_ZLIB: u8 = 2;
+
+/// A FSEQ v2 sequence as used by xLights and Falcon Player, with every frame expanded to all of its channels
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fseq {
+    /// Time between frames in milliseconds
+    pub step_ms: u8,
+    pub channel_count: u32,
+    pub frames: Vec<Vec<u8>>,
+}
+
+impl Fseq {
+    pub fn parse(data: &[u8]) -> Result<Fseq, Box<dyn Error>> {
+        if data.len() < FSEQ_HEADER_LEN || &data[0..4] != FSEQ_MAGIC {
+            return Err("Not a FSEQ file".into());
+        }
+
+        let major_version = data[7];
+        if major_version != 2 {
+            return Err(format!(
+                "FSEQ v{major_version} is not supported, re-save the sequence as v2"
+            )
+            .into());
+        }
+
+        let channel_data_offset = read_u16(data, 4) as usize;
+        let channel_count = read_u32(data, 10);
+        let frame_count = read_u32(data, 14);
+        let step_ms = data[18];
+        let compression = data[20] & 0x0F;
+        let block_count = data[21] as usize | (((data[20] >> 4) as usize) << 8);
+        let sparse_range_count = data[22] as usize;
+
+        debug!(
+            "FSEQ v2.{}: {channel_count} channels, {frame_count} frames at {step_ms}ms, compression {compression}, {block_count} blocks, {sparse_range_count} sparse ranges",
+            data[6]
+        );
+
+        if step_ms == 0 {
+            return Err("FSEQ has a step time of 0ms".into());
+        }
+
+        let block_index_end = FSEQ_HEADER_LEN + block_count * 8;
+        let sparse_ranges_end = block_index_end + sparse_range_count * 6;
+        if data.len() < sparse_ranges_end || data.len() < channel_data_offset {
+            return Err("FSEQ header is truncated".into());
+        }
+
+        // Sparse files only store the listed ranges, one after the other
+        let sparse_ranges: Vec<(usize, usize)> = (0..sparse_range_count)
+            .map(|i| {
+                let offset = block_index_end + i * 6;
+                (
+                    read_u24(data, offset) as usize,
+                    read_u24(data, offset + 3) as usize,
+                )
+            })
+            .collect();
+
+        let stored = channel_count as usize * frame_count as usize;
+        let channel_data = match compression {
+            COMPRESSION_NONE => data[channel_data_offset..]
+                .get(..stored)
+                .ok_or("FSEQ channel data is truncated")?
+                .to_vec(),
+            COMPRESSION_ZLIB => {
+                let mut decompressed = Vec::with_capacity(stored);
+                let mut block_start = channel_data_offset;
+
+                for i in 0..block_count {
+                    let block_len = read_u32(data, FSEQ_HEADER_LEN + i * 8 + 4) as usize;
+                    if block_len == 0 {
+                        // Unused entries pad the index
+                        continue;
+                    }
+
+                    let block = data
+                        .get(block_start..block_start + block_len)
+                        .ok_or("FSEQ compression block is truncated")?;
+                    ZlibDecoder::new(block).read_to_end(&mut decompressed)?;
+                    block_start += block_len;
+                }
+
+                decompressed
+            }
+            COMPRESSION_ZSTD => {
+                return Err(
+                    "zstd compressed FSEQ files are not supported, export the sequence from xLights with zlib or no compression"
+                        .into(),
+                );
+            }
+            compression => {
+                return Err(format!("Unknown FSEQ compression type {compression}").into());
+            }
+        };
+
+        if channel_data.len() < stored {
+            return Err(format!(
+                "FSEQ should contain {stored} bytes of channel data, found {}",
+                channel_data.len()
+            )
+            .into());
+        }
+
+        let full_channel_count = sparse_ranges
+            .iter()
+            .map(|(start, count)| start + count)
+            .max()
+            .unwrap_or(channel_count as usize);
+
+        let frames = channel_data
+            .chunks_exact(channel_count.max(1) as usize)
+            .take(frame_count as usize)
+            .map(|stored_frame| {
+                if sparse_ranges.is_empty() {
+                    return stored_frame.to_vec();
+                }
+
+                let mut frame = vec![0; full_channel_count];
+                let mut stored_offset = 0;
+                for (start, count) in &sparse_ranges {
+                    let count = (*count).min(stored_frame.len() - stored_offset);
+                    frame[*start..start + count]
+                        .copy_from_slice(&stored_frame[stored_offset..stored_offset + count]);
+                    stored_offset += count;
+                }
+                frame
+            })
+            .collect();
+
+        Ok(Fseq {
+            step_ms,
+            channel_count: full_channel_count as u32,
+            frames,
+        })
+    }
+
+    /// Serialises the sequence as an uncompressed FSEQ v2.0 file, which Falcon Player can play directly
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut producer = format!("svled {}", env!("CARGO_PKG_VERSION")).into_bytes();
+        producer.push(0);
+
+        // Variable headers are a length (including this 4 byte prefix), a 2 character code and the data
+        let mut variable_headers = Vec::new();
+        variable_headers.extend_from_slice(&((producer.len() + 4) as u16).to_le_bytes());
+        variable_headers.extend_from_slice(b"sp");
+        variable_headers.extend_from_slice(&producer);
+
+        // Keep the channel data aligned, like xLights does
+        let channel_data_offset = (FSEQ_HEADER_LEN + variable_headers.len()).next_multiple_of(4);
+        let unique_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut data = Vec::with_capacity(
+            channel_data_offset + self.frames.len() * self.channel_count as usize,
+        );
+        data.extend_from_slice(FSEQ_MAGIC);
+        data.extend_from_slice(&(channel_data_offset as u16).to_le_bytes());
+        data.push(0); // Minor version
+        data.push(2); // Major version
+        data.extend_from_slice(&(FSEQ_HEADER_LEN as u16).to_le_bytes());
+        data.extend_from_slice(&self.channel_count.to_le_bytes());
+        data.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        data.push(self.step_ms);
+        data.push(0); // Flags
+        data.push(COMPRESSION_NONE);
+        data.push(0); // Compression blocks
+        data.push(0); // Sparse ranges
+        data.push(0); // Flags
+        data.extend_from_slice(&unique_id.to_le_bytes());
+        data.extend_from_slice(&variable_headers);
+        data.resize(channel_data_offset, 0);
+
+        for frame in &self.frames {
+            let mut frame = frame.clone();
+            frame.resize(self.channel_count as usize, 0);
+            data.extend_from_slice(&frame);
+        }
+
+        data
+    }
+
+    /// Turns the RGB channels starting at `start_channel` (counting from 0) into colors for `num_led` LEDs.
+    /// Only changes between frames are recorded, so static parts of a sequence stay small.
+    pub fn to_vled(&self, start_channel: usize, num_led: usize) -> Vled {
+        let step = Duration::from_millis(self.step_ms as u64);
+        let mut renderer = FrameRenderer::new(num_led, step, step * self.frames.len() as u32);
+
+        for frame in &self.frames {
+            for n in 0..num_led {
+                let channel = start_channel + n * 3;
+                if let Some(rgb) = frame.get(channel..channel + 3) {
+                    renderer.set_color(n as u16, rgb[0], rgb[1], rgb[2]);
+                }
+            }
+            renderer.wait(step);
+        }
+
+        renderer.finish()
+    }
+
+    /// Samples `vled` every `step_ms` onto RGB channels starting at `start_channel` (counting from 0).
+    /// When `track` is set, only that container of a multi-track recording is exported.
+    pub fn from_vled(
+        vled: &Vled,
+        num_led: usize,
+        step_ms: u8,
+        start_channel: usize,
+        track: Option<u8>,
+    ) -> Fseq {
+        let step = Duration::from_millis(step_ms.max(1) as u64);
+        let channel_count = start_channel + num_led * 3;
+
+        let frames = vled
+            .to_frames(num_led, step, track)
+            .into_iter()
+            .map(|frame| {
+                let mut channels = vec![0; start_channel];
+                channels.extend(frame.into_iter().flat_map(|(r, g, b)| [r, g, b]));
+                channels
+            })
+            .collect();
+
+        Fseq {
+            step_ms: step_ms.max(1),
+            channel_count: channel_count as u32,
+            frames,
+        }
+    }
+}
+
+pub fn read_fseq_file(path: &Path) -> Result<Fseq, Box<dyn Error>> {
+    let data = fs::read(path).map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+
+    Fseq::parse(&data).map_err(|e| format!("{}: {e}", path.display()).into())
+}
+
+pub fn write_fseq_file(fseq: &Fseq, path: &Path) -> Result<(), Box<dyn Error>> {
+    fs::write(path, fseq.to_bytes())
+        .map_err(|e| format!("Could not write {}: {e}", path.display()))?;
+
+    Ok(())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u24(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], 0])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
@@ -0,0 +1,285 @@
+use std::{error::Error, f64::consts::TAU, fs, path::Path};
+
+use crate::ledmap::{LedMap, LedMapMetadata, LedPosition, LedStatus};
+
+/// Builds where the position of every LED is known ahead of time, so no camera scan is needed
+#[derive(Debug, Clone, PartialEq)]
+pub enum Layout {
+    /// A matrix of `width` by `height` by `depth` LEDs, wired row by row and layer by layer from the bottom.
+    /// With `zigzag`, every other row runs back the way the previous one came.
+    Cube {
+        width: usize,
+        height: usize,
+        depth: usize,
+        spacing: f64,
+        zigzag: bool,
+    },
+    /// A strip wound around a cylinder, rising `pitch` every `leds_per_turn` LEDs
+    Helix {
+        num_led: usize,
+        radius: f64,
+        leds_per_turn: f64,
+        pitch: f64,
+    },
+    /// Flat rings of LEDs stacked `spacing` apart, each starting at the same angle
+    Rings {
+        rings: usize,
+        leds_per_ring: usize,
+        radius: f64,
+        spacing: f64,
+    },
+    /// Matrix panels of `width` by `height` LEDs with zigzag rows, stacked `panel_spacing` apart front to back
+    Serpentine {
+        width: usize,
+        height: usize,
+        panels: usize,
+        spacing: f64,
+        panel_spacing: f64,
+    },
+}
+
+impl Layout {
+    /// The position of every LED in strip order, with y pointing up
+    pub fn points(&self) -> Vec<[f64; 3]> {
+        match *self {
+            Layout::Cube {
+                width,
+                height,
+                depth,
+                spacing,
+                zigzag,
+            } => {
+                let mut points = Vec::with_capacity(width * height * depth);
+                for y in 0..height {
+                    for z in 0..depth {
+                        let row = y * depth + z;
+                        for x in 0..width {
+                            let x = if zigzag && row % 2 == 1 {
+                                width - 1 - x
+                            } else {
+                                x
+                            };
+                            points.push([
+                                x as f64 * spacing,
+                                y as f64 * spacing,
+                                z as f64 * spacing,
+                            ]);
+                        }
+                    }
+                }
+                points
+            }
+            Layout::Helix {
+                num_led,
+                radius,
+                leds_per_turn,
+                pitch,
+            } => (0..num_led)
+                .map(|i| {
+                    let turns = i as f64 / leds_per_turn;
+                    let (sin, cos) = (turns * TAU).sin_cos();
+                    [radius * cos, turns * pitch, radius * sin]
+                })
+                .collect(),
+            Layout::Rings {
+                rings,
+                leds_per_ring,
+                radius,
+                spacing,
+            } => (0..rings * leds_per_ring)
+                .map(|i| {
+                    let angle = (i % leds_per_ring) as f64 / leds_per_ring as f64 * TAU;
+                    let (sin, cos) = angle.sin_cos();
+                    [
+                        radius * cos,
+                        (i / leds_per_ring) as f64 * spacing,
+                        radius * sin,
+                    ]
+                })
+                .collect(),
+            Layout::Serpentine {
+                width,
+                height,
+                panels,
+                spacing,
+                panel_spacing,
+            } => {
+                let mut points = Vec::with_capacity(width * height * panels);
+                for panel in 0..panels {
+                    for y in 0..height {
+                        for x in 0..width {
+                            let x = if y % 2 == 1 { width - 1 - x } else { x };
+                            points.push([
+                                x as f64 * spacing,
+                                y as f64 * spacing,
+                                panel as f64 * panel_spacing,
+                            ]);
+                        }
+                    }
+                }
+                points
+            }
+        }
+    }
+
+    /// A position file for this layout, in the same format `scan` writes
+    pub fn to_led_map(&self, units: &str) -> LedMap {
+        known_positions(self.points(), units)
+    }
+}
+
+/// Positions that didn't come from a camera are marked `IMPORTED` with full confidence, so everything treats them
+/// as located
+fn known_positions(points: Vec<[f64; 3]>, units: &str) -> LedMap {
+    let mut map = LedMap::new(
+        points.len(),
+        LedMapMetadata {
+            units: units.to_string(),
+            ..Default::default()
+        },
+    );
+
+    for (led, [x, y, z]) in map.leds.iter_mut().zip(points) {
+        *led = LedPosition {
+            x,
+            y,
+            z,
+            status: LedStatus::Imported,
+            confidence: 1.0,
+        };
+    }
+
+    map
+}
+
+/// Reads a CSV of positions. With a header, the `x`, `y` and `z` columns are used, along with `index`,
+/// `status` and `confidence` when present. Without one, rows are either `x,y,z` or `index,x,y,z`.
+/// Indices missing from the file are left uncalibrated.
+/// Columns a CSV header can name
+const CSV_COLUMNS: [&str; 6] = ["x", "y", "z", "index", "status", "confidence"];
+
+pub fn parse_csv_positions(contents: &str, units: &str) -> Result<LedMap, Box<dyn Error>> {
+    let mut rows = contents
+        .lines()
+        .enumerate()
+        .map(|(line_number, line)| (line_number + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_number, line)| {
+            (
+                line_number,
+                line.split(',').map(str::trim).collect::<Vec<&str>>(),
+            )
+        })
+        .peekable();
+
+    let Some((first_line, first)) = rows.peek() else {
+        return Err("CSV has no positions".into());
+    };
+
+    let has_header = first
+        .iter()
+        .all(|field| CSV_COLUMNS.contains(&field.to_lowercase().as_str()));
+    if !has_header && first.iter().any(|field| field.parse::<f64>().is_err()) {
+        return Err(format!(
+            "line {first_line}: expected numbers, or a header using only the columns {}",
+            CSV_COLUMNS.join(", ")
+        )
+        .into());
+    }
+    let columns: Vec<String> = if has_header {
+        let (_, header) = rows.next().unwrap_or_default();
+        header.iter().map(|field| field.to_lowercase()).collect()
+    } else if first.len() >= 4 {
+        ["index", "x", "y", "z"].map(String::from).to_vec()
+    } else {
+        ["x", "y", "z"].map(String::from).to_vec()
+    };
+
+    let column = |name: &str| columns.iter().position(|column| column == name);
+    let (Some(x), Some(y), Some(z)) = (column("x"), column("y"), column("z")) else {
+        return Err("CSV needs x, y and z columns".into());
+    };
+    let (index, status, confidence) = (column("index"), column("status"), column("confidence"));
+
+    let mut leds: Vec<Option<LedPosition>> = Vec::new();
+    for (line_number, fields) in rows {
+        let field = |column: usize| -> Result<&str, Box<dyn Error>> {
+            fields
+                .get(column)
+                .copied()
+                .ok_or_else(|| format!("line {line_number}: missing {}", columns[column]).into())
+        };
+        let number = |column: usize| -> Result<f64, Box<dyn Error>> {
+            let value = field(column)?;
+            value.parse::<f64>().map_err(|e| {
+                format!(
+                    "line {line_number}: invalid {} {value}: {e}",
+                    columns[column]
+                )
+                .into()
+            })
+        };
+
+        let led = LedPosition {
+            x: number(x)?,
+            y: number(y)?,
+            z: number(z)?,
+            status: match status {
+                Some(status) => LedStatus::from_legacy(field(status)?),
+                None => LedStatus::Imported,
+            },
+            confidence: match confidence {
+                Some(confidence) => number(confidence)?,
+                None => 1.0,
+            },
+        };
+
+        let position = match index {
+            Some(index) => {
+                let value = field(index)?;
+                value
+                    .parse::<usize>()
+                    .map_err(|e| format!("line {line_number}: invalid index {value}: {e}"))?
+            }
+            None => leds.len(),
+        };
+        // LEDs are addressed with 16 bits
+        if position > u16::MAX as usize {
+            return Err(format!(
+                "line {line_number}: index {position} is above the highest addressable LED, {}",
+                u16::MAX
+            )
+            .into());
+        }
+
+        if position >= leds.len() {
+            leds.resize(position + 1, None);
+        }
+        if leds[position].is_some() {
+            return Err(format!("line {line_number}: index {position} appears twice").into());
+        }
+        leds[position] = Some(led);
+    }
+
+    let mut map = LedMap::new(
+        leds.len(),
+        LedMapMetadata {
+            units: units.to_string(),
+            ..Default::default()
+        },
+    );
+    for (led, position) in map.leds.iter_mut().zip(leds) {
+        if let Some(position) = position {
+            *led = position;
+        }
+    }
+
+    Ok(map)
+}
+
+pub fn read_csv_positions(path: &Path, units: &str) -> Result<LedMap, Box<dyn Error>> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+
+    parse_csv_positions(&contents, units).map_err(|e| format!("{}: {e}", path.display()).into())
+}
@@ -1,19 +1,71 @@
 use std::{
-    env,
-    io::{BufWriter, ErrorKind::WouldBlock, IoSlice, Write},
+    fmt::Debug,
+    io::{ErrorKind::WouldBlock, IoSlice, Write},
     net::UdpSocket,
-    path::{Path, PathBuf},
     process,
     sync::{Arc, Mutex, atomic::Ordering},
     thread,
-    time::{Duration, SystemTime},
+    time::Duration,
 };
 
 use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, bounded};
 use log::{debug, error, info, warn};
 use serialport::SerialPort;
 
-use crate::{LedConfig, LedState, Task, utils::ManagerData};
+use crate::{
+    LedConfig, LedState, Task,
+    recording::{self, RecordingFormat, RecordingSession},
+    utils::ManagerData,
+    vled::Rgb,
+};
+
+/// Receives colors in place of the controller, so scans and effects can run without hardware
+pub trait LedSink: Send + Sync + Debug {
+    /// Called with the physical index, after recording
+    fn set_color(&self, n: u16, r: u8, g: u8, b: u8);
+}
+
+/// A strip that only remembers the color of each LED, for frame sources that need to know what is lit
+#[derive(Debug, Default)]
+pub struct VirtualStrip {
+    colors: Mutex<Vec<Rgb>>,
+}
+
+impl VirtualStrip {
+    pub fn new(num_led: usize) -> VirtualStrip {
+        VirtualStrip {
+            colors: Mutex::new(vec![(0, 0, 0); num_led]),
+        }
+    }
+
+    pub fn colors(&self) -> Vec<Rgb> {
+        self.colors.lock().unwrap().clone()
+    }
+
+    /// Index and color of every LED that isn't off
+    pub fn lit(&self) -> Vec<(usize, Rgb)> {
+        self.colors
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter(|(_, color)| **color != (0, 0, 0))
+            .map(|(index, color)| (index, *color))
+            .collect()
+    }
+}
+
+impl LedSink for VirtualStrip {
+    fn set_color(&self, n: u16, r: u8, g: u8, b: u8) {
+        let mut colors = self.colors.lock().unwrap();
+        let n = n as usize;
+        // Scans clear one past the last LED
+        if n >= colors.len() {
+            colors.resize(n + 1, (0, 0, 0));
+        }
+        colors[n] = (r, g, b);
+    }
+}
 
 enum ConnectionType<'a> {
     Udp(&'a mut Option<UdpSocket>),
@@ -123,37 +175,51 @@ fn dispatch_threads(manager: &mut ManagerData) -> Vec<Sender<Task>> {
 pub fn set_color(manager_guard: &Arc<Mutex<ManagerData>>, n: u16, r: u8, g: u8, b: u8) {
     let mut manager = manager_guard.lock().unwrap();
 
-    let record_data;
-    let record_esp_data;
-
-    // Unity controls if we record commands to a file using a file in the tmp dir
-    if manager.config.unity_controls_recording {
-        let unity_start_anim_path: PathBuf = [env::temp_dir().to_str().unwrap(), "start_animate"]
-            .iter()
-            .collect();
-        record_data = Path::new(&unity_start_anim_path.into_os_string()).exists();
-
-        let unity_start_anim_byte_path: PathBuf =
-            [env::temp_dir().to_str().unwrap(), "start_animate_byte"]
-                .iter()
-                .collect();
-        record_esp_data = Path::new(&unity_start_anim_byte_path.into_os_string()).exists();
-    } else {
-        record_data = manager.config.record_data;
-        record_esp_data = manager.config.record_esp_data;
+    // Recording is switched on and off through recording::start_recording/stop_recording,
+    // or the RECORD commands in get_events, with the config only providing the initial state
+    let record_data = manager
+        .state
+        .recording_control
+        .record_data
+        .load(Ordering::Relaxed);
+    let record_esp_data = manager
+        .state
+        .recording_control
+        .record_esp_data
+        .load(Ordering::Relaxed);
+
+    // Effects, Unity and vled files use logical indices, while the strip and bvled files for the ESP need physical ones
+    let physical = manager
+        .config
+        .index_map
+        .as_ref()
+        .map_or(n, |index_map| index_map.to_physical(n));
+
+    // Slots may be shared with other containers when recording a multi-track file, so they are locked after the manager
+    let data_recording = Arc::clone(&manager.io.data_recording);
+    let esp_data_recording = Arc::clone(&manager.io.esp_data_recording);
+    let mut data_recording = data_recording.lock().unwrap();
+    let mut esp_data_recording = esp_data_recording.lock().unwrap();
+
+    // Takes are finished as soon as recording is switched off so they land on disk in one piece
+    if !record_data && let Some(session) = data_recording.take() {
+        recording::finish_recording(session);
     }
-
-    if manager.state.first_run {
-        manager.state.first_run = false;
-        manager.state.call_time = SystemTime::now();
+    if !record_esp_data && let Some(session) = esp_data_recording.take() {
+        recording::finish_recording(session);
     }
 
     // If we want to record data
     if record_data || record_esp_data {
-        if record_data && manager.io.data_file_buf.is_none() {
-            manager.io.data_file_buf = Some(BufWriter::new(
-                match crate::utils::check_and_create_file(&manager.config.record_data_file) {
-                    Ok(file) => file,
+        if record_data && data_recording.is_none() {
+            *data_recording = Some(
+                match RecordingSession::start(
+                    &manager.config.record_data_file,
+                    RecordingFormat::Vled,
+                    manager.config.timestamp_recordings,
+                    manager.config.num_led,
+                ) {
+                    Ok(session) => session,
                     Err(e) => {
                         panic!(
                             "Could not open {} for writing animation: {}",
@@ -162,75 +228,50 @@ pub fn set_color(manager_guard: &Arc<Mutex<ManagerData>>, n: u16, r: u8, g: u8,
                         );
                     }
                 },
-            ));
+            );
         }
-        if record_esp_data && manager.io.esp_data_file_buf.is_none() {
-            manager.io.esp_data_file_buf = Some(BufWriter::new(
-                match crate::utils::check_and_create_file(&manager.config.record_esp_data_file) {
-                    Ok(file) => file,
+        if record_esp_data && esp_data_recording.is_none() {
+            *esp_data_recording = Some(
+                match RecordingSession::start(
+                    &manager.config.record_esp_data_file,
+                    RecordingFormat::Bvled,
+                    manager.config.timestamp_recordings,
+                    manager.config.num_led,
+                ) {
+                    Ok(session) => session,
                     Err(e) => {
                         panic!(
                             "Could not open {} for writing animation: {}",
-                            manager.config.record_esp_data, e
+                            manager.config.record_esp_data_file.display(),
+                            e
                         )
                     }
                 },
-            ));
+            );
         }
-        let end = SystemTime::now();
-        match end.duration_since(manager.state.call_time) {
-            Ok(duration) => {
-                manager.state.call_time = SystemTime::now(); // Reset timer
-                if record_data {
-                    match manager.io.data_file_buf.as_mut() {
-                        Some(data_file_buf) => {
-                            let millis = duration.as_millis();
-                            if millis >= 3 {
-                                writeln!(data_file_buf, "T:{}", &millis.to_string())
-                                    .expect("Could not write to data_file_buf!");
-                            }
-                            writeln!(data_file_buf, "{n}|{r}|{g}|{b}")
-                                .expect("Could not write to data_file_buf!");
-                        }
-                        None => error!(
-                            "record_data is true, but data_file_buf is None! Something has gone very wrong, please report this."
-                        ),
-                    }
-                }
-                if record_esp_data {
-                    match manager.io.esp_data_file_buf.as_mut() {
-                        Some(esp_data_file_buf) => {
-                            let mut millis = duration.as_millis();
-
-                            while millis > 255 {
-                                // Delay marker + max duration
-                                write!(esp_data_file_buf, "0xFE, 0xFF, ")
-                                    .expect("Failed to write delay");
-                                millis -= 255;
-                            }
-                            if millis > 0 {
-                                write!(esp_data_file_buf, "0xFE, {millis:#04X}, ")
-                                    .expect("Failed to write delay");
-                            }
 
-                            let n_bytes = n.to_le_bytes();
-                            write!(
-                                esp_data_file_buf,
-                                "0x{0:02X}, 0x{1:02X}, 0x{2:02X}, 0x{3:02X}, 0x{4:02X}, ",
-                                n_bytes[0], n_bytes[1], r, g, b
-                            )
-                            .expect("Failed to write LED data");
-                        }
-                        None => error!(
-                            "record_esp_data is true, but esp_data_file_buf is None!, Something has gone very wrong, please report this."
-                        ),
-                    }
-                }
-            }
-            Err(e) => println!("Error: {e}"),
+        if record_data && let Some(session) = data_recording.as_mut() {
+            session
+                .record(manager.config.recording_track, n, r, g, b)
+                .expect("Could not write to the vled recording!");
+        }
+        if record_esp_data && let Some(session) = esp_data_recording.as_mut() {
+            session
+                .record(None, physical, r, g, b)
+                .expect("Could not write to the bvled recording!");
         }
     }
 
+    drop(data_recording);
+    drop(esp_data_recording);
+
+    let n = physical;
+
+    if let Some(ref led_sink) = manager.io.led_sink {
+        led_sink.set_color(n, r, g, b);
+        return;
+    }
+
     if let Some(use_queue) = manager.config.use_queue {
         if !use_queue {
             if manager.config.led_config.is_none() {
@@ -0,0 +1,358 @@
+use std::{error::Error, fs, path::Path};
+
+use chrono::Local;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{Config, PosEntry, transform::TransformRecord};
+
+/// Version of the position file format written by this build. Bump it when the format changes
+/// and teach `LedMap::from_json` how to read the old one.
+pub const LEDMAP_VERSION: u32 = 1;
+
+/// How a position came to be, and whether it can be trusted
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LedStatus {
+    /// Never seen by the scanner, the coordinates are meaningless
+    #[default]
+    #[serde(rename = "UNCALIBRATED")]
+    Uncalibrated,
+    /// Found by the front camera, no depth yet
+    #[serde(rename = "SUCCESS-XY")]
+    SuccessXy,
+    /// Found by both cameras
+    #[serde(rename = "SUCCESS-Z")]
+    SuccessZ,
+    /// Too dim to be found by the front camera
+    #[serde(rename = "RECALIBRATE-XY")]
+    RecalibrateXy,
+    /// Too dim to be found by the depth camera
+    #[serde(rename = "RECALIBRATE-Z")]
+    RecalibrateZ,
+    /// Picked by hand on the front camera
+    #[serde(rename = "MANUAL-XY")]
+    ManualXy,
+    /// Picked by hand on the depth camera
+    #[serde(rename = "MANUAL-Z")]
+    ManualZ,
+    /// Not found, estimated from its neighbours on the strip
+    #[serde(rename = "INTERPOLATED")]
+    Interpolated,
+    /// Read from a position file or generated from a layout, rather than scanned
+    #[serde(rename = "IMPORTED")]
+    Imported,
+}
+
+impl LedStatus {
+    /// Reads the free-form status strings of legacy position files
+    pub fn from_legacy(status: &str) -> LedStatus {
+        match status {
+            "UNCALIBRATED" => LedStatus::Uncalibrated,
+            "SUCCESS-XY" => LedStatus::SuccessXy,
+            "SUCCESS-Z" => LedStatus::SuccessZ,
+            "RECALIBRATE-XY" => LedStatus::RecalibrateXy,
+            "RECALIBRATE-Z" => LedStatus::RecalibrateZ,
+            "MANUAL-XY" => LedStatus::ManualXy,
+            "MANUAL-Z" => LedStatus::ManualZ,
+            "INTERPOLATED" => LedStatus::Interpolated,
+            "IMPORTED" => LedStatus::Imported,
+            status => {
+                warn!("Unknown LED status {status}, treating it as uncalibrated");
+                LedStatus::Uncalibrated
+            }
+        }
+    }
+
+    /// The name used in position files, e.g. "SUCCESS-Z"
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LedStatus::Uncalibrated => "UNCALIBRATED",
+            LedStatus::SuccessXy => "SUCCESS-XY",
+            LedStatus::SuccessZ => "SUCCESS-Z",
+            LedStatus::RecalibrateXy => "RECALIBRATE-XY",
+            LedStatus::RecalibrateZ => "RECALIBRATE-Z",
+            LedStatus::ManualXy => "MANUAL-XY",
+            LedStatus::ManualZ => "MANUAL-Z",
+            LedStatus::Interpolated => "INTERPOLATED",
+            LedStatus::Imported => "IMPORTED",
+        }
+    }
+
+    pub fn needs_recalibration(&self) -> bool {
+        matches!(
+            self,
+            LedStatus::Uncalibrated | LedStatus::RecalibrateXy | LedStatus::RecalibrateZ
+        )
+    }
+
+    /// Whether all three coordinates can be used, front view only statuses leave z at a placeholder
+    pub fn is_located(&self) -> bool {
+        self.has_xy() && self.has_z()
+    }
+
+    /// Whether x and y hold a position, even if z doesn't
+    pub fn has_xy(&self) -> bool {
+        !matches!(self, LedStatus::Uncalibrated | LedStatus::RecalibrateXy)
+    }
+
+    /// Whether z holds a position
+    pub fn has_z(&self) -> bool {
+        matches!(
+            self,
+            LedStatus::SuccessZ
+                | LedStatus::ManualZ
+                | LedStatus::Interpolated
+                | LedStatus::Imported
+        )
+    }
+
+    pub fn is_success(&self) -> bool {
+        matches!(self, LedStatus::SuccessXy | LedStatus::SuccessZ)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct LedPosition {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub status: LedStatus,
+    /// How sure the scanner was about this position, from 0 to 1
+    #[serde(default)]
+    pub confidence: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min: [f64; 3],
+    pub max: [f64; 3],
+}
+
+impl Bounds {
+    pub fn size(&self) -> [f64; 3] {
+        [
+            self.max[0] - self.min[0],
+            self.max[1] - self.min[1],
+            self.max[2] - self.min[2],
+        ]
+    }
+
+    pub fn center(&self) -> [f64; 3] {
+        [
+            (self.min[0] + self.max[0]) / 2.0,
+            (self.min[1] + self.max[1]) / 2.0,
+            (self.min[2] + self.max[2]) / 2.0,
+        ]
+    }
+}
+
+/// The camera setup a scan was made with
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct CameraMetadata {
+    pub multi_camera: bool,
+    pub camera_index_1: String,
+    pub camera_index_2: Option<String>,
+    pub video_width: Option<f64>,
+    pub video_height: Option<f64>,
+    pub cam2_overhead: bool,
+    pub cam2_overhead_flip: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LedMapMetadata {
+    /// Unit of x, y and z. Scans are in camera pixels ("px").
+    pub units: String,
+    /// Bounds of every located LED, refreshed whenever the file is written
+    pub bounds: Option<Bounds>,
+    pub camera: Option<CameraMetadata>,
+    /// When the scan was made, in RFC 3339
+    pub scan_date: Option<String>,
+    /// Set when the file was converted from an older format
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migrated_from: Option<String>,
+    /// Position files `svled merge-pos` combined into this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merged_from: Vec<String>,
+    /// Every `svled transform-pos` run on this file, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transforms: Vec<TransformRecord>,
+}
+
+impl Default for LedMapMetadata {
+    fn default() -> LedMapMetadata {
+        LedMapMetadata {
+            units: "px".to_string(),
+            bounds: None,
+            camera: None,
+            scan_date: None,
+            migrated_from: None,
+            merged_from: Vec::new(),
+            transforms: Vec::new(),
+        }
+    }
+}
+
+impl LedMapMetadata {
+    /// Metadata for a scan starting now with `config`'s cameras
+    pub fn for_scan(config: &Config) -> LedMapMetadata {
+        LedMapMetadata {
+            camera: Some(CameraMetadata {
+                multi_camera: config.camera.multi_camera,
+                camera_index_1: config.camera.camera_index_1.clone(),
+                camera_index_2: config.camera.camera_index_2.clone(),
+                video_width: config.camera.video_width,
+                video_height: config.camera.video_height,
+                cam2_overhead: config.advanced.camera.cam2_overhead.unwrap_or(false),
+                cam2_overhead_flip: config.advanced.camera.cam2_overhead_flip.unwrap_or(false),
+            }),
+            scan_date: Some(Local::now().to_rfc3339()),
+            ..Default::default()
+        }
+    }
+}
+
+/// A position file: where every LED of a container is, indexed by its position on the strip
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LedMap {
+    pub version: u32,
+    pub metadata: LedMapMetadata,
+    pub leds: Vec<LedPosition>,
+}
+
+impl LedMap {
+    /// A map of `num_led` uncalibrated LEDs
+    pub fn new(num_led: usize, metadata: LedMapMetadata) -> LedMap {
+        LedMap {
+            version: LEDMAP_VERSION,
+            metadata,
+            leds: vec![LedPosition::default(); num_led],
+        }
+    }
+
+    /// Reads a position file of any version, migrating older formats
+    pub fn from_json(contents: &str) -> Result<LedMap, Box<dyn Error>> {
+        let value: serde_json::Value = serde_json::from_str(contents)?;
+
+        if value.is_array() {
+            // Before versioning, position files were a bare array of (status, (x, y), (z, y))
+            let legacy: PosEntry = serde_json::from_value(value)?;
+            return Ok(LedMap::from_legacy(&legacy));
+        }
+
+        let version = value
+            .get("version")
+            .and_then(serde_json::Value::as_u64)
+            .ok_or("position file has no version")?;
+        if version > LEDMAP_VERSION as u64 {
+            return Err(format!(
+                "position file is version {version}, but this build of svled only understands up to version {LEDMAP_VERSION}"
+            )
+            .into());
+        }
+
+        Ok(serde_json::from_value(value)?)
+    }
+
+    pub fn from_legacy(legacy: &PosEntry) -> LedMap {
+        let leds = legacy
+            .iter()
+            .map(|(status, (x, y), (z, _))| {
+                let status = LedStatus::from_legacy(status);
+                // Missing coordinates used to be stored as i32::MIN
+                let coordinate = |value: i32| if value == i32::MIN { 0.0 } else { value as f64 };
+
+                LedPosition {
+                    x: coordinate(*x),
+                    y: coordinate(*y),
+                    z: coordinate(*z),
+                    status,
+                    confidence: if status.needs_recalibration() {
+                        0.0
+                    } else {
+                        1.0
+                    },
+                }
+            })
+            .collect();
+
+        let mut map = LedMap {
+            version: LEDMAP_VERSION,
+            metadata: LedMapMetadata {
+                migrated_from: Some("legacy".to_string()),
+                ..Default::default()
+            },
+            leds,
+        };
+        map.metadata.bounds = map.bounds();
+        map
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut map = self.clone();
+        map.version = LEDMAP_VERSION;
+        map.metadata.bounds = map.bounds();
+
+        serde_json::to_string_pretty(&map).expect("Unable to serialize positions!")
+    }
+
+    pub fn len(&self) -> usize {
+        self.leds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leds.is_empty()
+    }
+
+    /// Indices and positions of every LED whose three coordinates can be used
+    pub fn located(&self) -> impl Iterator<Item = (usize, &LedPosition)> {
+        self.leds
+            .iter()
+            .enumerate()
+            .filter(|(_, led)| led.status.is_located())
+    }
+
+    /// Bounds of every located LED, or None if there are none
+    pub fn bounds(&self) -> Option<Bounds> {
+        self.located().fold(None, |bounds, (_, led)| {
+            let point = [led.x, led.y, led.z];
+            Some(match bounds {
+                None => Bounds {
+                    min: point,
+                    max: point,
+                },
+                Some(Bounds { min, max }) => Bounds {
+                    min: [0, 1, 2].map(|axis| min[axis].min(point[axis])),
+                    max: [0, 1, 2].map(|axis| max[axis].max(point[axis])),
+                },
+            })
+        })
+    }
+}
+
+pub fn read_led_map(path: &Path) -> Result<LedMap, Box<dyn Error>> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+
+    let map = LedMap::from_json(&contents).map_err(|e| {
+        format!(
+            "{} contains invalid or incomplete calibration data: {e}",
+            path.display()
+        )
+    })?;
+
+    if contents.trim_start().starts_with('[') {
+        info!(
+            "{} uses the legacy position format, it will be saved in the version {LEDMAP_VERSION} format next time it is written",
+            path.display()
+        );
+    }
+
+    Ok(map)
+}
+
+pub fn write_led_map(map: &LedMap, path: &Path) -> Result<(), Box<dyn Error>> {
+    fs::write(path, map.to_json())
+        .map_err(|e| format!("Could not write {}: {e}", path.display()))?;
+
+    Ok(())
+}
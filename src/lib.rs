@@ -1,13 +1,39 @@
 #[cfg(feature = "scan")]
+pub mod frame_source;
+#[cfg(feature = "scan")]
 pub mod scan;
+#[cfg(feature = "scan")]
+pub mod scan_ui;
+#[cfg(feature = "scan")]
+pub mod simulate;
+#[cfg(feature = "scan")]
+pub mod structured;
 
+pub mod analysis;
+pub mod convert;
 pub mod demo;
+pub mod diff;
 pub mod driver_wizard;
+pub mod export;
+pub mod fseq;
+pub mod import;
 pub mod led_manager;
+pub mod ledmap;
+pub mod merge;
+pub mod perspective;
+pub mod preview;
 pub mod read_vled;
+pub mod recording;
+pub mod remap;
+pub mod render;
+pub mod scene;
+pub mod spatial;
 pub mod speedtest;
+pub mod transform;
 pub mod unity;
 pub mod utils;
+pub mod vled;
+pub mod voxel;
 
 pub use demo::rainbow;
 pub use driver_wizard::wizard;
@@ -1,29 +1,40 @@
 use std::{
+    error::Error,
     fs::File,
-    io::{Read, Write},
+    io::Write,
     path::{Path, PathBuf},
     process,
     sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
 };
 
 use env_logger::Builder;
 use gumdrop::Options;
-use log::{debug, error, info, LevelFilter};
+use log::{debug, error, info, warn, LevelFilter};
 use opencv::{
     core::{Mat, MatTraitConst},
     videoio::{self, VideoCaptureTrait, VideoCaptureTraitConst},
 };
-#[cfg(feature = "scan")]
-use svled::scan;
 use svled::{
+    analysis, convert,
     demo::{self, render_jpg_onto_leds},
-    driver_wizard,
+    diff, driver_wizard, export, fseq, import,
     led_manager::{self, set_color},
-    read_vled,
+    ledmap::{self, LedMap},
+    merge, preview, read_vled, recording, remap, render,
     scan::position_adjustment,
-    speedtest,
+    scene, speedtest, transform,
     unity::{self, start_listeners},
-    utils, PosEntry,
+    utils,
+    vled::{self, Vled, VledEventKind},
+};
+#[cfg(feature = "scan")]
+use svled::{
+    frame_source::{FrameSource, ImageDirectory},
+    led_manager::VirtualStrip,
+    scan,
+    scan_ui::HeadlessUi,
+    simulate::{self, Simulator, VirtualCamera},
 };
 
 #[derive(Debug, Options)]
@@ -58,6 +69,10 @@ enum Command {
     #[options(help = "calibrate a svled container")]
     Calibrate(CalibrateOptions),
 
+    #[cfg(feature = "scan")]
+    #[options(help = "scan a known layout through simulated cameras and measure the error")]
+    Simulate(SimulateOptions),
+
     #[options(help = "send positions and connect to Unity")]
     Unity(UnityCommandOptions),
 
@@ -79,14 +94,49 @@ enum Command {
     #[options(help = "run a simple demo")]
     Demo(DemoOptions),
 
-    #[options(help = "convert an led position json into a C++ compatible constant")]
+    #[options(
+        help = "convert an led position json for C++, Arduino, FastLED, WLED or a binary file"
+    )]
     ConvertLedpos(ConvertLedposOptions),
 
+    #[options(help = "export a position file to CSV, PLY, OBJ, Houdini geo or JSON")]
+    ExportPos(ExportPosOptions),
+
+    #[options(help = "create a position file from a CSV or a known layout instead of scanning")]
+    ImportPos(ImportPosOptions),
+
     #[options(help = "list functioning camera indexes")]
     ListCams(ListCamsOptions),
 
+    #[options(help = "find LEDs that are far from their neighbours on the strip")]
+    CheckPos(CheckPosOptions),
+
+    #[options(help = "rotate, move, scale, mirror or recenter a position file")]
+    TransformPos(TransformPosOptions),
+
+    #[options(help = "merge several scans of the same container into one position file")]
+    MergePos(MergePosOptions),
+
+    #[options(help = "compare two position files of the same container")]
+    DiffPos(DiffPosOptions),
+
+    #[options(help = "write an order file addressing LEDs along an axis or in a spiral")]
+    IndexMap(IndexMapOptions),
+
     #[options(help = "perform perspective adjustment")]
     AdjustPerspective(AdjustPerspectiveOptions),
+
+    #[options(help = "edit vled recordings")]
+    Vled(VledOptions),
+
+    #[options(help = "render an effect to a vled or bvled file without hardware")]
+    Render(RenderOptions),
+
+    #[options(help = "convert between FSEQ sequences and vled recordings")]
+    Fseq(FseqOptions),
+
+    #[options(help = "render a vled file to a GIF or video preview")]
+    Preview(PreviewCommandOptions),
 }
 
 #[derive(Debug, Options)]
@@ -96,6 +146,9 @@ struct SpeedtestOptions {}
 struct ReadvledOptions {
     #[options(help = "vled file to read", required)]
     vled_file: PathBuf,
+
+    #[options(help = "only play back this container from a multi-track recording")]
+    track: Option<u8>,
 }
 
 #[derive(Debug, Options)]
@@ -112,7 +165,59 @@ struct SetColorOptions {
 
 #[cfg(feature = "scan")]
 #[derive(Debug, Options)]
-struct CalibrateOptions {}
+struct CalibrateOptions {
+    #[options(
+        help = "scan frames recorded into a directory instead of the cameras, without a controller or windows"
+    )]
+    frames: Option<PathBuf>,
+
+    #[options(help = "output path for a scan of --frames (default: streamlined.json)")]
+    output_file: Option<PathBuf>,
+}
+
+#[cfg(feature = "scan")]
+#[derive(Debug, Options)]
+struct SimulateOptions {
+    #[options(free, help = "position file with where the LEDs really are")]
+    layout_file: Option<String>,
+
+    #[options(help = "output path for the scanned positions (default: <layout>-simulated.json)")]
+    output_file: Option<String>,
+
+    #[options(
+        help = "horizontal field of view of the cameras in degrees (default: [advanced.perspective] fov, or 60)"
+    )]
+    fov: Option<f64>,
+
+    #[options(
+        help = "distance from the cameras to the middle of the LEDs (default: just far enough to see all of them)"
+    )]
+    distance: Option<f64>,
+
+    #[options(help = "standard deviation of the sensor noise, out of 255 (default: 2)")]
+    noise: Option<f64>,
+
+    #[options(help = "radius of the glow around lit LEDs in pixels, 0 for none (default: 4)")]
+    bloom: Option<f64>,
+
+    #[options(help = "brightness of the room with the LEDs off, out of 255 (default: 10)")]
+    ambient: Option<f64>,
+
+    #[options(help = "diameter of an LED in the units of the layout (default: 5)")]
+    led_size: Option<f64>,
+
+    #[options(
+        no_short,
+        help = "let the cameras see LEDs through the ones in front of them"
+    )]
+    no_occlusion: bool,
+
+    #[options(help = "seed for the sensor noise (default: 0)")]
+    seed: Option<i32>,
+
+    #[options(help = "fail when the RMS error is above this, in the units of the layout")]
+    max_error: Option<f64>,
+}
 
 #[derive(Debug, Options)]
 struct UnityCommandOptions {}
@@ -128,7 +233,7 @@ struct DriverWizardOptions {}
 
 #[derive(Debug, Options)]
 struct DemoOptions {
-    #[options(help = "demo to run (rainbow, rainbow-loop)", required)]
+    #[options(help = "demo to run (rainbow, rainbow-loop, image, plasma)", required)]
     active_demo: String,
 
     #[options(help = "image to render")]
@@ -143,10 +248,174 @@ struct ConvertLedposOptions {
     #[options(help = "input JSON file", required)]
     input: String,
 
-    #[options(help = "output file")]
+    #[options(
+        help = "cpp, arduino, fastled, wled or binary (default: from the output extension, or cpp)"
+    )]
+    target: Option<String>,
+
+    #[options(no_short, help = "scale positions into an integer range, e.g. 0..255")]
+    normalize: Option<String>,
+
+    #[options(help = "name of the generated constant (default: coords)")]
+    name: Option<String>,
+
+    #[options(help = "output file (default: print it)")]
     output: Option<String>,
 }
 
+#[derive(Debug, Options)]
+struct ExportPosOptions {
+    #[options(help = "position file to export")]
+    input: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        help = "scene to export every container of in world coordinates"
+    )]
+    scene: Option<PathBuf>,
+
+    #[options(help = "csv, ply, obj, geo or json (default: from the output extension)")]
+    format: Option<String>,
+
+    #[options(no_short, help = "add each LED's index as an attribute")]
+    index: bool,
+
+    #[options(no_short, help = "add each LED's scan status as an attribute")]
+    status: bool,
+
+    #[options(no_short, help = "leave out LEDs without all three coordinates")]
+    located_only: bool,
+
+    #[options(help = "output file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct ImportPosOptions {
+    #[options(command)]
+    command: Option<ImportPosCommand>,
+}
+
+#[derive(Debug, Options)]
+enum ImportPosCommand {
+    #[options(help = "read positions from a CSV with x, y and z columns")]
+    Csv(ImportCsvOptions),
+
+    #[options(help = "a matrix of LEDs wired row by row and layer by layer")]
+    Cube(ImportCubeOptions),
+
+    #[options(help = "a strip wound around a cylinder")]
+    Helix(ImportHelixOptions),
+
+    #[options(help = "rings of LEDs stacked on top of each other")]
+    Rings(ImportRingsOptions),
+
+    #[options(help = "zigzag matrix panels stacked front to back")]
+    Serpentine(ImportSerpentineOptions),
+}
+
+#[derive(Debug, Options)]
+struct ImportCsvOptions {
+    #[options(help = "csv file to import", required)]
+    input: PathBuf,
+
+    #[options(help = "unit of the coordinates (default: mm)")]
+    units: Option<String>,
+
+    #[options(help = "output position file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct ImportCubeOptions {
+    #[options(help = "LEDs along x", required)]
+    width: usize,
+
+    #[options(help = "LEDs along y, the number of layers", required)]
+    height: usize,
+
+    #[options(help = "LEDs along z", required)]
+    depth: usize,
+
+    #[options(help = "distance between neighbouring LEDs (default: 1)")]
+    spacing: Option<f64>,
+
+    #[options(help = "every other row runs back the way the previous one came")]
+    zigzag: bool,
+
+    #[options(help = "unit of the coordinates (default: mm)")]
+    units: Option<String>,
+
+    #[options(help = "output position file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct ImportHelixOptions {
+    #[options(help = "number of LEDs on the strip", required)]
+    num_led: usize,
+
+    #[options(help = "radius of the cylinder", required)]
+    radius: f64,
+
+    #[options(help = "LEDs in one turn around the cylinder", required)]
+    leds_per_turn: f64,
+
+    #[options(help = "height gained by each turn", required)]
+    pitch: f64,
+
+    #[options(help = "unit of the coordinates (default: mm)")]
+    units: Option<String>,
+
+    #[options(help = "output position file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct ImportRingsOptions {
+    #[options(help = "number of rings", required)]
+    rings: usize,
+
+    #[options(help = "LEDs in each ring", required)]
+    leds_per_ring: usize,
+
+    #[options(no_short, help = "radius of the rings", required)]
+    radius: f64,
+
+    #[options(help = "distance between rings (default: 1)")]
+    spacing: Option<f64>,
+
+    #[options(help = "unit of the coordinates (default: mm)")]
+    units: Option<String>,
+
+    #[options(help = "output position file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct ImportSerpentineOptions {
+    #[options(help = "LEDs in each row of a panel", required)]
+    width: usize,
+
+    #[options(help = "rows in each panel", required)]
+    height: usize,
+
+    #[options(help = "number of panels", required)]
+    panels: usize,
+
+    #[options(help = "distance between neighbouring LEDs on a panel (default: 1)")]
+    spacing: Option<f64>,
+
+    #[options(no_short, help = "distance between panels (default: 1)")]
+    panel_spacing: Option<f64>,
+
+    #[options(help = "unit of the coordinates (default: mm)")]
+    units: Option<String>,
+
+    #[options(help = "output position file", required)]
+    output: PathBuf,
+}
+
 #[derive(Debug, Options)]
 struct ListCamsOptions {
     #[options(help = "which index to start search at")]
@@ -156,6 +425,109 @@ struct ListCamsOptions {
     upper_index: Option<i32>,
 }
 
+#[derive(Debug, Options)]
+struct CheckPosOptions {
+    #[options(free, help = "position file to check")]
+    position_file: Option<String>,
+
+    #[options(help = "number of equally long strips the LEDs are split over (default: 1)")]
+    num_strips: Option<usize>,
+
+    #[options(
+        help = "how many deviations above the typical gap counts as an outlier (default: 3.5)"
+    )]
+    threshold: Option<f64>,
+
+    #[options(help = "estimate LEDs that need recalibrating from their neighbours on the strip")]
+    interpolate: bool,
+
+    #[options(help = "only report outliers, don't write a file")]
+    dry_run: bool,
+
+    #[options(help = "output path")]
+    output_file: Option<String>,
+}
+
+#[derive(Debug, Options)]
+struct TransformPosOptions {
+    #[options(free, help = "position file to transform")]
+    position_file: Option<String>,
+
+    #[options(
+        no_short,
+        help = "move the middle of the LEDs to the origin, before anything else"
+    )]
+    recenter: bool,
+
+    #[options(no_short, help = "swap two axes, e.g. y,z (repeatable)")]
+    swap: Vec<String>,
+
+    #[options(help = "flip an axis around the middle of the LEDs (repeatable)")]
+    mirror: Vec<String>,
+
+    #[options(
+        help = "rotate around an axis through the middle of the LEDs, e.g. x:90 (repeatable)"
+    )]
+    rotate: Vec<String>,
+
+    #[options(help = "scale from the origin, by one factor or one per axis, e.g. 2 or 1,-1,1")]
+    scale: Option<String>,
+
+    #[options(help = "move every LED, after everything else, e.g. 10,0,-5")]
+    translate: Option<String>,
+
+    #[options(help = "output path")]
+    output_file: Option<String>,
+}
+
+#[derive(Debug, Options)]
+struct MergePosOptions {
+    #[options(free, help = "position files to merge, lined up with the first one")]
+    position_files: Vec<String>,
+
+    #[options(help = "output path")]
+    output_file: Option<String>,
+}
+
+#[derive(Debug, Options)]
+struct DiffPosOptions {
+    #[options(free, help = "old and new position file")]
+    position_files: Vec<String>,
+
+    #[options(help = "write a PNG of the new positions coloured by drift")]
+    image: Option<PathBuf>,
+
+    #[options(help = "width and height of the image in pixels (default: 480)")]
+    size: Option<u32>,
+
+    #[options(help = "number of bars in the drift histogram (default: 10)")]
+    bins: Option<usize>,
+}
+
+#[derive(Debug, Options)]
+struct IndexMapOptions {
+    #[options(free, help = "position file to order")]
+    position_file: Option<String>,
+
+    #[options(help = "order the LEDs along an axis, e.g. y")]
+    axis: Option<String>,
+
+    #[options(help = "order the LEDs in a spiral climbing an axis, e.g. y")]
+    spiral: Option<String>,
+
+    #[options(help = "times the spiral goes round (default: 1)")]
+    turns: Option<f64>,
+
+    #[options(help = "order along the axis from highest to lowest")]
+    reverse: bool,
+
+    #[options(help = "check an order file against the position file instead of writing one")]
+    check: Option<PathBuf>,
+
+    #[options(help = "output path")]
+    output_file: Option<String>,
+}
+
 #[derive(Debug, Options)]
 struct AdjustPerspectiveOptions {
     #[options(help = "path to position file", required)]
@@ -165,6 +537,274 @@ struct AdjustPerspectiveOptions {
     output_file: Option<String>,
 }
 
+#[derive(Debug, Options)]
+struct VledOptions {
+    #[options(command)]
+    command: Option<VledCommand>,
+}
+
+#[derive(Debug, Options)]
+enum VledCommand {
+    #[options(help = "play recordings one after another")]
+    Concat(VledConcatOptions),
+
+    #[options(help = "keep only part of a recording")]
+    Trim(VledTrimOptions),
+
+    #[options(help = "speed up or slow down a recording")]
+    Scale(VledScaleOptions),
+
+    #[options(help = "play a recording backwards")]
+    Reverse(VledReverseOptions),
+
+    #[options(help = "repeat a recording")]
+    Loop(VledLoopOptions),
+
+    #[options(help = "fade from one recording into another")]
+    Crossfade(VledCrossfadeOptions),
+
+    #[options(help = "move LEDs to new indices using a mapping file")]
+    Remap(VledRemapOptions),
+
+    #[options(help = "combine recordings of different containers into a multi-track recording")]
+    Merge(VledMergeOptions),
+}
+
+#[derive(Debug, Options)]
+struct VledConcatOptions {
+    #[options(free, help = "vled files to concatenate")]
+    inputs: Vec<PathBuf>,
+
+    #[options(help = "output vled file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct VledTrimOptions {
+    #[options(help = "vled file to trim", required)]
+    input: PathBuf,
+
+    #[options(help = "where to start, e.g. 1.5s (default: beginning)")]
+    start: Option<String>,
+
+    #[options(help = "where to end, e.g. 10s (default: end of recording)")]
+    end: Option<String>,
+
+    #[options(help = "output vled file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct VledScaleOptions {
+    #[options(help = "vled file to scale", required)]
+    input: PathBuf,
+
+    #[options(
+        help = "time factor, 2 plays at half speed and 0.5 at double speed",
+        required
+    )]
+    factor: f64,
+
+    #[options(help = "output vled file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct VledReverseOptions {
+    #[options(help = "vled file to reverse", required)]
+    input: PathBuf,
+
+    #[options(help = "output vled file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct VledLoopOptions {
+    #[options(help = "vled file to repeat", required)]
+    input: PathBuf,
+
+    #[options(help = "how many times the recording should play", required)]
+    count: u32,
+
+    #[options(help = "output vled file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct VledCrossfadeOptions {
+    #[options(help = "recording to fade out of", required)]
+    first: PathBuf,
+
+    #[options(help = "recording to fade into", required)]
+    second: PathBuf,
+
+    #[options(no_short, help = "length of the fade, e.g. 2s", required)]
+    fade: String,
+
+    #[options(no_short, help = "frames per second during the fade (default: 30)")]
+    fps: Option<u32>,
+
+    #[options(help = "output vled file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct VledRemapOptions {
+    #[options(help = "vled file to remap", required)]
+    input: PathBuf,
+
+    #[options(
+        help = "JSON file mapping old indices to new ones, e.g. {\"0\": 5}",
+        required
+    )]
+    mapping: PathBuf,
+
+    #[options(help = "output vled file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct VledMergeOptions {
+    #[options(free, help = "vled files to merge, one per container")]
+    inputs: Vec<PathBuf>,
+
+    #[options(help = "comma separated container of each input (default: 0,1,2...)")]
+    tracks: Option<String>,
+
+    #[options(help = "output vled file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct RenderOptions {
+    #[options(
+        help = "effect to render (rainbow, rainbow-loop, image, plasma)",
+        required
+    )]
+    effect: String,
+
+    #[options(help = "frames per second to sample the effect at (default: 30)")]
+    fps: Option<u32>,
+
+    #[options(help = "length of the recording, e.g. 30s", required)]
+    duration: String,
+
+    #[options(help = "write a bvled file for the ESP instead of a vled file")]
+    bvled: bool,
+
+    #[options(
+        help = "position file to render against (default: the scene or first Unity position file)"
+    )]
+    positions: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        help = "scene to render across, into a multi-track recording"
+    )]
+    scene: Option<PathBuf>,
+
+    #[options(help = "image to render")]
+    image_path: Option<String>,
+
+    #[options(help = "output file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct FseqOptions {
+    #[options(command)]
+    command: Option<FseqCommand>,
+}
+
+#[derive(Debug, Options)]
+enum FseqCommand {
+    #[options(help = "convert a FSEQ v2 sequence into a vled recording")]
+    Import(FseqImportOptions),
+
+    #[options(help = "convert a vled recording into a FSEQ v2 sequence")]
+    Export(FseqExportOptions),
+}
+
+#[derive(Debug, Options)]
+struct FseqImportOptions {
+    #[options(help = "fseq file to import", required)]
+    input: PathBuf,
+
+    #[options(help = "channel of the first LED's red value, counting from 1 (default: 1)")]
+    start_channel: Option<usize>,
+
+    #[options(help = "number of LEDs to import (default: every channel of the sequence)")]
+    num_led: Option<usize>,
+
+    #[options(help = "output vled file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct FseqExportOptions {
+    #[options(help = "vled file to export", required)]
+    input: PathBuf,
+
+    #[options(help = "channel of the first LED's red value, counting from 1 (default: 1)")]
+    start_channel: Option<usize>,
+
+    #[options(help = "number of LEDs to export (default: highest index in the recording)")]
+    num_led: Option<usize>,
+
+    #[options(no_short, help = "milliseconds per frame (default: 25)")]
+    step_ms: Option<u8>,
+
+    #[options(help = "only export this container from a multi-track recording")]
+    track: Option<u8>,
+
+    #[options(help = "output fseq file", required)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Options)]
+struct PreviewCommandOptions {
+    #[options(free, help = "vled file to preview")]
+    input: Option<PathBuf>,
+
+    #[options(
+        help = "position file to draw the LEDs at (default: the scene or first Unity position file)"
+    )]
+    pos: Option<PathBuf>,
+
+    #[options(
+        no_short,
+        help = "scene to draw every container of, for multi-track recordings"
+    )]
+    scene: Option<PathBuf>,
+
+    #[options(help = "front, top, side, all or orbit (default: all)")]
+    view: Option<String>,
+
+    #[options(help = "frames per second (default: 20)")]
+    fps: Option<u32>,
+
+    #[options(help = "size of each view in pixels (default: 320)")]
+    size: Option<u32>,
+
+    #[options(help = "radius of lit LEDs in pixels (default: 3)")]
+    dot_radius: Option<u32>,
+
+    #[options(
+        no_short,
+        help = "seconds per revolution of the orbit view (default: 10)"
+    )]
+    orbit_seconds: Option<f64>,
+
+    #[options(help = "only show this container from a multi-track recording")]
+    track: Option<u8>,
+
+    #[options(
+        help = "output file, .gif or anything ffmpeg can write (e.g. .mp4)",
+        required
+    )]
+    output: PathBuf,
+}
+
 fn main() {
     let opts = MyOptions::parse_args_default_or_exit();
 
@@ -182,64 +822,9 @@ fn main() {
     builder.init();
 
     if let Some(Command::ConvertLedpos(ref convert_ledpos_options)) = opts.command {
-        let path = Path::new(&convert_ledpos_options.input);
-
-        info!(
-            "Converting {} into an C++ compatible data format",
-            path.display()
-        );
-
-        if path.exists() {
-            let mut pos_file = match File::open(path) {
-                Ok(file) => file,
-                Err(e) => {
-                    panic!("Could not read {:?}: {}", path.display(), e)
-                }
-            };
-
-            let mut file_contents = String::new();
-            match pos_file.read_to_string(&mut file_contents) {
-                Ok(_) => {}
-                Err(e) => {
-                    panic!("Could not read position file {}: {}", path.display(), e)
-                }
-            };
-
-            let json: PosEntry = match serde_json::from_str(&file_contents) {
-                Ok(json) => json,
-                Err(e) => {
-                    panic!(
-                        "{} contains invalid or incomplete calibration data: {}",
-                        path.display(),
-                        e
-                    )
-                }
-            };
-
-            let mut output_string = String::new();
-
-            let len = json.len();
-
-            output_string.push_str(&format!(
-                "static constexpr std::array<std::array<int16_t, 3>, {len}> coords = {{{{"
-            ));
-
-            for (index, entry) in json.iter().enumerate() {
-                output_string.push_str(&format!(
-                    "{{{}, {}, {}}}",
-                    entry.1 .0, entry.1 .1, entry.2 .1
-                ));
-
-                if index == len - 1 {
-                    output_string.push_str("}};")
-                } else {
-                    output_string.push_str(", ");
-                }
-            }
-
-            info!("{output_string}");
-        } else {
-            error!("{} not found", path.display());
+        if let Err(e) = convert_ledpos(convert_ledpos_options) {
+            error!("{e}");
+            process::exit(1);
         }
         return;
     } else if let Some(Command::ListCams(ref list_cams_options)) = opts.command {
@@ -276,74 +861,371 @@ fn main() {
 
         info!("\nWorking cameras: {working_cameras:?}");
 
+        return;
+    } else if let Some(Command::CheckPos(ref check_options)) = opts.command {
+        let Some(ref position_file) = check_options.position_file else {
+            error!("Pass the position file to check, e.g. svled check-pos ledpos.json");
+            process::exit(1);
+        };
+
+        let mut json = read_positions(Path::new(position_file));
+
+        let report = analysis::check_continuity(
+            &mut json,
+            check_options.num_strips.unwrap_or(1),
+            check_options
+                .threshold
+                .unwrap_or(analysis::DEFAULT_OUTLIER_THRESHOLD),
+        );
+
+        info!(
+            "Neighbouring LEDs are typically {:.1} {} apart, gaps above {:.1} count as outliers",
+            report.median_spacing, json.metadata.units, report.limit
+        );
+        for outlier in &report.outliers {
+            info!(
+                "LED {} is {:.1} {} from its closest neighbour, marked {}",
+                outlier.index,
+                outlier.spacing,
+                json.metadata.units,
+                outlier.status.as_str()
+            );
+        }
+
+        let interpolated = if check_options.interpolate {
+            analysis::interpolate_missing(&mut json, check_options.num_strips.unwrap_or(1))
+        } else {
+            Vec::new()
+        };
+        if !interpolated.is_empty() {
+            info!("Interpolated LEDs {interpolated:?} from their neighbours on the strip");
+        }
+
+        if report.outliers.is_empty() && interpolated.is_empty() {
+            info!("No outliers found in {position_file}");
+            return;
+        }
+
+        if check_options.dry_run {
+            info!(
+                "{} outliers found and {} LEDs interpolated, not writing them as this is a dry run",
+                report.outliers.len(),
+                interpolated.len()
+            );
+            return;
+        }
+
+        let name = check_options.output_file.clone().unwrap_or(format!(
+            "{}-checked.json",
+            position_file.strip_suffix(".json").unwrap_or(position_file)
+        ));
+
+        match ledmap::write_led_map(&json, Path::new(&name)) {
+            Ok(_) => info!(
+                "Marked {} outliers for recalibration and interpolated {} LEDs in {name}",
+                report.outliers.len(),
+                interpolated.len()
+            ),
+            Err(e) => {
+                error!("{e}");
+                process::exit(1);
+            }
+        }
+
+        return;
+    } else if let Some(Command::TransformPos(ref transform_options)) = opts.command {
+        if let Err(e) = transform_pos(transform_options) {
+            error!("{e}");
+            process::exit(1);
+        }
+        return;
+    } else if let Some(Command::MergePos(ref merge_options)) = opts.command {
+        if let Err(e) = merge_pos(merge_options) {
+            error!("{e}");
+            process::exit(1);
+        }
+        return;
+    } else if let Some(Command::DiffPos(ref diff_options)) = opts.command {
+        if let Err(e) = diff_pos(diff_options) {
+            error!("{e}");
+            process::exit(1);
+        }
+        return;
+    } else if let Some(Command::IndexMap(ref index_map_options)) = opts.command {
+        if let Err(e) = index_map(index_map_options) {
+            error!("{e}");
+            process::exit(1);
+        }
         return;
     } else if let Some(Command::AdjustPerspective(ref perspective_adjust_options)) = opts.command {
         let config_holder = utils::load_validate_conf(config_path).2;
 
-        let mut pos_file = match File::open(perspective_adjust_options.position_file.clone()) {
-            Ok(file) => file,
+        let mut json = read_positions(Path::new(&perspective_adjust_options.position_file));
+
+        if let Err(e) = position_adjustment(&mut json, &config_holder) {
+            error!("{e}");
+            process::exit(1);
+        }
+
+        let name = perspective_adjust_options
+            .output_file
+            .clone()
+            .unwrap_or(format!(
+                "{}-postprocess.json",
+                perspective_adjust_options
+                    .position_file
+                    .strip_suffix(".json")
+                    .unwrap_or(&perspective_adjust_options.position_file)
+            ));
+
+        let json = json.to_json();
+        let mut file = match File::create(Path::new(&name)) {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Unable to write temp-pos to {name}");
+                println!("Something went wrong trying to save the LED positions. Error: {e}");
+                process::exit(1);
+            }
+        };
+
+        match file.write_all(json.as_bytes()) {
+            Ok(_) => {}
+            Err(e) => {
+                error!("Unable to write temp-pos to {name}");
+                println!("Something went wrong trying to save the LED positions. Error: {e}");
+            }
+        }
+
+        info!("Wrote perspective-adjusted position file to {name}");
+
+        return;
+    } else if let Some(Command::Vled(ref vled_options)) = opts.command {
+        let Some(ref command) = vled_options.command else {
+            error!(
+                "Missing vled subcommand, available subcommands:\n{}",
+                VledOptions::command_list().unwrap_or_default()
+            );
+            process::exit(1);
+        };
+
+        if let Err(e) = edit_vled(command) {
+            error!("{e}");
+            process::exit(1);
+        }
+
+        return;
+    } else if let Some(Command::Fseq(ref fseq_options)) = opts.command {
+        let Some(ref command) = fseq_options.command else {
+            error!(
+                "Missing fseq subcommand, available subcommands:\n{}",
+                FseqOptions::command_list().unwrap_or_default()
+            );
+            process::exit(1);
+        };
+
+        if let Err(e) = convert_fseq(command) {
+            error!("{e}");
+            process::exit(1);
+        }
+
+        return;
+    } else if let Some(Command::Preview(ref preview_options)) = opts.command {
+        let Some(ref input) = preview_options.input else {
+            error!("Pass the vled file to preview, e.g. svled preview anim.vled -o preview.gif");
+            process::exit(1);
+        };
+
+        let (json, world) = read_world_positions(
+            preview_options.pos.as_deref(),
+            preview_options.scene.as_deref(),
+            config_path,
+            true,
+        );
+
+        let view = match preview_options
+            .view
+            .as_deref()
+            .unwrap_or("all")
+            .parse::<preview::PreviewView>()
+        {
+            Ok(view) => view,
+            Err(e) => {
+                error!("{e}");
+                process::exit(1);
+            }
+        };
+
+        let options = preview::PreviewOptions {
+            view,
+            fps: preview_options.fps.unwrap_or(20),
+            size: preview_options.size.unwrap_or(320),
+            dot_radius: preview_options.dot_radius.unwrap_or(3),
+            orbit_period: Duration::from_secs_f64(
+                preview_options.orbit_seconds.unwrap_or(10.0).max(0.1),
+            ),
+            // Tracks are turned into containers of the scene instead
+            track: preview_options.track.filter(|_| world.is_none()),
+        };
+
+        let result = vled::read_vled_file(input).and_then(|mut recording| {
+            if let Some(ref world) = world {
+                if let Some(only) = preview_options.track {
+                    recording.events.retain(|event| {
+                        !matches!(
+                            event.kind,
+                            VledEventKind::Color { track: Some(track), .. } if track != only
+                        )
+                    });
+                }
+                recording = world.join(&recording)?;
+            }
+            preview::write_preview(&recording, &json, &options, &preview_options.output)
+        });
+
+        match result {
+            Ok(frames) => info!(
+                "Wrote {frames} frame preview to {}",
+                preview_options.output.display()
+            ),
+            Err(e) => {
+                error!("{e}");
+                process::exit(1);
+            }
+        }
+
+        return;
+    } else if let Some(Command::Render(ref render_options)) = opts.command {
+        // Only fall back to the config for positions, rendering never talks to a controller. bvled files are
+        // played straight on the ESP, so effects are rendered onto the LEDs in strip order for them.
+        let (json, world) = read_world_positions(
+            render_options.positions.as_deref(),
+            render_options.scene.as_deref(),
+            config_path,
+            !render_options.bvled,
+        );
+        // A bvled file drives a single controller, it has no way of telling containers apart
+        if render_options.bvled && world.is_some() {
+            error!(
+                "bvled files hold one container, render each one with --positions instead of using a scene"
+            );
+            process::exit(1);
+        }
+
+        let duration = match utils::parse_duration(&render_options.duration) {
+            Ok(duration) => duration,
             Err(e) => {
-                panic!(
-                    "Could not read {:?}: {}",
-                    perspective_adjust_options.position_file, e
-                )
+                error!("{e}");
+                process::exit(1);
             }
         };
+        let fps = render_options.fps.unwrap_or(30);
 
-        let mut file_contents = String::new();
-        match pos_file.read_to_string(&mut file_contents) {
-            Ok(_) => {}
+        info!(
+            "Rendering {} for {:.2?} at {} fps",
+            render_options.effect, duration, fps
+        );
+
+        let rendered = match render::render_effect(
+            &render_options.effect,
+            &json,
+            fps,
+            duration,
+            render_options.image_path.as_deref(),
+        ) {
+            // One track per container, so each plays back on its own controller
+            Ok(rendered) => match world {
+                Some(ref world) => world.split(&rendered),
+                None => rendered,
+            },
             Err(e) => {
-                panic!(
-                    "Could not read position file {}: {}",
-                    perspective_adjust_options.position_file, e
-                )
+                error!("{e}");
+                process::exit(1);
             }
         };
 
-        let mut json: PosEntry = match serde_json::from_str(&file_contents) {
-            Ok(json) => json,
+        let written = if render_options.bvled {
+            vled::write_bvled_file(&rendered, &render_options.output)
+        } else {
+            vled::write_vled_file(&rendered, &render_options.output)
+        };
+
+        match written {
+            Ok(_) => info!(
+                "Wrote {} events to {}",
+                rendered.events.len(),
+                render_options.output.display()
+            ),
             Err(e) => {
-                panic!(
-                    "{} contains invalid or incomplete calibration data: {}",
-                    perspective_adjust_options.position_file, e
-                )
+                error!("{e}");
+                process::exit(1);
             }
-        };
+        }
 
-        position_adjustment(&mut json, &config_holder);
+        return;
+    } else if let Some(Command::ImportPos(ref import_options)) = opts.command {
+        let Some(ref command) = import_options.command else {
+            error!(
+                "Missing import-pos subcommand, available subcommands:\n{}",
+                ImportPosOptions::command_list().unwrap_or_default()
+            );
+            process::exit(1);
+        };
 
-        let name = perspective_adjust_options
-            .output_file
-            .clone()
-            .unwrap_or(format!(
-                "{}-postprocess.json",
-                perspective_adjust_options
-                    .position_file
-                    .strip_suffix(".json")
-                    .unwrap_or(&perspective_adjust_options.position_file)
-            ));
+        if let Err(e) = import_positions(command) {
+            error!("{e}");
+            process::exit(1);
+        }
 
-        let json = serde_json::to_string_pretty(&json).expect("Unable to serialize metadata!");
-        let mut file = match File::create(Path::new(&name)) {
-            Ok(file) => file,
+        return;
+    } else if let Some(Command::ExportPos(ref export_options)) = opts.command {
+        let format = match export_options.format {
+            Some(ref format) => format.parse::<export::ExportFormat>(),
+            None => export::ExportFormat::from_path(&export_options.output).ok_or(format!(
+                "Can't tell the format of {} from its extension, pass one with --format",
+                export_options.output.display()
+            )),
+        };
+        let format = match format {
+            Ok(format) => format,
             Err(e) => {
-                error!("Unable to write temp-pos to {name}");
-                println!("Something went wrong trying to save the LED positions. Error: {e}");
+                error!("{e}");
                 process::exit(1);
             }
         };
 
-        match file.write_all(json.as_bytes()) {
-            Ok(_) => {}
+        let (json, input) = match (&export_options.scene, &export_options.input) {
+            (Some(scene), _) => match scene::Scene::read(scene).and_then(|scene| scene.world()) {
+                Ok(world) => (world.combined(), scene),
+                Err(e) => {
+                    error!("{e}");
+                    process::exit(1);
+                }
+            },
+            (None, Some(input)) => (read_positions(input), input),
+            (None, None) => {
+                error!("Pass the position file to export with --input, or a scene with --scene");
+                process::exit(1);
+            }
+        };
+
+        let options = export::ExportOptions {
+            index: export_options.index,
+            status: export_options.status,
+            located_only: export_options.located_only,
+        };
+
+        match export::write_export_file(&json, format, &options, &export_options.output) {
+            Ok(_) => info!(
+                "Exported {} to {}",
+                input.display(),
+                export_options.output.display()
+            ),
             Err(e) => {
-                error!("Unable to write temp-pos to {name}");
-                println!("Something went wrong trying to save the LED positions. Error: {e}");
+                error!("{e}");
+                process::exit(1);
             }
         }
 
-        info!("Wrote perspective-adjusted position file to {name}");
-
         return;
     }
 
@@ -366,11 +1248,18 @@ fn main() {
         } else {
             info!("Playing back {}!", readvled_options.vled_file.display());
 
-            {
-                manager.lock().unwrap().config.record_data = false;
-                manager.lock().unwrap().config.record_esp_data = false;
-            }
-            match read_vled::read_vled(&manager, readvled_options.vled_file.clone()) {
+            recording::stop_recording(
+                &manager,
+                &[
+                    recording::RecordingFormat::Vled,
+                    recording::RecordingFormat::Bvled,
+                ],
+            );
+            match read_vled::read_vled(
+                &manager,
+                readvled_options.vled_file.clone(),
+                readvled_options.track,
+            ) {
                 Ok(_) => {}
                 Err(e) => {
                     panic!(
@@ -389,15 +1278,7 @@ fn main() {
 
             let mut manager = ctrlc_manager.lock().unwrap();
 
-            if let Some(file_buf) = &mut manager.io.data_file_buf {
-                debug!("Flushing data_file_buf");
-                file_buf.flush().unwrap();
-            }
-
-            if let Some(file_buf) = &mut manager.io.esp_data_file_buf {
-                debug!("Flushing esp_data_file_buf");
-                file_buf.flush().unwrap();
-            }
+            recording::finish_recordings(&mut manager);
 
             debug!("signaling to exit any threads");
             manager.state.keepalive.store(false, Ordering::Relaxed);
@@ -431,7 +1312,7 @@ fn main() {
 
         info!("Sending positions to Unity");
 
-        match unity::send_pos(unity_options.clone()) {
+        match unity::send_pos(&config_holder) {
             Ok(_) => {}
             Err(e) => {
                 panic!("There was an issue connecting to Unity: {e}");
@@ -449,7 +1330,7 @@ fn main() {
     } else if let Some(Command::SendPos(ref _sendpos_options)) = opts.command {
         info!("Sending positions to Unity");
 
-        match unity::send_pos(unity_options.clone()) {
+        match unity::send_pos(&config_holder) {
             Ok(_) => {}
             Err(e) => {
                 panic!("There was an issue connecting to Unity: {e}");
@@ -465,15 +1346,7 @@ fn main() {
 
             let mut manager = ctrlc_manager.lock().unwrap();
 
-            if let Some(file_buf) = &mut manager.io.data_file_buf {
-                debug!("Flushing data_file_buf");
-                file_buf.flush().unwrap();
-            }
-
-            if let Some(file_buf) = &mut manager.io.esp_data_file_buf {
-                debug!("Flushing esp_data_file_buf");
-                file_buf.flush().unwrap();
-            }
+            recording::finish_recordings(&mut manager);
 
             debug!("signaling to exit any threads");
             manager.state.keepalive.store(false, Ordering::Relaxed);
@@ -523,38 +1396,7 @@ fn main() {
     } else if let Some(Command::Demo(ref demo_options)) = opts.command {
         info!("Running demo!");
 
-        let mut pos_file = match File::open(unity_options.unity_position_files[0].clone()) {
-            Ok(file) => file,
-            Err(e) => {
-                panic!(
-                    "Could not read {:?}: {}",
-                    unity_options.unity_position_files[0], e
-                )
-            }
-        };
-
-        let mut file_contents = String::new();
-        match pos_file.read_to_string(&mut file_contents) {
-            Ok(_) => {}
-            Err(e) => {
-                panic!(
-                    "Could not read position file {}: {}",
-                    unity_options.unity_position_files[0].display(),
-                    e
-                )
-            }
-        };
-
-        let json: PosEntry = match serde_json::from_str(&file_contents) {
-            Ok(json) => json,
-            Err(e) => {
-                panic!(
-                    "{} contains invalid or incomplete calibration data: {}",
-                    unity_options.unity_position_files[0].display(),
-                    e
-                )
-            }
-        };
+        let json = read_logical_positions(&unity_options.unity_position_files[0], &config_holder);
 
         match demo_options.active_demo.to_lowercase().as_str() {
             "rainbow-loop" => loop {
@@ -567,6 +1409,7 @@ fn main() {
                 demo::rainbow(&manager, &json, 50, 50, false, demo::Axis::Y, true);
                 demo::rainbow(&manager, &json, 80, 50, false, demo::Axis::Z, true);
             }
+            "plasma" => demo::plasma(&manager, &json),
             "image" => {
                 render_jpg_onto_leds(
                     &demo_options.image_path.clone().unwrap(),
@@ -589,12 +1432,804 @@ fn main() {
     }
 
     #[cfg(feature = "scan")]
-    if let Some(Command::Calibrate(ref _calibrate_options)) = opts.command {
-        info!("Performing calibrating");
-        scan::scan(config_holder.clone(), &manager, false, None).expect("failure");
+    if let Some(Command::Calibrate(ref calibrate_options)) = opts.command {
+        if let Some(ref frames) = calibrate_options.frames {
+            let output = calibrate_options
+                .output_file
+                .clone()
+                .unwrap_or(PathBuf::from("streamlined.json"));
+            if let Err(e) = scan_frames(frames, &output, &config_holder, &manager) {
+                error!("{e}");
+                process::exit(1);
+            }
+        } else {
+            info!("Performing calibrating");
+            scan::scan(config_holder.clone(), &manager, false, None).expect("failure");
+        }
+    }
+
+    #[cfg(feature = "scan")]
+    if let Some(Command::Simulate(ref simulate_options)) = opts.command {
+        if let Err(e) = simulate(simulate_options, &config_holder, &manager) {
+            error!("{e}");
+            process::exit(1);
+        }
     }
 
     // led_manager::set_color(&mut manager, 1, 255, 255, 255);
 
     utils::flush_data(manager);
 }
+
+/// Runs a headless, streamlined scan against frames recorded with one LED lit at a time, see
+/// `frame_source::ImageDirectory`. Frames for the second camera go in a `camera2` directory inside `frames`.
+#[cfg(feature = "scan")]
+fn scan_frames(
+    frames: &Path,
+    output: &Path,
+    config_holder: &utils::Config,
+    manager: &Arc<Mutex<utils::ManagerData>>,
+) -> Result<(), Box<dyn Error>> {
+    let strip = Arc::new(VirtualStrip::new(config_holder.num_led as usize));
+    manager.lock().unwrap().io.led_sink = Some(strip.clone());
+
+    let opener = |second_cam: bool| -> Result<Box<dyn FrameSource>, Box<dyn Error>> {
+        let directory = if second_cam {
+            frames.join("camera2")
+        } else {
+            frames.to_path_buf()
+        };
+        Ok(Box::new(ImageDirectory::new(
+            &directory,
+            Arc::clone(&strip),
+        )?))
+    };
+
+    info!("Scanning frames from {}", frames.display());
+    let led_pos = scan::scan_with(
+        config_holder.clone(),
+        manager,
+        true,
+        None,
+        &opener,
+        &HeadlessUi,
+    )?;
+    ledmap::write_led_map(&led_pos, output)?;
+
+    info!(
+        "Located {} of {} LEDs, wrote {}",
+        led_pos.located().count(),
+        led_pos.len(),
+        output.display()
+    );
+
+    Ok(())
+}
+
+/// Scans the layout in a position file through two simulated cameras, then compares the result with the layout,
+/// see `simulate::Simulator`
+#[cfg(feature = "scan")]
+fn simulate(
+    options: &SimulateOptions,
+    config_holder: &utils::Config,
+    manager: &Arc<Mutex<utils::ManagerData>>,
+) -> Result<(), Box<dyn Error>> {
+    let Some(ref layout_file) = options.layout_file else {
+        return Err("Pass the position file to scan, e.g. svled simulate cube.json".into());
+    };
+    let layout = ledmap::read_led_map(Path::new(layout_file))?;
+    if layout.len() <= 5 {
+        return Err("The scanner needs at least 6 LEDs, as it measures brightness on LED 5".into());
+    }
+
+    let mut config = config_holder.clone();
+    config.num_led = layout.len() as u32;
+    config.camera.multi_camera = true;
+    // Simulated frames are rendered when they're read, so there's no backlog to drain
+    config.advanced.camera.no_background_frame_consumer = Some(true);
+    manager.lock().unwrap().config.num_led = config.num_led;
+
+    let size = (
+        config.camera.video_width.unwrap_or(1280.0) as i32,
+        config.camera.video_height.unwrap_or(720.0) as i32,
+    );
+    let fov = options
+        .fov
+        .or(config.advanced.perspective.fov)
+        .unwrap_or(60.0);
+    let cameras = simulate::camera_rig(
+        &layout,
+        fov,
+        size,
+        options.distance,
+        config.advanced.camera.cam2_overhead.unwrap_or(false),
+        config.advanced.camera.cam2_overhead_flip.unwrap_or(false),
+    )?
+    .map(|camera| VirtualCamera {
+        noise: options.noise.unwrap_or(2.0),
+        bloom: options.bloom.unwrap_or(4.0),
+        ambient: options.ambient.unwrap_or(10.0),
+        ..camera
+    });
+    let led_size = options.led_size.unwrap_or(5.0);
+
+    let strip = Arc::new(VirtualStrip::new(layout.len()));
+    manager.lock().unwrap().io.led_sink = Some(strip.clone());
+    opencv::core::set_rng_seed(options.seed.unwrap_or(0))?;
+
+    let simulator = |second_cam: bool| {
+        Simulator::new(
+            Arc::clone(&strip),
+            &layout,
+            cameras[second_cam as usize].clone(),
+            led_size,
+            !options.no_occlusion,
+        )
+    };
+    let opener = |second_cam: bool| -> Result<Box<dyn FrameSource>, Box<dyn Error>> {
+        Ok(Box::new(simulator(second_cam)))
+    };
+
+    info!(
+        "Scanning {layout_file} from {:.1} {} away, the front camera can see {} LEDs and the second one {}",
+        cameras[0].position[2] - cameras[0].target[2],
+        layout.metadata.units,
+        simulator(false).visible().count(),
+        simulator(true).visible().count()
+    );
+    let scan = scan::scan_with(config, manager, true, None, &opener, &HeadlessUi)?;
+
+    let output = options.output_file.clone().unwrap_or(format!(
+        "{}-simulated.json",
+        layout_file.strip_suffix(".json").unwrap_or(layout_file)
+    ));
+    ledmap::write_led_map(&scan, Path::new(&output))?;
+
+    let accuracy = simulate::measure_accuracy(&scan, &layout)?;
+    let units = &layout.metadata.units;
+    info!(
+        "Found {} of {} LEDs, {} were missed and {} found where there is none",
+        accuracy.errors.len(),
+        accuracy.expected,
+        accuracy.missed.len(),
+        accuracy.phantom.len()
+    );
+    info!(
+        "Error after scaling by {:.4} and lining up: {:.2} {units} RMS, {:.2} on average, {:.2} at most",
+        accuracy.scale,
+        accuracy.rms_error(),
+        accuracy.mean_error(),
+        accuracy.max_error()
+    );
+    for (index, error) in accuracy.worst(5) {
+        info!("LED {index} is {error:.2} {units} off");
+    }
+    if !accuracy.missed.is_empty() {
+        info!("Missed LEDs: {:?}", accuracy.missed);
+    }
+    if !accuracy.phantom.is_empty() {
+        warn!(
+            "LEDs found that the layout doesn't have: {:?}",
+            accuracy.phantom
+        );
+    }
+    info!("Wrote {output}");
+
+    if let Some(max_error) = options.max_error
+        && accuracy.rms_error() > max_error
+    {
+        return Err(format!(
+            "The RMS error of {:.2} {units} is above the maximum of {max_error}",
+            accuracy.rms_error()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn edit_vled(command: &VledCommand) -> Result<(), Box<dyn Error>> {
+    let (edited, output) = match command {
+        VledCommand::Concat(options) => {
+            if options.inputs.is_empty() {
+                return Err("Pass at least one vled file to concatenate".into());
+            }
+
+            let parts = options
+                .inputs
+                .iter()
+                .map(|input| vled::read_vled_file(input))
+                .collect::<Result<Vec<_>, _>>()?;
+            (Vled::concat(&parts), &options.output)
+        }
+        VledCommand::Trim(options) => {
+            let start = match options.start {
+                Some(ref start) => utils::parse_duration(start)?,
+                None => Duration::ZERO,
+            };
+            let end = options
+                .end
+                .as_deref()
+                .map(utils::parse_duration)
+                .transpose()?;
+
+            if end.is_some_and(|end| end <= start) {
+                return Err("--end must come after --start".into());
+            }
+
+            (
+                vled::read_vled_file(&options.input)?.trim(start, end),
+                &options.output,
+            )
+        }
+        VledCommand::Scale(options) => {
+            if !options.factor.is_finite() || options.factor <= 0.0 {
+                return Err(format!("{} is not a valid time factor", options.factor).into());
+            }
+
+            (
+                vled::read_vled_file(&options.input)?.scale(options.factor),
+                &options.output,
+            )
+        }
+        VledCommand::Reverse(options) => (
+            vled::read_vled_file(&options.input)?.reverse(),
+            &options.output,
+        ),
+        VledCommand::Loop(options) => {
+            if options.count == 0 {
+                return Err("--count must be at least 1".into());
+            }
+
+            (
+                vled::read_vled_file(&options.input)?.unroll(options.count),
+                &options.output,
+            )
+        }
+        VledCommand::Crossfade(options) => {
+            let first = vled::read_vled_file(&options.first)?;
+            let second = vled::read_vled_file(&options.second)?;
+            let fade = utils::parse_duration(&options.fade)?;
+
+            (
+                first.crossfade(&second, fade, options.fps.unwrap_or(30)),
+                &options.output,
+            )
+        }
+        VledCommand::Remap(options) => {
+            let mapping = vled::read_mapping_file(&options.mapping)?;
+
+            (
+                vled::read_vled_file(&options.input)?.remap(&mapping),
+                &options.output,
+            )
+        }
+        VledCommand::Merge(options) => {
+            if options.inputs.is_empty() {
+                return Err("Pass at least one vled file to merge".into());
+            }
+            if options.inputs.len() > u8::MAX as usize {
+                return Err(format!("Can merge at most {} vled files", u8::MAX).into());
+            }
+
+            let tracks = match options.tracks {
+                Some(ref tracks) => tracks
+                    .split(",")
+                    .map(|track| {
+                        track
+                            .trim()
+                            .parse::<u8>()
+                            .map_err(|e| format!("Invalid container {track}: {e}"))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+                None => (0..options.inputs.len() as u8).collect(),
+            };
+
+            if tracks.len() != options.inputs.len() {
+                return Err(format!(
+                    "Got {} containers for {} vled files",
+                    tracks.len(),
+                    options.inputs.len()
+                )
+                .into());
+            }
+
+            let parts = options
+                .inputs
+                .iter()
+                .map(|input| vled::read_vled_file(input))
+                .collect::<Result<Vec<_>, _>>()?;
+            (Vled::merge(&parts, &tracks), &options.output)
+        }
+    };
+
+    vled::write_vled_file(&edited, output)?;
+    info!(
+        "Wrote {} events ({:.2?}) to {}",
+        edited.events.len(),
+        edited.duration,
+        output.display()
+    );
+
+    Ok(())
+}
+
+fn convert_fseq(command: &FseqCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        FseqCommand::Import(options) => {
+            let sequence = fseq::read_fseq_file(&options.input)?;
+            let start_channel = options.start_channel.unwrap_or(1).saturating_sub(1);
+            let num_led = options
+                .num_led
+                .unwrap_or((sequence.channel_count as usize).saturating_sub(start_channel) / 3);
+
+            info!(
+                "Importing {} frames of {} LEDs at {}ms per frame",
+                sequence.frames.len(),
+                num_led,
+                sequence.step_ms
+            );
+
+            let imported = sequence.to_vled(start_channel, num_led);
+            vled::write_vled_file(&imported, &options.output)?;
+            info!(
+                "Wrote {} events ({:.2?}) to {}",
+                imported.events.len(),
+                imported.duration,
+                options.output.display()
+            );
+        }
+        FseqCommand::Export(options) => {
+            let recording = vled::read_vled_file(&options.input)?;
+            let num_led = options.num_led.unwrap_or_else(|| {
+                recording
+                    .events
+                    .iter()
+                    .filter_map(|event| match event.kind {
+                        VledEventKind::Color { index, .. } => Some(index as usize + 1),
+                        VledEventKind::Mark(_) => None,
+                    })
+                    .max()
+                    .unwrap_or(0)
+            });
+
+            let sequence = fseq::Fseq::from_vled(
+                &recording,
+                num_led,
+                options.step_ms.unwrap_or(25),
+                options.start_channel.unwrap_or(1).saturating_sub(1),
+                options.track,
+            );
+
+            fseq::write_fseq_file(&sequence, &options.output)?;
+            info!(
+                "Wrote {} frames of {} channels to {}",
+                sequence.frames.len(),
+                sequence.channel_count,
+                options.output.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn transform_pos(options: &TransformPosOptions) -> Result<(), Box<dyn Error>> {
+    let Some(ref position_file) = options.position_file else {
+        return Err("Pass the position file to transform, e.g. svled transform-pos ledpos.json --rotate x:90".into());
+    };
+
+    // Applied in a fixed order, as options don't keep the order they were given in
+    let mut steps = Vec::new();
+    if options.recenter {
+        steps.push(transform::TransformStep::Recenter);
+    }
+    for swap in &options.swap {
+        steps.push(transform::parse_swap(swap)?);
+    }
+    for mirror in &options.mirror {
+        steps.push(transform::TransformStep::Mirror(transform::parse_axis(
+            mirror,
+        )?));
+    }
+    for rotation in &options.rotate {
+        steps.push(transform::parse_rotation(rotation)?);
+    }
+    if let Some(ref scale) = options.scale {
+        steps.push(transform::parse_scale(scale)?);
+    }
+    if let Some(ref translate) = options.translate {
+        steps.push(transform::TransformStep::Translate(
+            transform::parse_vector(translate, false)?,
+        ));
+    }
+
+    if steps.is_empty() {
+        return Err("Nothing to do, pass at least one of --recenter, --swap, --mirror, --rotate, --scale or --translate".into());
+    }
+
+    let mut json = ledmap::read_led_map(Path::new(position_file))?;
+    let record = transform::transform_positions(&mut json, &steps);
+
+    let name = options.output_file.clone().unwrap_or(format!(
+        "{}-transformed.json",
+        position_file.strip_suffix(".json").unwrap_or(position_file)
+    ));
+    ledmap::write_led_map(&json, Path::new(&name))?;
+
+    info!(
+        "Applied {} to {position_file}, wrote {name}",
+        record.steps.join(", ")
+    );
+
+    Ok(())
+}
+
+fn index_map(options: &IndexMapOptions) -> Result<(), Box<dyn Error>> {
+    let Some(ref position_file) = options.position_file else {
+        return Err(
+            "Pass the position file to order, e.g. svled index-map ledpos.json --axis y".into(),
+        );
+    };
+    let json = ledmap::read_led_map(Path::new(position_file))?;
+
+    if let Some(ref check) = options.check {
+        let order = remap::read_index_map(check)?;
+        if order.len() != json.len() {
+            return Err(format!(
+                "{} orders {} LEDs, but {position_file} has {}",
+                check.display(),
+                order.len(),
+                json.len()
+            )
+            .into());
+        }
+
+        let moved = (0..order.len() as u16)
+            .filter(|logical| order.to_physical(*logical) != *logical)
+            .count();
+        info!(
+            "{} is a valid order for {position_file}, {moved} of {} LEDs are addressed at a different index",
+            check.display(),
+            order.len()
+        );
+        return Ok(());
+    }
+
+    let (order, description) = match (&options.axis, &options.spiral) {
+        (Some(axis), None) => (
+            remap::sort_along(&json, transform::parse_axis(axis)?, options.reverse),
+            format!("along {axis}"),
+        ),
+        (None, Some(axis)) => {
+            let turns = options.turns.unwrap_or(1.0);
+            if !turns.is_finite() || turns <= 0.0 {
+                return Err(format!("--turns has to be above 0, got {turns}").into());
+            }
+            (
+                remap::spiral(&json, transform::parse_axis(axis)?, turns),
+                format!("in a spiral of {turns} turns up {axis}"),
+            )
+        }
+        _ => return Err("Pass one of --axis, --spiral or --check".into()),
+    };
+
+    let name = options.output_file.clone().unwrap_or(format!(
+        "{}-order.json",
+        position_file.strip_suffix(".json").unwrap_or(position_file)
+    ));
+    remap::write_index_map(&order, Path::new(&name))?;
+
+    info!("Ordered {} LEDs {description}, wrote {name}", order.len());
+    info!("Set index_map under [advanced.remap] in svled.toml to address LEDs in this order");
+
+    Ok(())
+}
+
+fn merge_pos(options: &MergePosOptions) -> Result<(), Box<dyn Error>> {
+    if options.position_files.len() < 2 {
+        return Err(
+            "Pass at least two position files to merge, e.g. svled merge-pos a.json b.json".into(),
+        );
+    }
+
+    let scans = options
+        .position_files
+        .iter()
+        .map(|position_file| ledmap::read_led_map(Path::new(position_file)))
+        .collect::<Result<Vec<LedMap>, _>>()?;
+
+    let (mut json, report) = merge::merge_scans(&scans)?;
+    json.metadata.merged_from = options.position_files.clone();
+
+    let first = &options.position_files[0];
+
+    for (position_file, alignment) in options
+        .position_files
+        .iter()
+        .zip(&report.alignments)
+        .skip(1)
+    {
+        info!(
+            "Lined up {position_file} on {} shared LEDs, {:.2} {} apart on average afterwards",
+            alignment.shared, alignment.rms_error, json.metadata.units
+        );
+        if alignment.shared == 0 {
+            warn!(
+                "{position_file} shares no located LEDs with {first}, it was merged without being lined up"
+            );
+        } else if alignment.shared < 3 {
+            warn!("{position_file} shares too few located LEDs to be rotated, it was only moved");
+        }
+    }
+    if !report.missing.is_empty() {
+        warn!(
+            "{} LEDs weren't located in any scan: {:?}",
+            report.missing.len(),
+            report.missing
+        );
+    }
+
+    let name = options.output_file.clone().unwrap_or(format!(
+        "{}-merged.json",
+        first.strip_suffix(".json").unwrap_or(first)
+    ));
+    ledmap::write_led_map(&json, Path::new(&name))?;
+
+    info!("Merged {} scans into {name}", scans.len());
+
+    Ok(())
+}
+
+fn diff_pos(options: &DiffPosOptions) -> Result<(), Box<dyn Error>> {
+    let [ref old_file, ref new_file] = options.position_files[..] else {
+        return Err(
+            "Pass the old and new position file, e.g. svled diff-pos old.json new.json".into(),
+        );
+    };
+
+    let old = ledmap::read_led_map(Path::new(old_file))?;
+    let new = ledmap::read_led_map(Path::new(new_file))?;
+    let report = diff::diff_positions(&old, &new)?;
+    let units = &new.metadata.units;
+
+    let [x, y, z] = report.offset;
+    info!(
+        "Container moved by {x:.2}, {y:.2}, {z:.2} {units} and turned {:.2} degrees, lined up on {} shared LEDs",
+        report.rotation, report.alignment.shared
+    );
+    info!(
+        "Drift after lining up: {:.2} {units} on average, {:.2} at most",
+        report.mean_drift(),
+        report.max_drift()
+    );
+
+    let (found, lost) = report.located_changes();
+    info!("{found} LEDs newly located, {lost} no longer located");
+    for led in report.status_changes() {
+        info!(
+            "LED {}: {} -> {}",
+            led.index,
+            led.old_status.as_str(),
+            led.new_status.as_str()
+        );
+    }
+
+    let mut drifting: Vec<&diff::LedDiff> = report
+        .leds
+        .iter()
+        .filter(|led| led.drift.is_some())
+        .collect();
+    drifting.sort_by(|a, b| b.drift.unwrap_or(0.0).total_cmp(&a.drift.unwrap_or(0.0)));
+    // The ten that drifted most are always shown, the rest with --verbose
+    for (rank, led) in drifting.iter().enumerate() {
+        let message = format!(
+            "LED {} moved {:.2} {units}, drifted {:.2}",
+            led.index,
+            led.displacement.unwrap_or(0.0),
+            led.drift.unwrap_or(0.0)
+        );
+        if rank < 10 {
+            info!("{message}");
+        } else {
+            debug!("{message}");
+        }
+    }
+
+    let histogram = report.histogram(options.bins.unwrap_or(10));
+    let most = histogram
+        .iter()
+        .map(|(_, _, count)| *count)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    info!("Drift histogram ({units}):");
+    for (from, to, count) in histogram {
+        info!(
+            "{from:>8.2} - {to:<8.2} {count:>5} {}",
+            "#".repeat(count * 40 / most)
+        );
+    }
+
+    if let Some(ref image) = options.image {
+        diff::write_diff_image(&new, &report, options.size.unwrap_or(480), 3, image)?;
+        info!("Wrote drift image to {}", image.display());
+    }
+
+    Ok(())
+}
+
+fn convert_ledpos(options: &ConvertLedposOptions) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(&options.input);
+    if !path.exists() {
+        return Err(format!("{} not found", path.display()).into());
+    }
+
+    let output = options.output.as_ref().map(Path::new);
+    let target = match options.target {
+        Some(ref target) => target.parse::<convert::ConvertTarget>()?,
+        None => output
+            .and_then(convert::ConvertTarget::from_path)
+            .unwrap_or(convert::ConvertTarget::Cpp),
+    };
+
+    let convert_options = convert::ConvertOptions {
+        normalize: options
+            .normalize
+            .as_deref()
+            .map(convert::parse_range)
+            .transpose()?,
+        name: options.name.clone().unwrap_or("coords".to_string()),
+    };
+
+    info!("Converting {} for {target:?}", path.display());
+
+    let json = ledmap::read_led_map(path)?;
+
+    match output {
+        Some(output) => {
+            convert::write_convert_file(&json, target, &convert_options, output)?;
+            info!("Wrote {} LED positions to {}", json.len(), output.display());
+        }
+        None if target.is_binary() => {
+            return Err("The binary target needs an --output file".into());
+        }
+        None => {
+            let converted = convert::convert_positions(&json, target, &convert_options)?;
+            print!("{}", String::from_utf8_lossy(&converted));
+        }
+    }
+
+    Ok(())
+}
+
+fn import_positions(command: &ImportPosCommand) -> Result<(), Box<dyn Error>> {
+    let (json, units, output) = match command {
+        ImportPosCommand::Csv(options) => {
+            let units = options.units.as_deref().unwrap_or("mm");
+            (
+                import::read_csv_positions(&options.input, units)?,
+                units,
+                &options.output,
+            )
+        }
+        ImportPosCommand::Cube(options) => {
+            let layout = import::Layout::Cube {
+                width: options.width,
+                height: options.height,
+                depth: options.depth,
+                spacing: options.spacing.unwrap_or(1.0),
+                zigzag: options.zigzag,
+            };
+            let units = options.units.as_deref().unwrap_or("mm");
+            (layout.to_led_map(units), units, &options.output)
+        }
+        ImportPosCommand::Helix(options) => {
+            if options.leds_per_turn <= 0.0 {
+                return Err("--leds-per-turn has to be above 0".into());
+            }
+            let layout = import::Layout::Helix {
+                num_led: options.num_led,
+                radius: options.radius,
+                leds_per_turn: options.leds_per_turn,
+                pitch: options.pitch,
+            };
+            let units = options.units.as_deref().unwrap_or("mm");
+            (layout.to_led_map(units), units, &options.output)
+        }
+        ImportPosCommand::Rings(options) => {
+            let layout = import::Layout::Rings {
+                rings: options.rings,
+                leds_per_ring: options.leds_per_ring,
+                radius: options.radius,
+                spacing: options.spacing.unwrap_or(1.0),
+            };
+            let units = options.units.as_deref().unwrap_or("mm");
+            (layout.to_led_map(units), units, &options.output)
+        }
+        ImportPosCommand::Serpentine(options) => {
+            let layout = import::Layout::Serpentine {
+                width: options.width,
+                height: options.height,
+                panels: options.panels,
+                spacing: options.spacing.unwrap_or(1.0),
+                panel_spacing: options.panel_spacing.unwrap_or(1.0),
+            };
+            let units = options.units.as_deref().unwrap_or("mm");
+            (layout.to_led_map(units), units, &options.output)
+        }
+    };
+
+    if json.is_empty() {
+        return Err("The layout has no LEDs".into());
+    }
+
+    ledmap::write_led_map(&json, output)?;
+    info!(
+        "Wrote {} LED positions in {units} to {}",
+        json.len(),
+        output.display()
+    );
+
+    Ok(())
+}
+
+/// Positions for effects to run on. A scene, given or set in the config, is combined into one world with its
+/// containers numbered back to back, and returned so recordings can be split into one track per container.
+/// Unless `logical` is set, containers are left in strip order rather than the order from `index_map`.
+fn read_world_positions(
+    position_file: Option<&Path>,
+    scene_file: Option<&Path>,
+    config_path: &Path,
+    logical: bool,
+) -> (LedMap, Option<scene::World>) {
+    let scene = match (scene_file, position_file) {
+        (Some(scene_file), _) => scene::Scene::read(scene_file),
+        (None, Some(position_file)) => return (read_positions(position_file), None),
+        (None, None) => {
+            let config_holder = utils::load_validate_conf(config_path).2;
+            if config_holder.unity_options.scene.is_none() {
+                let position_file = &config_holder.unity_options.unity_position_files[0];
+                if logical {
+                    return (read_logical_positions(position_file, &config_holder), None);
+                }
+                return (read_positions(position_file), None);
+            }
+            scene::Scene::from_config(&config_holder)
+        }
+    };
+
+    let scene = scene.map(|mut scene| {
+        if !logical {
+            for container in scene.containers.iter_mut() {
+                container.index_map = None;
+            }
+        }
+        scene
+    });
+
+    match scene.and_then(|scene| scene.world()) {
+        Ok(world) => (world.combined(), Some(world)),
+        Err(e) => {
+            panic!("{e}")
+        }
+    }
+}
+
+fn read_positions(position_file: &Path) -> LedMap {
+    match ledmap::read_led_map(position_file) {
+        Ok(json) => json,
+        Err(e) => {
+            panic!("{e}")
+        }
+    }
+}
+
+/// A position file in the order effects address the LEDs in, set by `[advanced.remap]`
+fn read_logical_positions(position_file: &Path, config_holder: &utils::Config) -> LedMap {
+    let json = read_positions(position_file);
+    match config_holder.advanced.remap.index_map {
+        Some(ref path) => utils::load_index_map(path, json.len() as u32).reorder(&json),
+        None => json,
+    }
+}
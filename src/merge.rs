@@ -0,0 +1,510 @@
+use crate::{
+    ledmap::{LedMap, LedPosition},
+    transform::{self, IDENTITY, Matrix},
+};
+
+/// How one scan was lined up with the first
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alignment {
+    /// Moves the scan onto the first one
+    pub matrix: Matrix,
+    /// LEDs located in both scans, which the alignment is fitted to
+    pub shared: usize,
+    /// Root mean square distance between the shared LEDs after aligning
+    pub rms_error: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeReport {
+    /// One per scan, the first one being left as it is
+    pub alignments: Vec<Alignment>,
+    /// LEDs that weren't located in any scan
+    pub missing: Vec<usize>,
+}
+
+/// Merges scans of the same container. Every scan is rotated and moved onto the first one, fitted by least
+/// squares to the LEDs both located. Each LED is then averaged over the scans that located it, weighted by
+/// confidence, preferring scans that saw its depth. LEDs no scan located keep their most confident observation.
+pub fn merge_scans(scans: &[LedMap]) -> Result<(LedMap, MergeReport), String> {
+    let Some(first) = scans.first() else {
+        return Err("No scans to merge".to_string());
+    };
+
+    if let Some(scan) = scans.iter().find(|scan| scan.len() != first.len()) {
+        return Err(format!(
+            "Scans have to be of the same container, found {} and {} LEDs",
+            first.len(),
+            scan.len()
+        ));
+    }
+    if let Some(scan) = scans
+        .iter()
+        .find(|scan| scan.metadata.units != first.metadata.units)
+    {
+        return Err(format!(
+            "Scans have to be in the same units, found {} and {}",
+            first.metadata.units, scan.metadata.units
+        ));
+    }
+
+    let mut aligned = Vec::with_capacity(scans.len());
+    let mut alignments = Vec::with_capacity(scans.len());
+    for scan in scans {
+        let alignment = align(scan, first);
+        let mut scan = scan.clone();
+        for led in scan.leds.iter_mut() {
+            [led.x, led.y, led.z] = transform::apply(&alignment.matrix, [led.x, led.y, led.z]);
+        }
+        aligned.push(scan);
+        alignments.push(alignment);
+    }
+
+    let mut merged = first.clone();
+    let mut missing = Vec::new();
+    for (index, led) in merged.leds.iter_mut().enumerate() {
+        let observations: Vec<&LedPosition> =
+            aligned.iter().map(|scan| &scan.leds[index]).collect();
+        match merge_led(&observations) {
+            Some(merged_led) => *led = merged_led,
+            None => {
+                missing.push(index);
+                if let Some(best) = observations
+                    .iter()
+                    .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+                {
+                    *led = (*best).clone();
+                }
+            }
+        }
+    }
+    merged.metadata.bounds = merged.bounds();
+
+    Ok((
+        merged,
+        MergeReport {
+            alignments,
+            missing,
+        },
+    ))
+}
+
+/// Confidence weighted average of the observations that located an LED, if any did
+fn merge_led(observations: &[&LedPosition]) -> Option<LedPosition> {
+    let located: Vec<&LedPosition> = observations
+        .iter()
+        .copied()
+        .filter(|led| led.status.has_xy())
+        .collect();
+    let best = located
+        .iter()
+        .max_by(|a, b| a.confidence.total_cmp(&b.confidence))?;
+
+    let average = |leds: &[&&LedPosition], axis: fn(&LedPosition) -> f64| {
+        let total: f64 = leds.iter().map(|led| led.confidence.max(0.0)).sum();
+        if total > 0.0 {
+            leds.iter()
+                .map(|led| axis(led) * led.confidence.max(0.0))
+                .sum::<f64>()
+                / total
+        } else {
+            // Nothing to weigh by, so every scan counts the same
+            leds.iter().map(|led| axis(led)).sum::<f64>() / leds.len() as f64
+        }
+    };
+
+    // Aligning rotates the placeholder depth of front view only observations into x and y, so they are only
+    // used when no scan saw the LED's depth
+    let with_depth: Vec<&&LedPosition> = located.iter().filter(|led| led.status.has_z()).collect();
+    let used: Vec<&&LedPosition> = if with_depth.is_empty() {
+        located.iter().collect()
+    } else {
+        with_depth
+    };
+    let best_used = used
+        .iter()
+        .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+        .unwrap_or(&best);
+
+    Some(LedPosition {
+        x: average(&used, |led| led.x),
+        y: average(&used, |led| led.y),
+        z: average(&used, |led| led.z),
+        status: best_used.status,
+        confidence: best.confidence,
+    })
+}
+
+/// A position in a scan, the same position in the reference, and how much it counts
+type Pair = ([f64; 3], [f64; 3], f64);
+
+/// Rigid transform that best moves the LEDs located in both `scan` and `reference` onto each other, weighted
+/// by the lower of their confidences. When both scans have depth, only LEDs with depth in both are used. Needs
+/// three shared LEDs to rotate, with fewer it only moves the scan.
+pub fn align(scan: &LedMap, reference: &LedMap) -> Alignment {
+    let has_depth = |map: &LedMap| map.leds.iter().any(|led| led.status.has_z());
+    let use_depth = has_depth(scan) && has_depth(reference);
+
+    let pairs: Vec<Pair> = scan
+        .leds
+        .iter()
+        .zip(&reference.leds)
+        .filter(|(a, b)| {
+            a.status.has_xy()
+                && b.status.has_xy()
+                && (!use_depth || (a.status.has_z() && b.status.has_z()))
+        })
+        .map(|(a, b)| {
+            (
+                [a.x, a.y, a.z],
+                [b.x, b.y, b.z],
+                a.confidence.min(b.confidence).max(f64::EPSILON),
+            )
+        })
+        .collect();
+
+    if pairs.is_empty() {
+        return Alignment {
+            matrix: IDENTITY,
+            shared: 0,
+            rms_error: 0.0,
+        };
+    }
+
+    let total: f64 = pairs.iter().map(|(_, _, weight)| weight).sum();
+    let centroid = |point: fn(&Pair) -> [f64; 3]| {
+        [0, 1, 2].map(|axis| {
+            pairs
+                .iter()
+                .map(|pair| point(pair)[axis] * pair.2)
+                .sum::<f64>()
+                / total
+        })
+    };
+    let scan_centroid = centroid(|pair| pair.0);
+    let reference_centroid = centroid(|pair| pair.1);
+
+    let rotation = if pairs.len() >= 3 {
+        // Cross covariance of the centred points, for Horn's quaternion method
+        let mut covariance = [[0.0; 3]; 3];
+        for (a, b, weight) in &pairs {
+            for (row, covariance_row) in covariance.iter_mut().enumerate() {
+                for (column, value) in covariance_row.iter_mut().enumerate() {
+                    *value += weight
+                        * (a[row] - scan_centroid[row])
+                        * (b[column] - reference_centroid[column]);
+                }
+            }
+        }
+        rotation_from_covariance(&covariance)
+    } else {
+        [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+    };
+
+    // Rotate around the scan's centroid, then move it onto the reference's
+    let mut matrix = IDENTITY;
+    for row in 0..3 {
+        matrix[row][..3].copy_from_slice(&rotation[row]);
+        matrix[row][3] = reference_centroid[row]
+            - (0..3)
+                .map(|column| rotation[row][column] * scan_centroid[column])
+                .sum::<f64>();
+    }
+
+    let squared_error: f64 = pairs
+        .iter()
+        .map(|(a, b, _)| {
+            let moved = transform::apply(&matrix, *a);
+            (0..3)
+                .map(|axis| (moved[axis] - b[axis]).powi(2))
+                .sum::<f64>()
+        })
+        .sum();
+
+    Alignment {
+        matrix,
+        shared: pairs.len(),
+        rms_error: (squared_error / pairs.len() as f64).sqrt(),
+    }
+}
+
+/// The rotation maximising agreement for a cross covariance `s`, from the eigenvector of Horn's 4x4 matrix with
+/// the largest eigenvalue
+fn rotation_from_covariance(s: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let n = [
+        [
+            s[0][0] + s[1][1] + s[2][2],
+            s[1][2] - s[2][1],
+            s[2][0] - s[0][2],
+            s[0][1] - s[1][0],
+        ],
+        [
+            s[1][2] - s[2][1],
+            s[0][0] - s[1][1] - s[2][2],
+            s[0][1] + s[1][0],
+            s[2][0] + s[0][2],
+        ],
+        [
+            s[2][0] - s[0][2],
+            s[0][1] + s[1][0],
+            -s[0][0] + s[1][1] - s[2][2],
+            s[1][2] + s[2][1],
+        ],
+        [
+            s[0][1] - s[1][0],
+            s[2][0] + s[0][2],
+            s[1][2] + s[2][1],
+            -s[0][0] - s[1][1] + s[2][2],
+        ],
+    ];
+
+    let (values, vectors) = symmetric_eigen(n);
+    let largest = (0..4)
+        .max_by(|a, b| values[*a].total_cmp(&values[*b]))
+        .unwrap_or(0);
+    let [w, x, y, z] = [0, 1, 2, 3].map(|row| vectors[row][largest]);
+
+    [
+        [
+            w * w + x * x - y * y - z * z,
+            2.0 * (x * y - w * z),
+            2.0 * (x * z + w * y),
+        ],
+        [
+            2.0 * (x * y + w * z),
+            w * w - x * x + y * y - z * z,
+            2.0 * (y * z - w * x),
+        ],
+        [
+            2.0 * (x * z - w * y),
+            2.0 * (y * z + w * x),
+            w * w - x * x - y * y + z * z,
+        ],
+    ]
+}
+
+/// Eigenvalues and eigenvectors (as columns) of a symmetric matrix, with the cyclic Jacobi method
+fn symmetric_eigen(mut a: [[f64; 4]; 4]) -> ([f64; 4], [[f64; 4]; 4]) {
+    let mut vectors = IDENTITY;
+
+    for _ in 0..64 {
+        let off_diagonal: f64 = (0..4)
+            .flat_map(|row| {
+                (0..4)
+                    .filter(move |column| *column != row)
+                    .map(move |column| (row, column))
+            })
+            .map(|(row, column)| a[row][column].powi(2))
+            .sum();
+        if off_diagonal < 1e-22 {
+            break;
+        }
+
+        for p in 0..3 {
+            for q in p + 1..4 {
+                if a[p][q].abs() < 1e-300 {
+                    continue;
+                }
+
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+
+                for row in a.iter_mut() {
+                    let (ap, aq) = (row[p], row[q]);
+                    row[p] = c * ap - s * aq;
+                    row[q] = s * ap + c * aq;
+                }
+                let (row_p, row_q) = (a[p], a[q]);
+                for k in 0..4 {
+                    a[p][k] = c * row_p[k] - s * row_q[k];
+                    a[q][k] = s * row_p[k] + c * row_q[k];
+                }
+                for row in vectors.iter_mut() {
+                    let (vp, vq) = (row[p], row[q]);
+                    row[p] = c * vp - s * vq;
+                    row[q] = s * vp + c * vq;
+                }
+            }
+        }
+    }
+
+    ([0, 1, 2, 3].map(|i| a[i][i]), vectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ledmap::{LedMapMetadata, LedStatus},
+        transform::{TransformStep, multiply},
+    };
+
+    /// A helix, so the LEDs span all three axes and only one rotation fits them
+    fn layout() -> LedMap {
+        let mut layout = LedMap::new(12, LedMapMetadata::default());
+        for (index, led) in layout.leds.iter_mut().enumerate() {
+            let angle = index as f64 * 0.6;
+            *led = LedPosition {
+                x: 100.0 + 50.0 * angle.cos(),
+                y: 20.0 * index as f64,
+                z: 80.0 + 50.0 * angle.sin(),
+                status: LedStatus::SuccessZ,
+                confidence: 1.0,
+            };
+        }
+        layout
+    }
+
+    fn moved(layout: &LedMap, matrix: &Matrix) -> LedMap {
+        let mut moved = layout.clone();
+        for led in moved.leds.iter_mut() {
+            [led.x, led.y, led.z] = transform::apply(matrix, [led.x, led.y, led.z]);
+        }
+        moved
+    }
+
+    /// Rotated around all three axes, then moved
+    fn motion() -> Matrix {
+        [
+            TransformStep::Rotate {
+                axis: 0,
+                degrees: 30.0,
+            },
+            TransformStep::Rotate {
+                axis: 1,
+                degrees: -50.0,
+            },
+            TransformStep::Rotate {
+                axis: 2,
+                degrees: 20.0,
+            },
+            TransformStep::Translate([15.0, -40.0, 7.0]),
+        ]
+        .iter()
+        .fold(IDENTITY, |matrix, step| {
+            multiply(&step.matrix([10.0, 20.0, 30.0]), &matrix)
+        })
+    }
+
+    fn assert_identity(matrix: &Matrix) {
+        for (row, values) in matrix.iter().enumerate() {
+            for (column, value) in values.iter().enumerate() {
+                let expected = if row == column { 1.0 } else { 0.0 };
+                assert!(
+                    (value - expected).abs() < 1e-9,
+                    "{matrix:?} is not the identity"
+                );
+            }
+        }
+    }
+
+    fn observation(position: [f64; 3], status: LedStatus, confidence: f64) -> LedPosition {
+        let [x, y, z] = position;
+        LedPosition {
+            x,
+            y,
+            z,
+            status,
+            confidence,
+        }
+    }
+
+    #[test]
+    fn align_recovers_rotation_and_translation() {
+        let reference = layout();
+        let motion = motion();
+        let scan = moved(&reference, &motion);
+
+        let alignment = align(&scan, &reference);
+
+        assert_eq!(alignment.shared, reference.len());
+        assert!(alignment.rms_error < 1e-9);
+        // Undoes the motion exactly
+        assert_identity(&multiply(&alignment.matrix, &motion));
+    }
+
+    #[test]
+    fn align_ignores_unlocated_leds() {
+        let reference = layout();
+        let motion = motion();
+        let mut scan = moved(&reference, &motion);
+        // Missed LEDs keep a placeholder that would pull the fit off
+        for index in [2, 7] {
+            scan.leds[index] = observation([0.0; 3], LedStatus::Uncalibrated, 0.0);
+        }
+
+        let alignment = align(&scan, &reference);
+
+        assert_eq!(alignment.shared, reference.len() - 2);
+        assert_identity(&multiply(&alignment.matrix, &motion));
+    }
+
+    #[test]
+    fn align_only_moves_with_too_few_leds() {
+        let reference = layout();
+        let mut scan = moved(
+            &reference,
+            &TransformStep::Translate([5.0, 6.0, 7.0]).matrix([0.0; 3]),
+        );
+        for led in scan.leds.iter_mut().skip(2) {
+            led.status = LedStatus::Uncalibrated;
+        }
+
+        let alignment = align(&scan, &reference);
+
+        assert_eq!(alignment.shared, 2);
+        assert_eq!(
+            alignment.matrix,
+            TransformStep::Translate([-5.0, -6.0, -7.0]).matrix([0.0; 3])
+        );
+    }
+
+    #[test]
+    fn merge_led_weighs_by_confidence() {
+        let observations = [
+            observation([0.0, 0.0, 0.0], LedStatus::SuccessZ, 0.75),
+            observation([40.0, 20.0, 80.0], LedStatus::SuccessZ, 0.25),
+            observation([500.0, 500.0, 500.0], LedStatus::Uncalibrated, 1.0),
+        ];
+
+        let merged = merge_led(&observations.iter().collect::<Vec<_>>()).unwrap();
+
+        assert_eq!([merged.x, merged.y, merged.z], [10.0, 5.0, 20.0]);
+        assert_eq!(merged.status, LedStatus::SuccessZ);
+        assert_eq!(merged.confidence, 0.75);
+    }
+
+    #[test]
+    fn merge_led_prefers_observations_with_depth() {
+        let observations = [
+            observation([100.0, 100.0, 0.0], LedStatus::SuccessXy, 1.0),
+            observation([10.0, 20.0, 30.0], LedStatus::SuccessZ, 0.5),
+        ];
+
+        let merged = merge_led(&observations.iter().collect::<Vec<_>>()).unwrap();
+
+        assert_eq!([merged.x, merged.y, merged.z], [10.0, 20.0, 30.0]);
+        assert_eq!(merged.status, LedStatus::SuccessZ);
+        assert_eq!(merged.confidence, 1.0);
+    }
+
+    #[test]
+    fn merge_led_averages_without_confidence() {
+        let observations = [
+            observation([0.0, 0.0, 0.0], LedStatus::SuccessZ, 0.0),
+            observation([10.0, 20.0, 30.0], LedStatus::SuccessZ, 0.0),
+        ];
+
+        let merged = merge_led(&observations.iter().collect::<Vec<_>>()).unwrap();
+
+        assert_eq!([merged.x, merged.y, merged.z], [5.0, 10.0, 15.0]);
+    }
+
+    #[test]
+    fn merge_led_needs_a_located_observation() {
+        let observations = [observation([1.0, 2.0, 3.0], LedStatus::RecalibrateXy, 0.5)];
+
+        assert_eq!(merge_led(&observations.iter().collect::<Vec<_>>()), None);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{Config, ledmap::LedMap};
+
+/// Iterations of solving the front and second camera views against each other. Each one shrinks the error by
+/// roughly the product of how far off-axis an LED is in both views, so a handful is plenty.
+const SOLVE_ITERATIONS: usize = 16;
+
+/// Where the cameras are relative to the container, for turning pixel positions into real ones.
+///
+/// Both cameras are treated as pinhole cameras aimed at the middle of the container. The front camera looks
+/// straight at the front face, and the second camera at the right side, or down at the top when it's overhead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraSetup {
+    /// Focal length of the front camera in pixels
+    pub focal_length: f64,
+    /// Focal length of the second camera in pixels
+    pub camera2_focal_length: f64,
+    /// Distance from the front camera's lens to the front of the container
+    pub camera_distance: f64,
+    /// Distance from the second camera's lens to the side or top of the container
+    pub camera2_distance: f64,
+    /// Width, height and depth of the container
+    pub container_size: [f64; 3],
+    /// The second camera looks down from above instead of in from the side
+    pub overhead: bool,
+}
+
+impl CameraSetup {
+    /// Reads the setup from `[advanced.perspective]`
+    pub fn from_config(config: &Config) -> Result<CameraSetup, String> {
+        let perspective = &config.advanced.perspective;
+
+        let focal_length = |focal_length: Option<f64>, fov: Option<f64>| match (focal_length, fov) {
+            (Some(focal_length), _) => Ok(focal_length),
+            (None, Some(fov)) => match config.camera.video_width {
+                Some(width) => Ok(width / 2.0 / (fov.to_radians() / 2.0).tan()),
+                None => {
+                    Err("fov needs video_width to be set, or set focal_length instead".to_string())
+                }
+            },
+            (None, None) => Err("set either focal_length or fov".to_string()),
+        };
+
+        let main_focal_length = focal_length(perspective.focal_length, perspective.fov)?;
+        let camera2_focal_length = match (perspective.camera2_focal_length, perspective.camera2_fov)
+        {
+            (None, None) => main_focal_length,
+            (focal_length_2, fov_2) => focal_length(focal_length_2, fov_2)?,
+        };
+
+        let Some(camera_distance) = perspective.camera_distance else {
+            return Err("camera_distance is not set".to_string());
+        };
+
+        let container_size = match perspective.container_size.as_deref() {
+            Some(&[width, height, depth]) => [width, height, depth],
+            Some(_) => return Err("container_size needs a width, height and depth".to_string()),
+            None => return Err("container_size is not set".to_string()),
+        };
+
+        let camera2_distance = match perspective.camera2_distance {
+            Some(distance) => distance,
+            None if config.camera.multi_camera => {
+                return Err("camera2_distance is not set".to_string());
+            }
+            None => 0.0,
+        };
+
+        let setup = CameraSetup {
+            focal_length: main_focal_length,
+            camera2_focal_length,
+            camera_distance,
+            camera2_distance,
+            container_size,
+            overhead: config.advanced.camera.cam2_overhead.unwrap_or(false),
+        };
+
+        if [
+            setup.focal_length,
+            setup.camera2_focal_length,
+            setup.camera_distance,
+        ]
+        .into_iter()
+        .chain(setup.container_size)
+        .any(|value| !value.is_finite() || value <= 0.0)
+            || !setup.camera2_distance.is_finite()
+            || setup.camera2_distance < 0.0
+        {
+            return Err(
+                "focal lengths, distances and container_size have to be above 0".to_string(),
+            );
+        }
+
+        Ok(setup)
+    }
+
+    /// Back-projects a position seen `offset` pixels from the middle of the front view into a position relative to
+    /// the middle of the container. `offset2` is how far along the depth of the container the second camera saw
+    /// the LED from its middle, if it did, positive towards the front camera.
+    pub fn back_project(&self, offset: [f64; 2], offset2: Option<f64>) -> [f64; 3] {
+        let [width, height, depth] = self.container_size;
+
+        let mut position = [0.0; 3];
+        for _ in 0..SOLVE_ITERATIONS {
+            // z grows towards the front camera, so LEDs further back have a lower z
+            let front_depth = self.camera_distance + depth / 2.0 - position[2];
+            position[0] = offset[0] * front_depth / self.focal_length;
+            position[1] = offset[1] * front_depth / self.focal_length;
+
+            let Some(offset2) = offset2 else {
+                break;
+            };
+            let side_depth = if self.overhead {
+                self.camera2_distance + height / 2.0 - position[1]
+            } else {
+                self.camera2_distance + width / 2.0 - position[0]
+            };
+            position[2] = offset2 * side_depth / self.camera2_focal_length;
+        }
+
+        position
+    }
+}
+
+/// Turns pixel positions from post processing into positions in mm from the bottom left back corner of the
+/// container, with z growing towards the front camera as it does in scans. The middle of the located LEDs is taken
+/// to be where each camera is aimed. LEDs without a front view position are put in that corner, and LEDs without
+/// depth are placed halfway back.
+pub fn correct_perspective(led_pos: &mut LedMap, setup: &CameraSetup) {
+    let middle = |values: &mut dyn Iterator<Item = f64>| {
+        let (min, max) = values.fold((f64::MAX, f64::MIN), |(min, max), value| {
+            (min.min(value), max.max(value))
+        });
+        if min > max { 0.0 } else { (min + max) / 2.0 }
+    };
+
+    let front = || led_pos.leds.iter().filter(|led| led.status.has_xy());
+    let x_middle = middle(&mut front().map(|led| led.x));
+    let y_middle = middle(&mut front().map(|led| led.y));
+    let z_middle = middle(
+        &mut led_pos
+            .leds
+            .iter()
+            .filter(|led| led.status.has_z())
+            .map(|led| led.z),
+    );
+
+    let [width, height, depth] = setup.container_size;
+    for led in led_pos.leds.iter_mut() {
+        if !led.status.has_xy() {
+            led.x = 0.0;
+            led.y = 0.0;
+            led.z = 0.0;
+            continue;
+        }
+
+        let offset2 = led.status.has_z().then_some(led.z - z_middle);
+        let [x, y, z] = setup.back_project([led.x - x_middle, led.y - y_middle], offset2);
+
+        led.x = x + width / 2.0;
+        led.y = y + height / 2.0;
+        led.z = z + depth / 2.0;
+    }
+
+    led_pos.metadata.units = "mm".to_string();
+}
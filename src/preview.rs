@@ -0,0 +1,334 @@
+use std::{
+    error::Error,
+    f64::consts::TAU,
+    ffi::OsStr,
+    fs::File,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+    str::FromStr,
+    time::Duration,
+};
+
+use image::{
+    Delay, Frame, Rgba, RgbaImage,
+    codecs::gif::{GifEncoder, Repeat},
+};
+use log::info;
+
+use crate::{ledmap::LedMap, vled::Vled};
+
+/// LEDs that are off are still drawn in this color, so the shape of the container stays visible
+pub(crate) const OFF_COLOR: Rgba<u8> = Rgba([40, 40, 40, 255]);
+pub(crate) const BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreviewView {
+    /// x and y, as seen by the first camera
+    Front,
+    /// x and z, looking down
+    Top,
+    /// z and y
+    Side,
+    /// Front, top and side next to each other
+    All,
+    /// A camera circling the container
+    Orbit,
+}
+
+impl FromStr for PreviewView {
+    type Err = String;
+
+    fn from_str(view: &str) -> Result<PreviewView, String> {
+        match view.to_lowercase().as_str() {
+            "front" => Ok(PreviewView::Front),
+            "top" => Ok(PreviewView::Top),
+            "side" => Ok(PreviewView::Side),
+            "all" => Ok(PreviewView::All),
+            "orbit" => Ok(PreviewView::Orbit),
+            view => Err(format!(
+                "Unknown view {view}, expected front, top, side, all or orbit"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PreviewOptions {
+    pub view: PreviewView,
+    pub fps: u32,
+    /// Width and height of each view in pixels
+    pub size: u32,
+    pub dot_radius: u32,
+    /// How long one revolution of the orbit camera takes
+    pub orbit_period: Duration,
+    /// Only show this container of a multi-track recording
+    pub track: Option<u8>,
+}
+
+/// Renders `vled` as coloured dots at the calibrated positions and writes it to `output`. GIFs are encoded
+/// directly, any other extension (e.g. `.mp4`) is encoded by piping the frames through `ffmpeg`.
+/// Returns the number of frames written.
+pub fn write_preview(
+    vled: &Vled,
+    led_pos: &LedMap,
+    options: &PreviewOptions,
+    output: &Path,
+) -> Result<u32, Box<dyn Error>> {
+    let fps = options.fps.max(1);
+    let positions: Vec<(usize, [f64; 3])> = led_pos
+        .located()
+        .map(|(i, led)| (i, [led.x, led.y, led.z]))
+        .collect();
+
+    if positions.is_empty() {
+        return Err("The position file has no calibrated LEDs to draw".into());
+    }
+
+    let panels = if options.view == PreviewView::All {
+        3
+    } else {
+        1
+    };
+    // Video encoders need even dimensions
+    let size = options.size.max(16).next_multiple_of(2);
+    let (width, height) = (size * panels, size);
+
+    let frames = vled.frames(led_pos.len(), Duration::from_secs(1) / fps, options.track);
+    let frame_count = frames.len();
+
+    let mut sink: Box<dyn FrameSink> = if output
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_lowercase)
+        == Some("gif".to_string())
+    {
+        Box::new(GifSink::new(output, fps)?)
+    } else {
+        Box::new(FfmpegSink::new(output, fps, width, height)?)
+    };
+
+    info!(
+        "Rendering {frame_count} frames at {width}x{height} to {}",
+        output.display()
+    );
+
+    let views = match options.view {
+        PreviewView::All => vec![PreviewView::Front, PreviewView::Top, PreviewView::Side],
+        view => vec![view],
+    };
+
+    for (frame_number, colors) in frames.enumerate() {
+        let mut image = RgbaImage::from_pixel(width, height, BACKGROUND);
+        let time = frame_number as f64 / fps as f64;
+
+        for (panel, view) in views.iter().copied().enumerate() {
+            let angle = TAU * time / options.orbit_period.as_secs_f64().max(0.001);
+            let mut dots = project(&positions, view, angle);
+
+            // Draw far away LEDs first so closer ones cover them
+            dots.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+            let offset_x = panel as u32 * size;
+            for (i, _, (u, v)) in dots {
+                let x = offset_x as f64 + u * size as f64;
+                let y = v * size as f64;
+
+                let (r, g, b) = colors[i];
+                if (r, g, b) == (0, 0, 0) {
+                    draw_dot(&mut image, x, y, 1, OFF_COLOR);
+                } else {
+                    draw_dot(&mut image, x, y, options.dot_radius, Rgba([r, g, b, 255]));
+                }
+            }
+        }
+
+        sink.push(image)?;
+    }
+
+    sink.finish()?;
+
+    Ok(frame_count as u32)
+}
+
+/// Projects every position into the unit square of `view`, returning (index, depth, (u, v)).
+/// Both axes share one scale so the container keeps its proportions.
+pub(crate) fn project(
+    positions: &[(usize, [f64; 3])],
+    view: PreviewView,
+    angle: f64,
+) -> Vec<(usize, f64, (f64, f64))> {
+    let points: Vec<(usize, f64, (f64, f64))> = if view == PreviewView::Orbit {
+        // Rotate around the vertical axis through the middle of the container
+        let count = positions.len() as f64;
+        let center_x = positions.iter().map(|(_, p)| p[0]).sum::<f64>() / count;
+        let center_z = positions.iter().map(|(_, p)| p[2]).sum::<f64>() / count;
+        let (sin, cos) = angle.sin_cos();
+
+        positions
+            .iter()
+            .map(|(i, [x, y, z])| {
+                let (dx, dz) = (x - center_x, z - center_z);
+                (*i, dx * sin + dz * cos, (dx * cos - dz * sin, *y))
+            })
+            .collect()
+    } else {
+        positions
+            .iter()
+            .map(|(i, [x, y, z])| match view {
+                PreviewView::Top => (*i, *y, (*x, *z)),
+                PreviewView::Side => (*i, *x, (*z, *y)),
+                _ => (*i, *z, (*x, *y)),
+            })
+            .collect()
+    };
+
+    let (mut min_u, mut max_u, mut min_v, mut max_v) = (f64::MAX, f64::MIN, f64::MAX, f64::MIN);
+    for (_, _, (u, v)) in &points {
+        min_u = min_u.min(*u);
+        max_u = max_u.max(*u);
+        min_v = min_v.min(*v);
+        max_v = max_v.max(*v);
+    }
+
+    let mut range = (max_u - min_u).max(max_v - min_v).max(1.0);
+    if view == PreviewView::Orbit {
+        // Keep the scale fixed while rotating, using the widest the container can get
+        let widest = points
+            .iter()
+            .map(|(_, depth, (u, _))| (depth * depth + u * u).sqrt())
+            .fold(0.0, f64::max)
+            * 2.0;
+        range = widest.max(max_v - min_v).max(1.0);
+        min_u = -range / 2.0;
+        max_u = range / 2.0;
+    }
+
+    // Leave a margin around the edges, and center the shorter axis
+    let scale = 0.9 / range;
+    let pad_u = (1.0 - (max_u - min_u) * scale) / 2.0;
+    let pad_v = (1.0 - (max_v - min_v) * scale) / 2.0;
+
+    points
+        .into_iter()
+        .map(|(i, depth, (u, v))| {
+            (
+                i,
+                depth,
+                (pad_u + (u - min_u) * scale, pad_v + (v - min_v) * scale),
+            )
+        })
+        .collect()
+}
+
+pub(crate) fn draw_dot(image: &mut RgbaImage, x: f64, y: f64, radius: u32, color: Rgba<u8>) {
+    let radius = radius as i64;
+    let (cx, cy) = (x.round() as i64, y.round() as i64);
+
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            if dx * dx + dy * dy > radius * radius {
+                continue;
+            }
+
+            let (px, py) = (cx + dx, cy + dy);
+            if px >= 0 && py >= 0 && (px as u32) < image.width() && (py as u32) < image.height() {
+                image.put_pixel(px as u32, py as u32, color);
+            }
+        }
+    }
+}
+
+/// Receives preview frames one at a time, so long recordings never have to be held in memory
+trait FrameSink {
+    fn push(&mut self, image: RgbaImage) -> Result<(), Box<dyn Error>>;
+    fn finish(self: Box<Self>) -> Result<(), Box<dyn Error>>;
+}
+
+struct GifSink {
+    encoder: GifEncoder<File>,
+    delay: Delay,
+}
+
+impl GifSink {
+    fn new(output: &Path, fps: u32) -> Result<GifSink, Box<dyn Error>> {
+        let file = File::create(output)
+            .map_err(|e| format!("Could not create {}: {e}", output.display()))?;
+        let mut encoder = GifEncoder::new_with_speed(file, 10);
+        encoder.set_repeat(Repeat::Infinite)?;
+
+        Ok(GifSink {
+            encoder,
+            delay: Delay::from_numer_denom_ms(1000, fps),
+        })
+    }
+}
+
+impl FrameSink for GifSink {
+    fn push(&mut self, image: RgbaImage) -> Result<(), Box<dyn Error>> {
+        self.encoder
+            .encode_frame(Frame::from_parts(image, 0, 0, self.delay))?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), Box<dyn Error>> {
+        // The encoder writes the GIF trailer when it is dropped
+        drop(self.encoder);
+        Ok(())
+    }
+}
+
+/// Hands raw frames to ffmpeg, which picks the container and codec from the output extension
+struct FfmpegSink {
+    child: std::process::Child,
+}
+
+impl FfmpegSink {
+    fn new(output: &Path, fps: u32, width: u32, height: u32) -> Result<FfmpegSink, Box<dyn Error>> {
+        let child = Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-loglevel",
+                "error",
+                "-f",
+                "rawvideo",
+                "-pix_fmt",
+                "rgba",
+                "-s",
+            ])
+            .arg(format!("{width}x{height}"))
+            .arg("-r")
+            .arg(fps.to_string())
+            .args(["-i", "-", "-pix_fmt", "yuv420p"])
+            .arg(output)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                format!(
+                    "Could not start ffmpeg, which is needed for anything but GIF previews: {e}"
+                )
+            })?;
+
+        Ok(FfmpegSink { child })
+    }
+}
+
+impl FrameSink for FfmpegSink {
+    fn push(&mut self, image: RgbaImage) -> Result<(), Box<dyn Error>> {
+        let stdin = self.child.stdin.as_mut().ok_or("ffmpeg closed its input")?;
+        stdin.write_all(image.as_raw())?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), Box<dyn Error>> {
+        // Closing stdin tells ffmpeg there are no more frames
+        drop(self.child.stdin.take());
+        let status = self.child.wait()?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("ffmpeg exited with {status}").into())
+        }
+    }
+}
@@ -9,22 +9,59 @@ use std::{
 };
 
 use log::{info, warn};
-use time::Instant;
+use time::{Duration, Instant};
 
-use crate::{led_manager, ManagerData};
+use crate::{ManagerData, led_manager};
 
-pub fn read_vled(manager: &Arc<Mutex<ManagerData>>, file: PathBuf) -> Result<(), Box<dyn Error>> {
+/// Plays back a vled file. For multi-track recordings, `track` limits playback to a single container.
+pub fn read_vled(
+    manager: &Arc<Mutex<ManagerData>>,
+    file: PathBuf,
+    track: Option<u8>,
+) -> Result<(), Box<dyn Error>> {
     if file.extension().and_then(OsStr::to_str) != Some("vled") {
         // Only doing this check because I feel like people are gonna try using bvled files with this function
         warn!("File extension is not a vled file, it may not be read correctly");
     }
     info!("Playing back {}", file.display());
 
+    // Every command is scheduled against the time it was recorded at relative to the start of the file,
+    // so time spent waiting on the controller is absorbed by the next delay instead of accumulating.
+    let playback_start = Instant::now();
+    let mut timeline = Duration::ZERO;
+    let mut max_lag = Duration::ZERO;
+
+    // Container the following commands were recorded from, only set in multi-track recordings
+    let mut current_track: Option<u8> = None;
+
     let mut start = Instant::now();
     let mut packets_per_second = 0;
     if let Ok(lines) = read_lines(file) {
         // Consumes the iterator, returns an (Optional) String
         for mut line in lines.map_while(Result::ok) {
+            if let Some(track_line) = line.strip_prefix("C:") {
+                current_track = match track_line.trim().parse::<u8>() {
+                    Ok(track) => Some(track),
+                    Err(e) => {
+                        panic!("VLED was malformed: Attempted to convert {track_line} to u8: {e}")
+                    }
+                };
+                continue;
+            }
+
+            if let Some(label) = line.strip_prefix("M:") {
+                info!("Reached mark {label}");
+                continue;
+            }
+
+            if let Some(track) = track
+                && current_track.is_some_and(|current_track| current_track != track)
+                && (line.contains("E") || line.contains("|"))
+            {
+                // Belongs to another container, but delays still apply to every track
+                continue;
+            }
+
             if line.contains("E") {
                 // Clear color of index `EN`
                 line.remove(0);
@@ -34,6 +71,7 @@ pub fn read_vled(manager: &Arc<Mutex<ManagerData>>, file: PathBuf) -> Result<(),
                         panic!("VLED was malformed: Attempted to convert {line} to u8: {e}")
                     }
                 };
+                max_lag = max_lag.max(wait_until(playback_start + timeline));
                 led_manager::set_color(manager, index, 0, 0, 0);
                 packets_per_second += 1;
             } else if line.contains("|") {
@@ -48,28 +86,33 @@ pub fn read_vled(manager: &Arc<Mutex<ManagerData>>, file: PathBuf) -> Result<(),
                         }
                     };
                 }
+                max_lag = max_lag.max(wait_until(playback_start + timeline));
                 led_manager::set_color(manager, xs[0], xs[1] as u8, xs[2] as u8, xs[3] as u8);
                 packets_per_second += 1;
             } else if line.contains("T") {
                 line.remove(0);
                 line.remove(0);
-                let sleep = match line.to_string().parse::<u64>() {
-                    Ok(sleep) => sleep,
+                // Older recordings store whole milliseconds, newer ones store fractional milliseconds
+                let sleep = match line.to_string().parse::<f64>() {
+                    Ok(sleep) if sleep.is_finite() && sleep >= 0.0 => sleep,
+                    Ok(sleep) => {
+                        panic!("VLED was malformed: {sleep} is not a valid delay")
+                    }
                     Err(e) => {
-                        panic!("VLED was malformed: Attempted to convert {line} to u64: {e}")
+                        panic!("VLED was malformed: Attempted to convert {line} to f64: {e}")
                     }
                 };
-                // println!("Sleeping for {}");
-                thread::sleep(time::Duration::from_millis(sleep as u64));
+                timeline += Duration::from_secs_f64(sleep / 1000.0);
             } else {
                 warn!("Unable to parse invalid line of vled file: {line}");
             }
 
             if start.elapsed().as_secs() >= 1 {
                 info!(
-                    "{} packets per {} seconds.",
+                    "{} packets per {} seconds, {:.2?} behind the recording.",
                     packets_per_second,
-                    start.elapsed().as_secs()
+                    start.elapsed().as_secs(),
+                    playback_start.elapsed().saturating_sub(timeline)
                 );
                 packets_per_second = 0;
                 start = Instant::now();
@@ -77,9 +120,33 @@ pub fn read_vled(manager: &Arc<Mutex<ManagerData>>, file: PathBuf) -> Result<(),
         }
     }
 
+    // Trailing delays are part of the animation, so honour them before reporting
+    wait_until(playback_start + timeline);
+
+    let played = playback_start.elapsed();
+    info!(
+        "Played back {:.2?} of recording in {:.2?}. Final lag: {:.2?}, worst lag: {:.2?}",
+        timeline,
+        played,
+        played.saturating_sub(timeline),
+        max_lag
+    );
+
     Ok(())
 }
 
+/// Sleeps until `deadline`, returning how far behind it we already were.
+fn wait_until(deadline: Instant) -> Duration {
+    let now = Instant::now();
+
+    if deadline > now {
+        thread::sleep(deadline - now);
+        Duration::ZERO
+    } else {
+        now - deadline
+    }
+}
+
 fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
 where
     P: AsRef<Path>,
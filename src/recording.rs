@@ -0,0 +1,432 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+
+use crate::{ManagerData, vled};
+
+/// Gaps shorter than this are not written to vled files immediately, but are carried over into the next delay.
+const MIN_RECORDED_DELAY_MICROS: u128 = 500;
+
+/// Replaced with the container index in `record_data_file` and `record_esp_data_file`
+pub const CONTAINER_PLACEHOLDER: &str = "{container}";
+
+/// Holds the take currently being recorded, if any. Containers recording into one multi-track file share a slot.
+pub type RecordingSlot = Arc<Mutex<Option<RecordingSession>>>;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingFormat {
+    Vled,
+    Bvled,
+}
+
+/// Whether commands are currently being recorded. Every container shares one of these, so starting or
+/// stopping a recording from any of them (or from the library) applies to all of them.
+#[derive(Debug, Default)]
+pub struct RecordingControl {
+    pub record_data: AtomicBool,
+    pub record_esp_data: AtomicBool,
+}
+
+/// A named point in a recording, e.g. the start of a scene
+#[derive(Serialize, Debug, Clone)]
+pub struct RecordingMark {
+    pub label: String,
+    pub time_ms: f64,
+}
+
+/// A single take being written to disk. Data goes to `<file>.part` and is only renamed to its
+/// final name once the take is finished, so an interrupted session never leaves a half written file behind.
+#[derive(Debug)]
+pub struct RecordingSession {
+    pub format: RecordingFormat,
+    pub path: PathBuf,
+    part_path: PathBuf,
+    writer: BufWriter<File>,
+    started_at: DateTime<Local>,
+    /// Start of the recording timeline, delays are written relative to this
+    started: Instant,
+    /// How much of the timeline has been written to the file as delays
+    recorded: Duration,
+    commands: u64,
+    num_led: u32,
+    /// Container the previous command was recorded for, used to only write track markers on changes
+    current_track: Option<u8>,
+    tracks: Vec<u8>,
+    marks: Vec<RecordingMark>,
+}
+
+/// Written next to a finished recording as `<file>.meta.json`
+#[derive(Serialize, Debug)]
+pub struct RecordingMetadata {
+    pub format: RecordingFormat,
+    pub file: PathBuf,
+    pub started: String,
+    pub finished: String,
+    pub duration_ms: f64,
+    pub commands: u64,
+    pub num_led: u32,
+    /// Containers present in a multi-track recording
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tracks: Vec<u8>,
+    pub marks: Vec<RecordingMark>,
+    pub svled_version: String,
+}
+
+impl RecordingSession {
+    /// Starts a new take based on `requested`. Existing files are never touched, instead the take is
+    /// written to the first free `name-N.ext` (or `name-<timestamp>.ext` when `timestamped` is set).
+    pub fn start(
+        requested: &Path,
+        format: RecordingFormat,
+        timestamped: bool,
+        num_led: u32,
+    ) -> io::Result<RecordingSession> {
+        let started_at = Local::now();
+
+        // Other containers may be starting takes at the same time, so only claim a path we created ourselves
+        let (path, part_path, file) = loop {
+            let path = free_recording_path(requested, timestamped.then_some(&started_at));
+            let part_path = with_suffix(&path, ".part");
+
+            match File::create_new(&part_path) {
+                Ok(file) => break (path, part_path, file),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        };
+
+        debug!("Recording {} to {}", path.display(), part_path.display());
+
+        let writer = BufWriter::new(file);
+
+        info!("Recording to {}", path.display());
+
+        Ok(RecordingSession {
+            format,
+            path,
+            part_path,
+            writer,
+            started_at,
+            started: Instant::now(),
+            recorded: Duration::ZERO,
+            commands: 0,
+            num_led,
+            current_track: None,
+            tracks: Vec::new(),
+            marks: Vec::new(),
+        })
+    }
+
+    /// Appends a color command, preceded by however much time has passed since the last written delay.
+    /// When `track` is set, a `C:<container>` marker is written whenever it differs from the previous command's.
+    pub fn record(&mut self, track: Option<u8>, n: u16, r: u8, g: u8, b: u8) -> io::Result<()> {
+        self.write_pending_delay(MIN_RECORDED_DELAY_MICROS)?;
+
+        match self.format {
+            RecordingFormat::Vled => {
+                if let Some(track) = track
+                    && self.current_track != Some(track)
+                {
+                    writeln!(self.writer, "C:{track}")?;
+                    self.current_track = Some(track);
+                    if !self.tracks.contains(&track) {
+                        self.tracks.push(track);
+                    }
+                }
+                writeln!(self.writer, "{n}|{r}|{g}|{b}")?;
+            }
+            RecordingFormat::Bvled => {
+                let n_bytes = n.to_le_bytes();
+                write!(
+                    self.writer,
+                    "0x{0:02X}, 0x{1:02X}, 0x{2:02X}, 0x{3:02X}, 0x{4:02X}, ",
+                    n_bytes[0], n_bytes[1], r, g, b
+                )?;
+            }
+        }
+
+        self.commands += 1;
+        Ok(())
+    }
+
+    /// Writes however much time has passed since the last written delay, if it is at least `min_micros` long.
+    /// Delays are measured against the start of the recording rather than the previous call,
+    /// so gaps too short to write out are carried into the next delay instead of being lost.
+    fn write_pending_delay(&mut self, min_micros: u128) -> io::Result<()> {
+        let elapsed = self.started.elapsed();
+
+        match self.format {
+            RecordingFormat::Vled => {
+                let micros = elapsed.saturating_sub(self.recorded).as_micros();
+                if micros >= min_micros.max(1) {
+                    writeln!(self.writer, "T:{}.{:03}", micros / 1000, micros % 1000)?;
+                    self.recorded += Duration::from_micros(micros as u64);
+                }
+            }
+            RecordingFormat::Bvled => {
+                // bVLED delays are whole milliseconds, the remainder stays pending for the next command
+                vled::write_bvled_delay(&mut self.writer, elapsed, &mut self.recorded)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a named mark at the current point of the recording. vled files get an `M:<label>` line,
+    /// bvled files can't hold marks so they only end up in the metadata.
+    pub fn mark(&mut self, label: &str) -> io::Result<()> {
+        // Keep marks on one line so they can't be confused with commands
+        let label = label.replace(['\r', '\n'], " ");
+        let time = self.started.elapsed();
+
+        if self.format == RecordingFormat::Vled {
+            let micros = time.saturating_sub(self.recorded).as_micros();
+            if micros > 0 {
+                writeln!(self.writer, "T:{}.{:03}", micros / 1000, micros % 1000)?;
+                self.recorded += Duration::from_micros(micros as u64);
+            }
+            writeln!(self.writer, "M:{label}")?;
+        }
+
+        self.marks.push(RecordingMark {
+            label,
+            time_ms: time.as_secs_f64() * 1000.0,
+        });
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Writes the time since the last command, so the final frame is held for as long as it was on screen,
+    /// then flushes and syncs the take, moves it to its final name and writes the metadata sidecar.
+    pub fn finish(mut self) -> io::Result<PathBuf> {
+        // The sidecar duration is taken from what was written, so this has to come first
+        self.write_pending_delay(0)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        fs::rename(&self.part_path, &self.path)?;
+
+        let metadata = RecordingMetadata {
+            format: self.format,
+            file: self.path.clone(),
+            started: self.started_at.to_rfc3339(),
+            finished: Local::now().to_rfc3339(),
+            duration_ms: self.recorded.as_secs_f64() * 1000.0,
+            commands: self.commands,
+            num_led: self.num_led,
+            tracks: self.tracks.clone(),
+            marks: self.marks.clone(),
+            svled_version: env!("CARGO_PKG_VERSION").to_string(),
+        };
+
+        let metadata_path = with_suffix(&self.path, ".meta.json");
+        let metadata_part_path = with_suffix(&metadata_path, ".part");
+        let json = serde_json::to_string_pretty(&metadata).map_err(io::Error::other)?;
+        fs::write(&metadata_part_path, json)?;
+        fs::rename(&metadata_part_path, &metadata_path)?;
+
+        Ok(self.path)
+    }
+}
+
+/// Finishes a take, logging where it ended up.
+pub fn finish_recording(session: RecordingSession) {
+    let part_path = session.part_path.clone();
+
+    match session.finish() {
+        Ok(path) => info!("Saved recording to {}", path.display()),
+        Err(e) => error!(
+            "Could not finish recording! What was recorded has been left in {}. Error: {}",
+            part_path.display(),
+            e
+        ),
+    }
+}
+
+/// Starts recording `formats` on every container sharing `manager`'s recording control.
+/// The takes themselves are opened on the next color command.
+pub fn start_recording(manager: &Arc<Mutex<ManagerData>>, formats: &[RecordingFormat]) {
+    let manager = manager.lock().unwrap();
+    let control = &manager.state.recording_control;
+
+    for format in formats {
+        info!("Starting {format:?} recording");
+        match format {
+            RecordingFormat::Vled => control.record_data.store(true, Ordering::Relaxed),
+            RecordingFormat::Bvled => control.record_esp_data.store(true, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Stops recording `formats`. Takes belonging to `manager` are finished right away,
+/// other containers finish theirs as soon as they notice.
+pub fn stop_recording(manager: &Arc<Mutex<ManagerData>>, formats: &[RecordingFormat]) {
+    let mut manager = manager.lock().unwrap();
+
+    for format in formats {
+        let was_recording = match format {
+            RecordingFormat::Vled => manager
+                .state
+                .recording_control
+                .record_data
+                .swap(false, Ordering::Relaxed),
+            RecordingFormat::Bvled => manager
+                .state
+                .recording_control
+                .record_esp_data
+                .swap(false, Ordering::Relaxed),
+        };
+
+        if was_recording {
+            info!("Stopping {format:?} recording");
+        }
+    }
+
+    finish_stopped_recordings(&mut manager);
+}
+
+/// Adds a mark to every take `manager` is currently recording.
+pub fn mark_recording(manager: &Arc<Mutex<ManagerData>>, label: &str) {
+    let manager = manager.lock().unwrap();
+    let mut marked = false;
+
+    for slot in [&manager.io.data_recording, &manager.io.esp_data_recording] {
+        if let Some(session) = slot.lock().unwrap().as_mut() {
+            if let Err(e) = session.mark(label) {
+                error!(
+                    "Could not add mark {label} to {}: {e}",
+                    session.path.display()
+                );
+            }
+            marked = true;
+        }
+    }
+
+    if marked {
+        info!("Marked recording: {label}");
+    } else {
+        warn!("Not recording, ignoring mark {label}");
+    }
+}
+
+/// Finishes any takes whose format has been switched off.
+pub fn finish_stopped_recordings(manager: &mut ManagerData) {
+    if !manager
+        .state
+        .recording_control
+        .record_data
+        .load(Ordering::Relaxed)
+        && let Some(session) = manager.io.data_recording.lock().unwrap().take()
+    {
+        finish_recording(session);
+    }
+
+    if !manager
+        .state
+        .recording_control
+        .record_esp_data
+        .load(Ordering::Relaxed)
+        && let Some(session) = manager.io.esp_data_recording.lock().unwrap().take()
+    {
+        finish_recording(session);
+    }
+}
+
+/// Parses the formats of a `RECORD START`/`RECORD STOP` command, `VLED`, `BVLED` or `ALL`.
+pub fn parse_recording_formats(formats: &str) -> Option<Vec<RecordingFormat>> {
+    match formats.trim().to_uppercase().as_str() {
+        "VLED" => Some(vec![RecordingFormat::Vled]),
+        "BVLED" => Some(vec![RecordingFormat::Bvled]),
+        "ALL" => Some(vec![RecordingFormat::Vled, RecordingFormat::Bvled]),
+        _ => None,
+    }
+}
+
+/// Finishes every active take on `manager`. Should be called before exiting.
+pub fn finish_recordings(manager: &mut ManagerData) {
+    if let Some(session) = manager.io.data_recording.lock().unwrap().take() {
+        debug!("Finishing vled recording");
+        finish_recording(session);
+    }
+
+    if let Some(session) = manager.io.esp_data_recording.lock().unwrap().take() {
+        debug!("Finishing bvled recording");
+        finish_recording(session);
+    }
+}
+
+/// Fills in `{container}` in a recording file name. If there is no placeholder but several containers
+/// are recording, the container is appended to the name so they don't end up fighting over the same file.
+pub fn container_recording_path(template: &Path, container: &str, num_container: u8) -> PathBuf {
+    let template_str = template.to_string_lossy();
+
+    if template_str.contains(CONTAINER_PLACEHOLDER) {
+        PathBuf::from(template_str.replace(CONTAINER_PLACEHOLDER, container))
+    } else if num_container > 1 {
+        let stem = template
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or("recording".to_string());
+
+        match template.extension() {
+            Some(extension) => template.with_file_name(format!(
+                "{stem}-{container}.{}",
+                extension.to_string_lossy()
+            )),
+            None => template.with_file_name(format!("{stem}-{container}")),
+        }
+    } else {
+        template.to_path_buf()
+    }
+}
+
+/// Returns `requested` if neither it nor its `.part` file exist, otherwise the first free variant of it.
+pub fn free_recording_path(requested: &Path, timestamp: Option<&DateTime<Local>>) -> PathBuf {
+    let stem = requested
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or("recording".to_string());
+    let extension = requested
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let candidate = |name: String| requested.with_file_name(format!("{name}{extension}"));
+
+    let base = match timestamp {
+        Some(timestamp) => format!("{stem}-{}", timestamp.format("%Y-%m-%d-%H-%M-%S")),
+        None => stem,
+    };
+
+    let is_free = |path: &Path| !path.exists() && !with_suffix(path, ".part").exists();
+
+    let first = candidate(base.clone());
+    if is_free(&first) {
+        return first;
+    }
+
+    (1..)
+        .map(|i| candidate(format!("{base}-{i}")))
+        .find(|path| is_free(path))
+        .unwrap()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
@@ -0,0 +1,246 @@
+use std::{error::Error, f64::consts::TAU, fs, path::Path};
+
+use crate::ledmap::LedMap;
+
+/// Order of the LEDs as effects, Unity and vled files see them, independent of how the strips are wired.
+/// Logical index `i` is physical index `physical[i]` on the strip. Rewiring only means writing a new order,
+/// everything addressing logical indices keeps working.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexMap {
+    physical: Vec<u16>,
+    logical: Vec<u16>,
+}
+
+impl IndexMap {
+    /// Every LED at its own index
+    pub fn identity(num_led: usize) -> IndexMap {
+        let order: Vec<u16> = (0..num_led as u16).collect();
+        IndexMap {
+            physical: order.clone(),
+            logical: order,
+        }
+    }
+
+    /// From the physical index of each logical LED, which has to use every index once
+    pub fn from_order(order: Vec<u16>) -> Result<IndexMap, String> {
+        let mut logical = vec![u16::MAX; order.len()];
+        for (logical_index, physical_index) in order.iter().enumerate() {
+            match logical.get_mut(*physical_index as usize) {
+                Some(slot) if *slot == u16::MAX => *slot = logical_index as u16,
+                Some(_) => return Err(format!("LED {physical_index} is in the order twice")),
+                None => {
+                    return Err(format!(
+                        "LED {physical_index} is out of range for an order of {} LEDs",
+                        order.len()
+                    ));
+                }
+            }
+        }
+
+        Ok(IndexMap {
+            physical: order,
+            logical,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.physical.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.physical.is_empty()
+    }
+
+    /// Physical index of each logical LED
+    pub fn order(&self) -> &[u16] {
+        &self.physical
+    }
+
+    /// Indices past the end of the order are left alone
+    pub fn to_physical(&self, logical: u16) -> u16 {
+        self.physical
+            .get(logical as usize)
+            .copied()
+            .unwrap_or(logical)
+    }
+
+    pub fn to_logical(&self, physical: u16) -> u16 {
+        self.logical
+            .get(physical as usize)
+            .copied()
+            .unwrap_or(physical)
+    }
+
+    /// A scanned position file, which is in strip order, put into logical order
+    pub fn reorder(&self, led_pos: &LedMap) -> LedMap {
+        let mut reordered = led_pos.clone();
+        for (logical, led) in reordered.leds.iter_mut().enumerate() {
+            if let Some(physical) = led_pos.leds.get(self.to_physical(logical as u16) as usize) {
+                *led = physical.clone();
+            }
+        }
+        reordered
+    }
+}
+
+/// Orders the LEDs along an axis, lowest first, or highest first when `reverse` is set. LEDs at the same
+/// height keep their strip order, and LEDs that weren't located come last.
+pub fn sort_along(led_pos: &LedMap, axis: usize, reverse: bool) -> IndexMap {
+    sort_by_key(led_pos, |[x, y, z]| {
+        let value = [x, y, z][axis];
+        if reverse { -value } else { value }
+    })
+}
+
+/// Orders the LEDs in a spiral climbing `axis`, going round the middle of the LEDs `turns` times from bottom to
+/// top. Within each turn LEDs are ordered by their angle around the axis. A fractional `turns` makes the top turn
+/// shorter than the others, and LEDs that weren't located come last. `turns` has to be above 0.
+pub fn spiral(led_pos: &LedMap, axis: usize, turns: f64) -> IndexMap {
+    let (min, max, middle) = match led_pos.bounds() {
+        Some(bounds) => (bounds.min, bounds.max, bounds.center()),
+        None => ([0.0; 3], [0.0; 3], [0.0; 3]),
+    };
+    let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+    let height = (max[axis] - min[axis]).max(f64::EPSILON);
+    // The LEDs at the very top would otherwise start a turn of their own
+    let last_turn = turns.ceil().max(1.0) - 1.0;
+
+    sort_by_key(led_pos, |point| {
+        let turn = ((point[axis] - min[axis]) / height * turns)
+            .floor()
+            .min(last_turn);
+        let angle = (point[b] - middle[b])
+            .atan2(point[a] - middle[a])
+            .rem_euclid(TAU);
+        turn + angle / TAU
+    })
+}
+
+/// Reads an order file, a JSON array of the physical index of each logical LED, e.g. `[3, 2, 1, 0]`
+pub fn read_index_map(path: &Path) -> Result<IndexMap, Box<dyn Error>> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+    let order: Vec<u16> = serde_json::from_str(&contents)
+        .map_err(|e| format!("{} is not a valid order file: {e}", path.display()))?;
+
+    IndexMap::from_order(order)
+        .map_err(|e| format!("{} is not a valid order file: {e}", path.display()).into())
+}
+
+pub fn write_index_map(map: &IndexMap, path: &Path) -> Result<(), Box<dyn Error>> {
+    fs::write(path, serde_json::to_string(map.order())?)
+        .map_err(|e| format!("Could not write {}: {e}", path.display()))?;
+
+    Ok(())
+}
+
+/// Sorts the located LEDs by `key`, then appends the rest in strip order, as their positions are placeholders
+fn sort_by_key(led_pos: &LedMap, key: impl Fn([f64; 3]) -> f64) -> IndexMap {
+    let mut order: Vec<(u16, f64)> = led_pos
+        .located()
+        .map(|(index, led)| (index as u16, key([led.x, led.y, led.z])))
+        .collect();
+    order.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    let unlocated = led_pos
+        .leds
+        .iter()
+        .enumerate()
+        .filter(|(_, led)| !led.status.is_located())
+        .map(|(index, _)| index as u16);
+
+    IndexMap::from_order(
+        order
+            .into_iter()
+            .map(|(index, _)| index)
+            .chain(unlocated)
+            .collect(),
+    )
+    .expect("Sorting keeps every index once")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledmap::{LedMapMetadata, LedPosition, LedStatus};
+
+    /// LEDs at the given heights, with `None` for ones that weren't located
+    fn column(heights: &[Option<f64>]) -> LedMap {
+        let mut led_pos = LedMap::new(heights.len(), LedMapMetadata::default());
+        for (led, height) in led_pos.leds.iter_mut().zip(heights) {
+            *led = match height {
+                Some(y) => LedPosition {
+                    x: 0.0,
+                    y: *y,
+                    z: 0.0,
+                    status: LedStatus::SuccessZ,
+                    confidence: 1.0,
+                },
+                None => LedPosition {
+                    x: 0.0,
+                    y: -100.0,
+                    z: 0.0,
+                    status: LedStatus::Uncalibrated,
+                    confidence: 0.0,
+                },
+            };
+        }
+        led_pos
+    }
+
+    #[test]
+    fn from_order_maps_both_ways() {
+        let map = IndexMap::from_order(vec![2, 0, 1]).unwrap();
+
+        assert_eq!(map.order(), &[2, 0, 1]);
+        assert_eq!(map.to_physical(0), 2);
+        assert_eq!(map.to_logical(2), 0);
+        assert_eq!(map.to_logical(map.to_physical(1)), 1);
+        // Past the end of the order nothing is remapped
+        assert_eq!(map.to_physical(7), 7);
+        assert_eq!(map.to_logical(7), 7);
+    }
+
+    #[test]
+    fn from_order_rejects_duplicates() {
+        assert_eq!(
+            IndexMap::from_order(vec![0, 1, 1]),
+            Err("LED 1 is in the order twice".to_string())
+        );
+    }
+
+    #[test]
+    fn from_order_rejects_out_of_range() {
+        assert_eq!(
+            IndexMap::from_order(vec![0, 3, 1]),
+            Err("LED 3 is out of range for an order of 3 LEDs".to_string())
+        );
+    }
+
+    #[test]
+    fn reorder_puts_positions_in_logical_order() {
+        let led_pos = column(&[Some(0.0), Some(1.0), Some(2.0)]);
+        let map = IndexMap::from_order(vec![2, 0, 1]).unwrap();
+
+        let heights: Vec<f64> = map.reorder(&led_pos).leds.iter().map(|led| led.y).collect();
+        assert_eq!(heights, [2.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn reorder_leaves_leds_past_a_shorter_map() {
+        let led_pos = column(&[Some(0.0), Some(1.0), Some(2.0), Some(3.0)]);
+        let map = IndexMap::from_order(vec![1, 0]).unwrap();
+
+        let heights: Vec<f64> = map.reorder(&led_pos).leds.iter().map(|led| led.y).collect();
+        assert_eq!(heights, [1.0, 0.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn sort_along_appends_unlocated_leds() {
+        let led_pos = column(&[Some(3.0), None, Some(1.0), None, Some(2.0)]);
+
+        assert_eq!(sort_along(&led_pos, 1, false).order(), &[2, 4, 0, 1, 3]);
+        assert_eq!(sort_along(&led_pos, 1, true).order(), &[0, 4, 2, 1, 3]);
+        assert_eq!(spiral(&led_pos, 1, 1.0).order()[3..], [1, 3]);
+    }
+}
@@ -0,0 +1,151 @@
+use std::{error::Error, time::Duration};
+
+use crate::{
+    demo::{self, Axis, LedOutput},
+    ledmap::LedMap,
+    vled::{Rgb, Vled, VledEvent, VledEventKind},
+};
+
+/// Effects that can be rendered with `render_effect`
+pub const EFFECTS: [&str; 4] = ["rainbow", "rainbow-loop", "image", "plasma"];
+
+/// Runs effects on a virtual clock instead of the wall clock, sampling the LEDs at a fixed frame rate
+/// and recording every change between frames. Nothing is sent to a controller.
+pub struct FrameRenderer {
+    frame_interval: Duration,
+    duration: Duration,
+    /// Virtual time the effect has waited up to
+    time: Duration,
+    /// What the effect has drawn so far
+    frame: Vec<Rgb>,
+    /// What the recording shows after the last sampled frame
+    shown: Vec<Rgb>,
+    frames_sampled: u32,
+    vled: Vled,
+}
+
+impl FrameRenderer {
+    pub fn new(num_led: usize, frame_interval: Duration, duration: Duration) -> FrameRenderer {
+        FrameRenderer {
+            frame_interval,
+            duration,
+            time: Duration::ZERO,
+            frame: vec![(0, 0, 0); num_led],
+            shown: vec![(0, 0, 0); num_led],
+            frames_sampled: 0,
+            vled: Vled::default(),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.time >= self.duration
+    }
+
+    /// Samples the remaining frames, holding whatever the effect drew last, and returns the recording.
+    pub fn finish(mut self) -> Vled {
+        self.sample_frames_before(self.duration);
+        self.vled.duration = self.duration;
+        self.vled
+    }
+
+    /// Records every frame due before `time`, each showing the current state of `frame`
+    fn sample_frames_before(&mut self, time: Duration) {
+        let end = time.min(self.duration);
+
+        loop {
+            let frame_time = self.frame_interval * self.frames_sampled;
+            if frame_time >= end {
+                break;
+            }
+
+            for (index, (color, shown)) in self.frame.iter().zip(self.shown.iter_mut()).enumerate()
+            {
+                if color != shown {
+                    *shown = *color;
+                    self.vled.events.push(VledEvent {
+                        time: frame_time,
+                        kind: VledEventKind::Color {
+                            track: None,
+                            index: index as u16,
+                            r: color.0,
+                            g: color.1,
+                            b: color.2,
+                        },
+                    });
+                }
+            }
+
+            self.frames_sampled += 1;
+        }
+    }
+}
+
+impl LedOutput for FrameRenderer {
+    fn set_color(&mut self, n: u16, r: u8, g: u8, b: u8) {
+        let n = n as usize;
+        if n >= self.frame.len() {
+            self.frame.resize(n + 1, (0, 0, 0));
+            self.shown.resize(n + 1, (0, 0, 0));
+        }
+        self.frame[n] = (r, g, b);
+    }
+
+    fn wait(&mut self, delay: Duration) -> bool {
+        // Everything drawn so far stays visible until the end of the delay
+        self.sample_frames_before(self.time + delay);
+        self.time += delay;
+
+        !self.is_finished()
+    }
+}
+
+/// Renders one of `EFFECTS` against `led_pos` into a recording of `duration`, sampled at `fps`.
+/// Effects that finish early hold their last frame until the end.
+pub fn render_effect(
+    effect: &str,
+    led_pos: &LedMap,
+    fps: u32,
+    duration: Duration,
+    image_path: Option<&str>,
+) -> Result<Vled, Box<dyn Error>> {
+    let mut renderer =
+        FrameRenderer::new(led_pos.len(), Duration::from_secs(1) / fps.max(1), duration);
+
+    match effect.to_lowercase().as_str() {
+        "rainbow" => {
+            rainbow_sweeps(&mut renderer, led_pos);
+        }
+        "rainbow-loop" => {
+            // Passes advance the virtual clock until the duration is reached, unless there is nothing to sweep
+            loop {
+                let pass_start = renderer.time;
+                if !rainbow_sweeps(&mut renderer, led_pos) || renderer.time == pass_start {
+                    break;
+                }
+            }
+        }
+        "image" => {
+            let Some(image_path) = image_path else {
+                return Err("The image effect needs an image to render".into());
+            };
+            demo::render_jpg_to(&mut renderer, image_path, led_pos, Some(0..=250));
+        }
+        "plasma" => demo::plasma_to(&mut renderer, led_pos),
+        effect => {
+            return Err(format!(
+                "Unknown effect {effect}, expected one of {}",
+                EFFECTS.join(", ")
+            )
+            .into());
+        }
+    }
+
+    Ok(renderer.finish())
+}
+
+/// The sweeps used by `svled demo --active-demo rainbow`
+fn rainbow_sweeps(output: &mut impl LedOutput, led_pos: &LedMap) -> bool {
+    demo::rainbow_to(output, led_pos, 80, 50, false, Axis::X, true)
+        && demo::rainbow_to(output, led_pos, 50, 50, false, Axis::Y, true)
+        && demo::rainbow_to(output, led_pos, 80, 50, false, Axis::Z, true)
+}
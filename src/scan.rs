@@ -1,5 +1,4 @@
 use std::{
-    cmp::{max, min},
     error::Error,
     fs::File,
     io::Write,
@@ -22,27 +21,62 @@ use opencv::{
     videoio::{self, CAP_PROP_FRAME_HEIGHT, CAP_PROP_FRAME_WIDTH, VideoCapture},
 };
 
-use crate::{Config, CropPos, ManagerData, PosEntry, ScanData, led_manager};
+use crate::{
+    Config, CropPos, ManagerData, ScanData, analysis,
+    frame_source::{CameraOpener, FrameSource, SharedFrameSource},
+    led_manager,
+    ledmap::{LedMap, LedMapMetadata, LedPosition, LedStatus},
+    perspective,
+    scan_ui::{HighguiUi, ScanUi},
+    structured,
+};
 
 type ScanResult = Result<(i32, i32, Option<i32>, Option<i32>), Box<dyn Error>>;
 type CropData = Option<((i32, i32, i32, i32), (i32, i32, i32, i32))>;
 type CallbackResult = (i32, i32, Option<i32>, Option<i32>);
 
+/// Scans with the cameras and windows from the config, then saves the positions
 pub fn scan(
     config: Config,
     manager_guard: &Arc<Mutex<ManagerData>>,
     streamlined: bool,
     crop_data: CropData,
 ) -> Result<()> {
+    let opener = |second_cam| open_camera(&config, second_cam);
+    let led_pos = scan_with(
+        config.clone(),
+        manager_guard,
+        streamlined,
+        crop_data,
+        &opener,
+        &HighguiUi,
+    )?;
+
+    // Color filtered scans always ask, as they weren't streamlined
+    save_scan(&led_pos, !streamlined || config.scan.scan_mode == 1);
+
+    Ok(())
+}
+
+/// Scans the LEDs of `manager_guard`, reading frames from whatever `open_camera` opens and showing progress on `ui`.
+/// Returns the positions, post processed and adjusted as configured. Pair a `VirtualStrip` as the manager's LED
+/// sink with an `ImageDirectory` or `SyntheticFrames` and `HeadlessUi` to scan without any hardware.
+pub fn scan_with(
+    config: Config,
+    manager_guard: &Arc<Mutex<ManagerData>>,
+    streamlined: bool,
+    crop_data: CropData,
+    open_camera: CameraOpener,
+    ui: &dyn ScanUi,
+) -> Result<LedMap> {
     // streamlined skips cropping and ALL prompts, thus requiring multiple cameras to function
-    let mut led_pos = vec![
-        (
-            "UNCALIBRATED".to_string(),
-            (i32::MIN, i32::MIN),
-            (i32::MIN, i32::MIN)
-        );
-        config.num_led as usize
-    ];
+    if !streamlined && !ui.interactive() {
+        return Err(anyhow!(
+            "Only streamlined scans can run without a display, as cropping and manual calibration need one"
+        ));
+    }
+
+    let mut led_pos = LedMap::new(config.num_led as usize, LedMapMetadata::for_scan(&config));
     let num_led;
     let scan_mode;
     let filter_color;
@@ -50,13 +84,15 @@ pub fn scan(
         num_led = manager_guard.lock().unwrap().config.num_led;
         scan_mode = manager_guard.lock().unwrap().config.scan_mode;
         filter_color = manager_guard.lock().unwrap().config.filter_color;
+        // Scans find where each LED is on the strip, so they address strip indices
+        manager_guard.lock().unwrap().config.index_map = None;
     }
     info!("Clearing strip");
     for i in 0..=num_led {
         led_manager::set_color(manager_guard, i.try_into().unwrap(), 0, 0, 0);
     }
 
-    if scan_mode != 0 {
+    if scan_mode == 1 {
         if filter_color.unwrap() == 0 {
             debug!("Using red filter");
         } else if filter_color.unwrap() == 1 {
@@ -70,7 +106,7 @@ pub fn scan(
     if let Some(no_video) = config.advanced.camera.no_video
         && !no_video
     {
-        highgui::named_window(window, highgui::WINDOW_AUTOSIZE)?;
+        ui.named_window(window)?;
     }
 
     let mut pos = CropPos {
@@ -91,35 +127,32 @@ pub fn scan(
 
     if !streamlined {
         info!("Starting crop");
-        pos = match crop(&config, manager_guard) {
+        pos = match crop(&config, manager_guard, open_camera) {
             Ok(pos) => pos,
             Err(e) => return Err(anyhow!("There was a problem while trying to crop: {e}")),
         };
     }
 
-    let cam = Arc::new(Mutex::new(
-        get_cam(&config, &config.camera.camera_index_1.to_string()).unwrap(),
-    )); // We need to constantly poll this in the background to get the most recent frame due to OpenCV bug(?)
+    let cam = open_camera(false).map_err(|e| anyhow!("Unable to open the first camera: {e}"))?;
+    let cam = Arc::new(Mutex::new(cam)); // We need to constantly poll this in the background to get the most recent frame due to OpenCV bug(?)
 
     if config.camera.video_width.is_some() && config.camera.video_height.is_some() {
-        cam.lock()
-            .unwrap()
-            .set(CAP_PROP_FRAME_WIDTH, config.camera.video_width.unwrap())?;
-        cam.lock()
-            .unwrap()
-            .set(CAP_PROP_FRAME_HEIGHT, config.camera.video_height.unwrap())?;
+        cam.lock().unwrap().set_frame_size(
+            config.camera.video_width.unwrap(),
+            config.camera.video_height.unwrap(),
+        )?;
     }
 
-    let mut cam2: Option<Arc<Mutex<VideoCapture>>> = None;
+    let mut cam2: Option<SharedFrameSource> = None;
 
     let cam_guard = Arc::clone(&cam);
-    let cam2_guard: Arc<Mutex<VideoCapture>>;
+    let cam2_guard: SharedFrameSource;
 
     let keepalive = Arc::new(Mutex::new(true));
 
     let thread1_keepalive_guard = Arc::clone(&keepalive);
 
-    match videoio::VideoCapture::is_opened(&cam.lock().unwrap())? {
+    match cam.lock().unwrap().is_opened()? {
         true => {}
         false => {
             return Err(anyhow!(
@@ -153,16 +186,8 @@ pub fn scan(
             pos.x2_start = Some(0);
             pos.y2_start = Some(0);
 
-            pos.x1_end = cam
-                .lock()
-                .unwrap()
-                .get(opencv::videoio::CAP_PROP_FRAME_WIDTH)
-                .unwrap() as i32; // TODO: Real error handling
-            pos.y1_end = cam
-                .lock()
-                .unwrap()
-                .get(opencv::videoio::CAP_PROP_FRAME_HEIGHT)
-                .unwrap() as i32;
+            pos.x1_end = cam.lock().unwrap().frame_size()?.0; // TODO: Real error handling
+            pos.y1_end = cam.lock().unwrap().frame_size()?.1;
         } else if let Some(crop_data) = crop_data {
             pos.x1_start = crop_data.0.0;
             pos.x1_end = crop_data.0.1;
@@ -177,44 +202,25 @@ pub fn scan(
 
         if config.camera.multi_camera {
             debug!("Getting second cam limits");
-            cam2 = Some(Arc::new(Mutex::new(
-                get_cam(&config, &config.camera.camera_index_2.clone().unwrap()).unwrap(),
-            )));
+            let cam =
+                open_camera(true).map_err(|e| anyhow!("Unable to open the second camera: {e}"))?;
+            cam2 = Some(Arc::new(Mutex::new(cam)));
 
             if config.camera.video_width.is_some() && config.camera.video_height.is_some() {
-                cam2.as_ref()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
-                    .set(CAP_PROP_FRAME_WIDTH, config.camera.video_width.unwrap())?;
-                cam2.as_ref()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
-                    .set(CAP_PROP_FRAME_HEIGHT, config.camera.video_height.unwrap())?;
+                cam2.as_ref().unwrap().lock().unwrap().set_frame_size(
+                    config.camera.video_width.unwrap(),
+                    config.camera.video_height.unwrap(),
+                )?;
             }
 
-            pos.x2_end = Some(
-                cam2.as_ref()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
-                    .get(opencv::videoio::CAP_PROP_FRAME_WIDTH)
-                    .unwrap() as i32,
-            ); // Sometimes OpenCV will silently fail to set the width/height, so we can't rely on config.video_width here
-            pos.y2_end = Some(
-                cam2.as_ref()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
-                    .get(opencv::videoio::CAP_PROP_FRAME_HEIGHT)
-                    .unwrap() as i32,
-            );
+            pos.x2_end = Some(cam2.as_ref().unwrap().lock().unwrap().frame_size()?.0); // Sometimes OpenCV will silently fail to set the width/height, so we can't rely on config.video_width here
+            pos.y2_end = Some(cam2.as_ref().unwrap().lock().unwrap().frame_size()?.1);
         }
     }
 
+    // The color to filter for is picked by hand, unless nobody is there to pick it
     let streamlined = {
-        if config.scan.scan_mode == 1 {
+        if config.scan.scan_mode == 1 && ui.interactive() {
             false
         } else {
             streamlined
@@ -248,27 +254,21 @@ pub fn scan(
     );
 
     if config.camera.multi_camera && !streamlined {
-        highgui::named_window(window, highgui::WINDOW_AUTOSIZE)?;
-        cam2 = Some(Arc::new(Mutex::new(
-            get_cam(&config, &config.camera.camera_index_2.clone().unwrap()).unwrap(),
-        )));
+        ui.named_window(window)?;
+        let cam =
+            open_camera(true).map_err(|e| anyhow!("Unable to open the second camera: {e}"))?;
+        cam2 = Some(Arc::new(Mutex::new(cam)));
 
         if config.camera.video_width.is_some() && config.camera.video_height.is_some() {
-            cam2.as_ref()
-                .unwrap()
-                .lock()
-                .unwrap()
-                .set(CAP_PROP_FRAME_WIDTH, config.camera.video_width.unwrap())?;
-            cam2.as_ref()
-                .unwrap()
-                .lock()
-                .unwrap()
-                .set(CAP_PROP_FRAME_HEIGHT, config.camera.video_height.unwrap())?;
+            cam2.as_ref().unwrap().lock().unwrap().set_frame_size(
+                config.camera.video_width.unwrap(),
+                config.camera.video_height.unwrap(),
+            )?;
         }
 
         cam2_guard = Arc::clone(cam2.as_ref().unwrap());
 
-        match videoio::VideoCapture::is_opened(&cam2_guard.lock().unwrap())? {
+        match cam2_guard.lock().unwrap().is_opened()? {
             true => {}
             false => {
                 return Err(anyhow!(
@@ -370,7 +370,7 @@ pub fn scan(
         }
     }
 
-    highgui::destroy_all_windows().unwrap();
+    ui.destroy_all_windows().unwrap();
 
     let data = Arc::new(Mutex::new(ScanData {
         pos,
@@ -386,6 +386,7 @@ pub fn scan(
         cam2.as_ref(),
         &mut led_pos,
         data.clone(),
+        ui,
     ) {
         Ok((success, failures, success_cam_2, failures_cam_2)) => {
             (success, failures, success_cam_2, failures_cam_2)
@@ -417,11 +418,11 @@ pub fn scan(
         info!(
             "Please rotate the container 180 degrees to recalibrate failures. Press space to continue."
         );
-        highgui::set_window_title(
+        ui.set_window_title(
             window,
             "Please rotate the container 180 degrees to recalibrate failures. Press space to continue.",
         )?;
-        match wait(data.clone(), &cam, window) {
+        match wait(data.clone(), &cam, window, ui) {
             Ok(_) => {}
             Err(e) => {
                 panic!(
@@ -438,6 +439,7 @@ pub fn scan(
             cam2.as_ref(),
             &mut led_pos,
             data.clone(),
+            ui,
         ) {
             Ok((cam_1_success, cam_1_failures, cam_2_success, cam_2_failures)) => {
                 (cam_1_success, cam_1_failures, cam_2_success, cam_2_failures)
@@ -469,7 +471,7 @@ pub fn scan(
             }
         }
         info!("Please rotate the container 270 degrees to calibrate Z. Press space to continue."); // The LEDS will be 180 degrees away from the original position, and they need to be rotated 270 degrees in this case to go to the appropriate Z calibration position.
-        highgui::set_window_title(
+        ui.set_window_title(
             window,
             "Please rotate the container 270 degrees to calibrate Z. Press space to continue.",
         )?;
@@ -480,12 +482,12 @@ pub fn scan(
             info!(
                 "Please rotate the container 90 degrees to calibrate Z. Press space to continue."
             );
-            highgui::set_window_title(
+            ui.set_window_title(
                 window,
                 "Please rotate the container 90 degrees to calibrate Z. Press space to continue.",
             )?;
         }
-        match wait(data.clone(), &cam, window) {
+        match wait(data.clone(), &cam, window, ui) {
             Ok(_) => {}
             Err(e) => {
                 panic!(
@@ -509,6 +511,7 @@ pub fn scan(
             cam2.as_ref(),
             &mut led_pos,
             data.clone(),
+            ui,
         ) {
             Ok((cam_1_success, cam_1_failures, cam_2_success, cam_2_failures)) => {
                 (cam_1_success, cam_1_failures, cam_2_success, cam_2_failures)
@@ -539,11 +542,11 @@ pub fn scan(
             info!(
                 "Please rotate the container 180 degrees to recalibrate failures. Press space to continue."
             );
-            highgui::set_window_title(
+            ui.set_window_title(
                 window,
                 "Please rotate the container 180 degrees to recalibrate failures. Press space to continue.",
             )?;
-            match wait(data.clone(), &cam, window) {
+            match wait(data.clone(), &cam, window, ui) {
                 Ok(_) => {}
                 Err(e) => {
                     panic!(
@@ -560,6 +563,7 @@ pub fn scan(
                 cam2.as_ref(),
                 &mut led_pos,
                 data.clone(),
+                ui,
             ) {
                 Ok((cam_1_success, cam_1_failures, cam_2_success, cam_2_failures)) => {
                     (cam_1_success, cam_1_failures, cam_2_success, cam_2_failures)
@@ -608,7 +612,32 @@ pub fn scan(
         cam2.unwrap().lock().unwrap().release().unwrap();
     }
 
-    highgui::destroy_all_windows().unwrap();
+    ui.destroy_all_windows().unwrap();
+
+    // Continuity only depends on distances, so it is checked before post processing moves anything
+    let report = analysis::check_continuity(
+        &mut led_pos,
+        config.num_strips as usize,
+        analysis::DEFAULT_OUTLIER_THRESHOLD,
+    );
+    if !report.outliers.is_empty() {
+        warn!(
+            "{} LEDs are far from their neighbours on the strip and were marked for recalibration: {:?}",
+            report.outliers.len(),
+            report
+                .outliers
+                .iter()
+                .map(|outlier| outlier.index)
+                .collect::<Vec<usize>>()
+        );
+    }
+
+    if let Some(interpolate) = config.advanced.transform.interpolate_after_scan
+        && interpolate
+    {
+        let interpolated = analysis::interpolate_missing(&mut led_pos, config.num_strips as usize);
+        info!("Interpolated LEDs {interpolated:?} from their neighbours on the strip");
+    }
 
     post_process(&mut led_pos, manager_guard.lock().unwrap().config.num_led);
 
@@ -616,10 +645,17 @@ pub fn scan(
         && adjust
     {
         info!("Performing position adjustment");
-        position_adjustment(&mut led_pos, &config);
+        if let Err(e) = position_adjustment(&mut led_pos, &config) {
+            error!("{e}, keeping positions in pixels");
+        }
     }
 
-    if !streamlined {
+    Ok(led_pos)
+}
+
+/// Writes a scan to a file named by the user when `prompt` is set, or to streamlined.json
+fn save_scan(led_pos: &LedMap, prompt: bool) {
+    if prompt {
         loop {
             let date = Local::now();
             let name = inquire::Text::new(&format!(
@@ -633,8 +669,7 @@ pub fn scan(
                     if name.is_empty() {
                         name = format!("{}-ledpos.json", date.format("%Y-%m-%d-%H:%M:%S"));
                     }
-                    let json = serde_json::to_string_pretty(&led_pos)
-                        .expect("Unable to serialize metadata!");
+                    let json = led_pos.to_json();
                     let mut file = match File::create(Path::new(&name)) {
                         Ok(file) => file,
                         Err(e) => {
@@ -670,7 +705,7 @@ pub fn scan(
     } else {
         let name = "streamlined.json";
 
-        let json = serde_json::to_string_pretty(&led_pos).expect("Unable to serialize metadata!");
+        let json = led_pos.to_json();
         let mut file = match File::create(Path::new(&name)) {
             Ok(file) => file,
             Err(e) => {
@@ -698,12 +733,10 @@ pub fn scan(
 
         info!("Scan exiting!");
     }
-
-    Ok(())
 }
 
 fn brightest_darkest(
-    cam: &Arc<Mutex<VideoCapture>>,
+    cam: &SharedFrameSource,
     config: &Config,
     manager: &Arc<Mutex<ManagerData>>,
     x_start: i32,
@@ -833,7 +866,7 @@ fn brightest_darkest(
 }
 
 pub fn select_brightest(
-    cam: &Arc<Mutex<VideoCapture>>,
+    cam: &SharedFrameSource,
     manager: &Arc<Mutex<ManagerData>>,
     config: &Config,
 ) -> Result<(i32, i32), Box<dyn Error>> {
@@ -955,7 +988,7 @@ pub fn select_brightest(
     let mut cam = cam.lock().unwrap();
     highgui::set_window_title(window, "Modify upper and lower bounds").unwrap();
 
-    match videoio::VideoCapture::is_opened(&cam)? {
+    match cam.is_opened()? {
         true => {}
         false => {
             panic!("Unable to open camera!")
@@ -1160,7 +1193,11 @@ pub fn select_brightest(
     Ok((*x1.lock().unwrap(), *y1.lock().unwrap()))
 }
 
-pub fn crop(config: &Config, manager: &Arc<Mutex<ManagerData>>) -> Result<CropPos, Box<dyn Error>> {
+pub fn crop(
+    config: &Config,
+    manager: &Arc<Mutex<ManagerData>>,
+    open_camera: CameraOpener,
+) -> Result<CropPos, Box<dyn Error>> {
     if config.advanced.transform.crop_override.is_some() {
         let crop_override = config.advanced.transform.crop_override.clone().unwrap();
         if config.camera.multi_camera {
@@ -1239,22 +1276,16 @@ pub fn crop(config: &Config, manager: &Arc<Mutex<ManagerData>>) -> Result<CropPo
         y2_end_guard.clone(),
     )?;
 
-    let cam_guard = Arc::new(Mutex::new(
-        get_cam(config, &config.camera.camera_index_1).unwrap(),
-    )); // callback_loop only accepts a Arc<Mutex<VideoCapture>>
+    let cam_guard = Arc::new(Mutex::new(open_camera(false)?)); // callback_loop only accepts a SharedFrameSource
 
     if config.camera.video_width.is_some() && config.camera.video_height.is_some() {
-        cam_guard
-            .lock()
-            .unwrap()
-            .set(CAP_PROP_FRAME_WIDTH, config.camera.video_width.unwrap())?;
-        cam_guard
-            .lock()
-            .unwrap()
-            .set(CAP_PROP_FRAME_HEIGHT, config.camera.video_height.unwrap())?;
+        cam_guard.lock().unwrap().set_frame_size(
+            config.camera.video_width.unwrap(),
+            config.camera.video_height.unwrap(),
+        )?;
     }
 
-    match videoio::VideoCapture::is_opened(&cam_guard.lock().unwrap())? {
+    match cam_guard.lock().unwrap().is_opened()? {
         true => {}
         false => {
             panic!(
@@ -1309,20 +1340,16 @@ pub fn crop(config: &Config, manager: &Arc<Mutex<ManagerData>>) -> Result<CropPo
     if let Some(index) = &config.camera.camera_index_2 {
         debug!("Cropping second camera with index {index}");
         *camera_active.lock().unwrap() = 1;
-        let cam_guard = Arc::new(Mutex::new(get_cam(config, index).unwrap()));
+        let cam_guard = Arc::new(Mutex::new(open_camera(true)?));
 
         if config.camera.video_width.is_some() && config.camera.video_height.is_some() {
-            cam_guard
-                .lock()
-                .unwrap()
-                .set(CAP_PROP_FRAME_WIDTH, config.camera.video_width.unwrap())?;
-            cam_guard
-                .lock()
-                .unwrap()
-                .set(CAP_PROP_FRAME_HEIGHT, config.camera.video_height.unwrap())?;
+            cam_guard.lock().unwrap().set_frame_size(
+                config.camera.video_width.unwrap(),
+                config.camera.video_height.unwrap(),
+            )?;
         }
 
-        match videoio::VideoCapture::is_opened(&cam_guard.lock().unwrap())? {
+        match cam_guard.lock().unwrap().is_opened()? {
             true => {}
             false => {
                 panic!("Unable to open camera {index}! Please select another.")
@@ -1440,7 +1467,7 @@ pub fn set_crop_mouse_callback(
 }
 
 fn callback_loop(
-    cam: &Arc<Mutex<VideoCapture>>,
+    cam: &SharedFrameSource,
     manager: &Arc<Mutex<ManagerData>>,
     x_start: Arc<Mutex<i32>>,
     y_start: Arc<Mutex<i32>>,
@@ -1455,7 +1482,7 @@ fn callback_loop(
     let mut cam = cam.lock().unwrap();
     highgui::set_window_title(window, &msg).unwrap();
 
-    match videoio::VideoCapture::is_opened(&cam)? {
+    match cam.is_opened()? {
         true => {}
         false => {
             panic!("Unable to open camera!")
@@ -1632,10 +1659,11 @@ pub fn get_brightest_cam_1_pos(mut frame: Mat) -> (f64, f64, Point) {
 pub fn scan_area(
     manager: &Arc<Mutex<ManagerData>>,
     config: &Config,
-    cam: &Arc<Mutex<videoio::VideoCapture>>,
-    cam2: Option<&Arc<Mutex<videoio::VideoCapture>>>,
-    led_pos: &mut PosEntry,
+    cam: &SharedFrameSource,
+    cam2: Option<&SharedFrameSource>,
+    led_pos: &mut LedMap,
     scan_data: Arc<Mutex<ScanData>>,
+    ui: &dyn ScanUi,
 ) -> ScanResult {
     let scan_data = &mut *scan_data.lock().unwrap();
 
@@ -1644,11 +1672,11 @@ pub fn scan_area(
 
     if let Some(no_video) = config.advanced.camera.no_video {
         if !no_video {
-            highgui::named_window(cam_1_window, highgui::WINDOW_AUTOSIZE)?;
+            ui.named_window(cam_1_window)?;
         }
 
         if config.camera.multi_camera && !no_video {
-            highgui::named_window(cam_2_window, highgui::WINDOW_AUTOSIZE)?;
+            ui.named_window(cam_2_window)?;
         }
     }
 
@@ -1658,14 +1686,21 @@ pub fn scan_area(
     let mut cam_2_success = None;
     let mut cam_2_failures = None;
 
-    for i in 0..config.num_led {
-        let valid_cycle = if scan_data.depth {
-            led_pos[i as usize].0 != "SUCCESS-Z"
-        } else {
-            !led_pos[i as usize].0.contains("SUCCESS")
-        };
+    if config.scan.scan_mode == 2 {
+        scan_area_structured(
+            manager,
+            config,
+            cam,
+            cam2,
+            led_pos,
+            scan_data,
+            [cam_1_window, cam_2_window],
+            ui,
+        )?;
+    }
 
-        if valid_cycle {
+    for i in 0..config.num_led {
+        if needs_scan(&led_pos.leds[i as usize], scan_data.depth) {
             debug!("calling scan_area_cycle for cam1");
             (success, failures) = scan_area_cycle(
                 manager,
@@ -1676,6 +1711,7 @@ pub fn scan_area(
                 i,
                 false,
                 cam_1_window,
+                ui,
             )
             .unwrap();
 
@@ -1690,6 +1726,7 @@ pub fn scan_area(
                     i,
                     true,
                     cam_2_window,
+                    ui,
                 )
                 .unwrap();
                 (cam_2_success, cam_2_failures) =
@@ -1816,34 +1853,18 @@ pub fn filter(mut frame: &mut Mat, filter_color: &u32, manager: &Arc<Mutex<Manag
 fn scan_area_cycle(
     manager: &Arc<Mutex<ManagerData>>,
     config: &Config,
-    cam: Option<&Arc<Mutex<VideoCapture>>>,
+    cam: Option<&SharedFrameSource>,
     scan_data: &mut ScanData,
-    led_pos: &mut PosEntry,
+    led_pos: &mut LedMap,
     i: u32,
     second_cam: bool,
     window: &str,
+    ui: &dyn ScanUi,
 ) -> Result<(i32, i32), Box<dyn Error>> {
-    let capture_frames = config.advanced.camera.capture_frames.unwrap_or(3);
-
     let mut success = 0;
     let mut failures = 0;
 
-    let x_start;
-    let x_end;
-    let y_start;
-    let y_end;
-
-    if !second_cam {
-        x_start = scan_data.pos.x1_start;
-        x_end = scan_data.pos.x1_end;
-        y_start = scan_data.pos.y1_start;
-        y_end = scan_data.pos.y1_end;
-    } else {
-        x_start = scan_data.pos.x2_start.unwrap();
-        x_end = scan_data.pos.x2_end.unwrap();
-        y_start = scan_data.pos.y2_start.unwrap();
-        y_end = scan_data.pos.y2_end.unwrap();
-    }
+    let (_, _, y_start, y_end) = crop_bounds(scan_data, second_cam);
 
     let filter_color = manager.lock().unwrap().config.filter_color.unwrap();
     let scan_mode = manager.lock().unwrap().config.scan_mode;
@@ -1881,9 +1902,144 @@ fn scan_area_cycle(
         );
     }
 
+    let mut frame = capture_area(cam.unwrap(), config, scan_data, second_cam)?;
+
+    if scan_mode == 1 {
+        debug!("applying filter");
+        filter(&mut frame, &filter_color, manager);
+    }
+
+    let (_, max_val, pos) = get_brightest_cam_1_pos(frame.try_clone()?);
+
+    let darkest = scan_data.pos.cam_1_darkest.unwrap();
+    let brightest = scan_data.pos.cam_1_brightest.unwrap();
+    let confidence =
+        ((max_val - darkest) / (brightest - darkest).max(f64::EPSILON)).clamp(0.0, 1.0);
+    let led = &mut led_pos.leds[i as usize];
+
+    led.status = {
+        if max_val >= darkest + ((brightest - darkest) * 0.5) {
+            debug!("Succesful xy calibration: {pos:?} on index: {i}");
+            success += 1;
+            imgproc::circle(
+                &mut frame,
+                pos,
+                20,
+                Scalar::new(0.0, 255.0, 0.0, 255.0),
+                2,
+                LINE_8,
+                0,
+            )?;
+
+            if scan_data.depth || second_cam {
+                LedStatus::SuccessZ
+            } else {
+                LedStatus::SuccessXy
+            }
+        } else {
+            debug!("Failed xy calibration: {pos:?} on index: {i}");
+            failures += 1;
+            imgproc::circle(
+                &mut frame,
+                pos,
+                20,
+                Scalar::new(0.0, 0.0, 255.0, 255.0),
+                2,
+                LINE_8,
+                0,
+            )?;
+
+            if scan_data.depth {
+                LedStatus::RecalibrateZ
+            } else {
+                LedStatus::RecalibrateXy
+            }
+        }
+    };
+
+    if scan_data.depth || second_cam {
+        led.z = depth_from(config, pos.x as f64, pos.y as f64, y_start, y_end);
+        // A position is only as good as the weakest camera that saw it
+        led.confidence = led.confidence.min(confidence);
+    } else {
+        led.x = pos.x as f64;
+        led.y = pos.y as f64;
+        led.confidence = confidence;
+    }
+
+    // Update frame_cam_x after all our modifications
+    if second_cam {
+        manager.lock().unwrap().vision.frame_cam_2 = frame.clone();
+    } else {
+        manager.lock().unwrap().vision.frame_cam_1 = frame.clone();
+    }
+
+    if let Some(no_video) = config.advanced.camera.no_video
+        && !no_video
+    {
+        ui.set_window_title(window, &("LED index: ".to_owned() + &i.to_string()))?;
+        ui.imshow(window, &frame)?;
+    }
+
+    ui.wait_key(1)?;
+    led_manager::set_color(manager, i.try_into().unwrap(), 0, 0, 0);
+    Ok((success, failures))
+}
+
+/// Whether an LED still has to be found in this pass
+fn needs_scan(led: &LedPosition, depth: bool) -> bool {
+    if depth {
+        led.status != LedStatus::SuccessZ
+    } else {
+        !led.status.is_success()
+    }
+}
+
+/// The cropped area of a camera as x start, x end, y start and y end
+fn crop_bounds(scan_data: &ScanData, second_cam: bool) -> (i32, i32, i32, i32) {
+    if !second_cam {
+        (
+            scan_data.pos.x1_start,
+            scan_data.pos.x1_end,
+            scan_data.pos.y1_start,
+            scan_data.pos.y1_end,
+        )
+    } else {
+        (
+            scan_data.pos.x2_start.unwrap(),
+            scan_data.pos.x2_end.unwrap(),
+            scan_data.pos.y2_start.unwrap(),
+            scan_data.pos.y2_end.unwrap(),
+        )
+    }
+}
+
+/// Depth of an LED seen at `x`, `y` by the second camera, or by the first one once the container is turned
+fn depth_from(config: &Config, x: f64, y: f64, y_start: i32, y_end: i32) -> f64 {
+    if config.advanced.camera.cam2_overhead.unwrap_or(false) {
+        if config.advanced.camera.cam2_overhead_flip.unwrap_or(false) {
+            y
+        } else {
+            (y_start + y_end) as f64 - y
+        }
+    } else {
+        x
+    }
+}
+
+/// Grabs a fresh frame of the cropped area, mirrored when scanning from behind
+fn capture_area(
+    cam: &SharedFrameSource,
+    config: &Config,
+    scan_data: &ScanData,
+    second_cam: bool,
+) -> Result<Mat, Box<dyn Error>> {
+    let capture_frames = config.advanced.camera.capture_frames.unwrap_or(3);
+    let (x_start, x_end, y_start, y_end) = crop_bounds(scan_data, second_cam);
+
     let mut frame = Mat::default();
     {
-        let mut cam = cam.unwrap().lock().unwrap();
+        let mut cam = cam.lock().unwrap();
 
         // if config.camera.video_width.is_some() && config.camera.video_height.is_some() {
         //     cam.set(CAP_PROP_FRAME_WIDTH, config.camera.video_width.unwrap())?;
@@ -1930,111 +2086,132 @@ fn scan_area_cycle(
         flip(&frame.clone(), &mut frame, 1).unwrap();
     }
 
-    if scan_mode == 1 {
-        debug!("applying filter");
-        filter(&mut frame, &filter_color, manager);
-    }
-
-    let (_, max_val, pos) = get_brightest_cam_1_pos(frame.try_clone()?);
+    Ok(frame)
+}
 
-    led_pos[i as usize] = {
-        let status_msg = {
-            if max_val
-                >= scan_data.pos.cam_1_darkest.unwrap()
-                    + ((scan_data.pos.cam_1_brightest.unwrap()
-                        - scan_data.pos.cam_1_darkest.unwrap())
-                        * 0.5)
-            {
-                debug!("Succesful xy calibration: {pos:?} on index: {i}");
-                success += 1;
-                imgproc::circle(
-                    &mut frame,
-                    pos,
-                    20,
-                    Scalar::new(0.0, 255.0, 0.0, 255.0),
-                    2,
-                    LINE_8,
-                    0,
-                )?;
+/// Finds every LED at once by lighting them in Gray code patterns and their inverses, see `structured`. Only LEDs
+/// every camera could read the index of are placed, the rest are left for `scan_area` to light one at a time.
+#[allow(clippy::too_many_arguments)]
+fn scan_area_structured(
+    manager: &Arc<Mutex<ManagerData>>,
+    config: &Config,
+    cam: &SharedFrameSource,
+    cam2: Option<&SharedFrameSource>,
+    led_pos: &mut LedMap,
+    scan_data: &ScanData,
+    windows: [&str; 2],
+    ui: &dyn ScanUi,
+) -> Result<(), Box<dyn Error>> {
+    let num_led = config.num_led as usize;
+    let bits = structured::bit_planes(num_led);
+    let brightness = config.scan.color_bright.unwrap();
 
-                if scan_data.depth || second_cam {
-                    "SUCCESS-Z"
-                } else {
-                    "SUCCESS-XY"
-                }
-            } else {
-                debug!("Failed xy calibration: {pos:?} on index: {i}");
-                failures += 1;
-                imgproc::circle(
-                    &mut frame,
-                    pos,
-                    20,
-                    Scalar::new(0.0, 0.0, 255.0, 255.0),
-                    2,
-                    LINE_8,
-                    0,
-                )?;
+    let mut cams = vec![(cam, false)];
+    if config.camera.multi_camera
+        && let Some(cam2) = cam2
+    {
+        cams.push((cam2, true));
+    }
 
-                if scan_data.depth {
-                    "RECALIBRATE-Z"
+    info!("Lighting {} patterns to find {num_led} LEDs", bits * 2);
+    let mut captures: Vec<Vec<Mat>> = vec![Vec::new(); cams.len()];
+    for bit in 0..bits {
+        for inverse in [false, true] {
+            for index in 0..num_led {
+                let level = if structured::is_lit(index, bit, inverse) {
+                    brightness
                 } else {
-                    "RECALIBRATE-XY"
-                }
+                    0
+                };
+                led_manager::set_color(manager, index as u16, level, level, level);
             }
-        };
 
-        if scan_data.depth || second_cam {
-            if config.advanced.camera.cam2_overhead.unwrap_or(false) {
-                if config.advanced.camera.cam2_overhead_flip.unwrap_or(false) {
-                    (
-                        status_msg.to_string(),
-                        led_pos[i as usize].1,
-                        (pos.y, pos.x),
-                    )
-                } else {
-                    (
-                        status_msg.to_string(),
-                        led_pos[i as usize].1,
-                        (y_start + y_end - pos.y, pos.x),
-                    )
+            for (captures, (cam, second_cam)) in captures.iter_mut().zip(&cams) {
+                let frame = capture_area(cam, config, scan_data, *second_cam)?;
+
+                if let Some(no_video) = config.advanced.camera.no_video
+                    && !no_video
+                {
+                    let window = windows[*second_cam as usize];
+                    ui.set_window_title(
+                        window,
+                        &format!("Pattern {} of {}", captures.len() + 1, bits * 2),
+                    )?;
+                    ui.imshow(window, &frame)?;
                 }
-            } else {
-                (
-                    status_msg.to_string(),
-                    led_pos[i as usize].1,
-                    (pos.x, pos.y),
-                )
+
+                captures.push(structured::prepare(&frame)?);
             }
-        } else {
-            (
-                status_msg.to_string(),
-                (pos.x, pos.y),
-                led_pos[i as usize].2,
-            )
+            ui.wait_key(1)?;
         }
-    };
+    }
 
-    // Update frame_cam_x after all our modifications
-    if second_cam {
-        manager.lock().unwrap().vision.frame_cam_2 = frame.clone();
-    } else {
-        manager.lock().unwrap().vision.frame_cam_1 = frame.clone();
+    for index in 0..num_led {
+        led_manager::set_color(manager, index as u16, 0, 0, 0);
     }
 
-    if let Some(no_video) = config.advanced.camera.no_video
-        && !no_video
-    {
-        highgui::set_window_title(window, &("LED index: ".to_owned() + &i.to_string()))?;
-        highgui::imshow(window, &frame)?;
+    let darkest = scan_data.pos.cam_1_darkest.unwrap();
+    let brightest = scan_data.pos.cam_1_brightest.unwrap();
+    let threshold = darkest + (brightest - darkest) * 0.5;
+
+    let decoded = captures
+        .iter()
+        .map(|captures| structured::decode(captures, threshold, num_led))
+        .collect::<opencv::Result<Vec<_>>>()?;
+    for (decoded, (_, second_cam)) in decoded.iter().zip(&cams) {
+        let camera = if *second_cam { "second" } else { "first" };
+        info!(
+            "The {camera} camera read the index of {} LEDs, {} were ambiguous",
+            decoded.found(),
+            decoded.ambiguous.len()
+        );
+        if !decoded.ambiguous.is_empty() {
+            debug!(
+                "Ambiguous LEDs on the {camera} camera: {:?}",
+                decoded.ambiguous
+            );
+        }
     }
 
-    highgui::wait_key(1)?;
-    led_manager::set_color(manager, i.try_into().unwrap(), 0, 0, 0);
-    Ok((success, failures))
+    let mut placed = 0;
+    for index in 0..num_led {
+        if !needs_scan(&led_pos.leds[index], scan_data.depth) {
+            continue;
+        }
+        let Some(blobs) = decoded
+            .iter()
+            .map(|decoded| decoded.blobs[index].as_ref())
+            .collect::<Option<Vec<_>>>()
+        else {
+            continue;
+        };
+
+        for (blob, (_, second_cam)) in blobs.into_iter().zip(&cams) {
+            let (_, _, y_start, y_end) = crop_bounds(scan_data, *second_cam);
+            let confidence =
+                ((blob.peak - darkest) / (brightest - darkest).max(f64::EPSILON)).clamp(0.0, 1.0);
+            let led = &mut led_pos.leds[index];
+
+            if scan_data.depth || *second_cam {
+                led.z = depth_from(config, blob.x, blob.y, y_start, y_end);
+                led.confidence = led.confidence.min(confidence);
+                led.status = LedStatus::SuccessZ;
+            } else {
+                led.x = blob.x;
+                led.y = blob.y;
+                led.confidence = confidence;
+                led.status = LedStatus::SuccessXy;
+            }
+        }
+        placed += 1;
+    }
+
+    info!("Placed {placed} LEDs from the patterns, the rest are scanned one at a time");
+    Ok(())
 }
 
-pub fn failed_calibration(led_pos: PosEntry) -> String {
-    let json = serde_json::to_string_pretty(&led_pos).expect("Unable to serialize metadata!");
+pub fn failed_calibration(led_pos: LedMap) -> String {
+    let json = led_pos.to_json();
 
     let date = Local::now();
     let path = format!("temp-pos-{}", date.format("%Y-%m-%d-%H:%M:%S"));
@@ -2047,8 +2224,9 @@ pub fn failed_calibration(led_pos: PosEntry) -> String {
 
 pub fn wait(
     scan_data_lock: Arc<Mutex<ScanData>>,
-    cam: &Arc<Mutex<videoio::VideoCapture>>,
+    cam: &SharedFrameSource,
     window: &str,
+    ui: &dyn ScanUi,
 ) -> Result<(), Box<dyn Error>> {
     let scan_data = scan_data_lock.lock().unwrap();
     loop {
@@ -2067,13 +2245,14 @@ pub fn wait(
                 height: scan_data.pos.y1_end - scan_data.pos.y1_start,
             },
         )
-        .unwrap();
+        .unwrap()
+        .try_clone()?;
 
         if frame.size()?.width > 0 {
-            highgui::imshow(window, &cropped_image)?;
+            ui.imshow(window, &cropped_image)?;
         }
 
-        let key = highgui::wait_key(10)?;
+        let key = ui.wait_key(10)?;
         if key == 32 && key != 255 {
             break;
         }
@@ -2085,8 +2264,8 @@ pub fn manual_calibrate(
     manager_guard: &Arc<Mutex<ManagerData>>,
     config: &Config,
     window: &str,
-    cam: &Arc<Mutex<videoio::VideoCapture>>,
-    led_pos: &mut PosEntry,
+    cam: &SharedFrameSource,
+    led_pos: &mut LedMap,
     scan_data_guard: &Arc<Mutex<ScanData>>,
 ) -> Result<()> {
     let scan_data = scan_data_guard.lock().unwrap();
@@ -2155,27 +2334,37 @@ pub fn manual_calibrate(
         }
 
         let mut color = Scalar::new(0.0, 0.0, 255.0, 255.0);
-        if !led_pos[led_index].0.contains("RECALIBRATE") {
+        if !matches!(
+            led_pos.leds[led_index].status,
+            LedStatus::RecalibrateXy | LedStatus::RecalibrateZ
+        ) {
             color = Scalar::new(0.0, 255.0, 0.0, 255.0);
         }
         let pos;
         if scan_data.depth {
             if !*callback_called.lock().unwrap() {
                 debug!("pos is from depth, callback uncalled.");
-                pos = match led_pos[led_index].2 {
-                    (i32::MIN, i32::MIN) => {
+                let led = &led_pos.leds[led_index];
+                pos = match led.status {
+                    LedStatus::SuccessZ
+                    | LedStatus::RecalibrateZ
+                    | LedStatus::ManualZ
+                    | LedStatus::Interpolated => {
+                        Point::new(led.z.round() as i32, led.y.round() as i32)
+                    }
+                    _ => {
                         error!("led_pos does not contain any depth data, setting to 0, 0!");
                         Point::new(0, 0)
                     }
-                    pos => Point::new(pos.0, pos.1),
                 };
             } else {
                 debug!("pos is from callback");
-                led_pos[led_index].0 = "MANUAL-Z".to_string();
-                led_pos[led_index].2 = {
+                led_pos.leds[led_index].status = LedStatus::ManualZ;
+                led_pos.leds[led_index].confidence = 1.0;
+                led_pos.leds[led_index].z = {
                     if config.advanced.camera.cam2_overhead.unwrap_or(false) {
                         if config.advanced.camera.cam2_overhead_flip.unwrap_or(false) {
-                            (*y_click.lock().unwrap(), *x_click.lock().unwrap())
+                            *y_click.lock().unwrap() as f64
                         } else {
                             let (y_start, y_end) = {
                                 if config.camera.multi_camera {
@@ -2188,13 +2377,10 @@ pub fn manual_calibrate(
                                     (scan_data.pos.y1_start, scan_data.pos.y1_end)
                                 }
                             };
-                            (
-                                y_start + y_end - *y_click.lock().unwrap(),
-                                *x_click.lock().unwrap(),
-                            )
+                            (y_start + y_end - *y_click.lock().unwrap()) as f64
                         }
                     } else {
-                        (*x_click.lock().unwrap(), *y_click.lock().unwrap())
+                        *x_click.lock().unwrap() as f64
                     }
                 };
                 pos = Point::new(*x_click.lock().unwrap(), *y_click.lock().unwrap());
@@ -2202,15 +2388,19 @@ pub fn manual_calibrate(
                 *callback_called.lock().unwrap() = false;
             }
         } else if !*callback_called.lock().unwrap() {
+            let led = &led_pos.leds[led_index];
             debug!(
-                "pos not from depth, from led_pos[led_index].1 which is {:?}",
-                led_pos[led_index].1
+                "pos not from depth, from led_pos[led_index] which is {:?}",
+                (led.x, led.y)
             );
-            pos = Point::new(led_pos[led_index].1.0, led_pos[led_index].1.1)
+            pos = Point::new(led.x.round() as i32, led.y.round() as i32)
         } else {
             debug!("pos not from depth, from callback");
-            led_pos[led_index].0 = "MANUAL-XY".to_string();
-            led_pos[led_index].1 = (*x_click.lock().unwrap(), *y_click.lock().unwrap());
+            let led = &mut led_pos.leds[led_index];
+            led.status = LedStatus::ManualXy;
+            led.confidence = 1.0;
+            led.x = *x_click.lock().unwrap() as f64;
+            led.y = *y_click.lock().unwrap() as f64;
             pos = Point::new(*x_click.lock().unwrap(), *y_click.lock().unwrap());
             color = Scalar::new(0.0, 255.0, 0.0, 255.0);
             *callback_called.lock().unwrap() = false;
@@ -2255,7 +2445,10 @@ pub fn manual_calibrate(
                 let led_begin = led_index; // Needed because of clippy::mut_range_bound
                 for _ in led_begin..config.num_led.try_into().unwrap() {
                     led_index += 1;
-                    if led_pos[led_index].0.contains("RECALIBRATE") {
+                    if matches!(
+                        led_pos.leds[led_index].status,
+                        LedStatus::RecalibrateXy | LedStatus::RecalibrateZ
+                    ) {
                         break;
                     }
                 }
@@ -2268,157 +2461,98 @@ pub fn manual_calibrate(
     Ok(())
 }
 
-pub fn post_process(led_pos: &mut PosEntry, led_count: u32) {
-    let mut x_max = i32::MIN;
-    let mut y_max = i32::MIN;
-    let mut z_max = i32::MIN;
+pub fn post_process(led_pos: &mut LedMap, led_count: u32) {
+    let leds = &mut led_pos.leds[..led_count as usize];
+
+    let mut x_max = f64::MIN;
+    let mut y_max = f64::MIN;
+    let mut z_max = f64::MIN;
 
-    let mut x_min = i32::MAX;
-    let mut y_min = i32::MAX;
-    let mut z_min = i32::MAX;
+    let mut x_min = f64::MAX;
+    let mut y_min = f64::MAX;
+    let mut z_min = f64::MAX;
 
-    for i in 0..led_count {
+    // LEDs that weren't found hold placeholder positions, which would stretch the bounds
+    for led in leds.iter().filter(|led| led.status.has_xy()) {
         // Get max and min values in led_pos
-        x_max = max(led_pos[i as usize].1.0, x_max);
-        x_min = min(led_pos[i as usize].1.0, x_min);
+        x_max = led.x.max(x_max);
+        x_min = led.x.min(x_min);
 
-        y_max = max(led_pos[i as usize].1.1, y_max);
-        y_min = min(led_pos[i as usize].1.1, y_min);
+        y_max = led.y.max(y_max);
+        y_min = led.y.min(y_min);
+    }
+    for led in leds.iter().filter(|led| led.status.has_z()) {
+        z_min = led.z.min(z_min);
+        z_max = led.z.max(z_max);
+    }
 
-        z_min = min(led_pos[i as usize].2.0, z_min);
-        z_max = max(led_pos[i as usize].2.0, z_max);
+    if x_min == f64::MAX {
+        return;
+    }
+    if z_min == f64::MAX {
+        z_min = 0.0;
+        z_max = 0.0;
     }
 
-    for i in 0..led_count {
+    for led in leds.iter_mut() {
         // Normalize values
-        led_pos[i as usize].1.0 -= x_min;
-        led_pos[i as usize].1.1 -= y_min;
-        led_pos[i as usize].2.0 -= z_min;
+        if led.status.has_xy() {
+            led.x -= x_min;
+            led.y -= y_min;
+        }
+        if led.status.has_z() {
+            led.z -= z_min;
+        }
     }
 
-    for i in 0..led_count {
-        let y_mid = y_max / 2;
-        let current_y = led_pos[i as usize].1.1;
+    // Positions are whole camera pixels, keep the middle on one as well
+    let y_mid = (y_max / 2.0).trunc();
+    let z_mid = (z_max / 2.0).trunc();
 
-        let z_mid = z_max / 2;
-        let current_z = led_pos[i as usize].2.0;
-
-        led_pos[i as usize].1.1 = match current_y {
-            y if y > y_mid => y_mid - (y - y_mid),
-            y if y < y_mid => y_mid + (y_mid - y),
-            _ => led_pos[i as usize].1.1, // when current_y == y_mid, no change
-        };
-
-        if current_z > z_mid {
-            led_pos[i as usize].2.0 = z_mid - (current_z - z_mid);
-        } else if current_z < z_mid {
-            led_pos[i as usize].2.0 = z_mid + (z_mid - current_z);
+    for led in leds.iter_mut() {
+        // Mirror around the middle, when y or z are on the middle they don't change
+        if led.status.has_xy() {
+            led.y = y_mid - (led.y - y_mid);
+        }
+        if led.status.has_z() {
+            led.z = z_mid - (led.z - z_mid);
         }
     }
 }
 
-pub fn position_adjustment(led_pos: &mut PosEntry, config: &Config) {
-    // Get mins and maxs
-    let mut x_max = i32::MIN;
-    let mut y_max = i32::MIN;
-    let mut z_max = i32::MIN;
-
-    let mut x_min = i32::MAX;
-    let mut y_min = i32::MAX;
-    let mut z_min = i32::MAX;
-
-    for position in led_pos.iter() {
-        x_max = max(position.1.0, x_max);
-        x_min = min(position.1.0, x_min);
-
-        y_max = max(position.1.1, y_max);
-        y_min = min(position.1.1, y_min);
-
-        z_min = min(position.2.0, z_min);
-        z_max = max(position.2.0, z_max);
-    }
-
-    // Apply stretch/shrink
-
-    let x_adjust = config.advanced.transform.x_stretch_adjust;
-    let y_adjust = config.advanced.transform.y_stretch_adjust;
-    let z_adjust = config.advanced.transform.z_stretch_adjust;
-
-    if x_adjust.is_some() || y_adjust.is_some() || z_adjust.is_some() {
-        for position in led_pos.iter_mut() {
-            for (coordinate, adjust_opt, center) in [
-                (&mut position.1.0, x_adjust, x_max / 2),
-                (&mut position.1.1, y_adjust, y_max / 2),
-                (&mut position.2.0, z_adjust, z_max / 2),
-            ] {
-                if let Some(adjust_amount) = adjust_opt {
-                    let dist_from_center = (*coordinate - center).abs();
-
-                    let stretch_factor = ((dist_from_center as f32 / (x_max as f32 / 2.0))
-                        * adjust_amount as f32)
-                        .round() as i32;
-
-                    if *coordinate > center {
-                        *coordinate += stretch_factor;
-                    } else if *coordinate < center {
-                        *coordinate -= stretch_factor;
-                    }
-                }
-            }
-        }
+/// Corrects for perspective with the camera setup in `[advanced.perspective]`, turning pixel positions into mm
+pub fn position_adjustment(led_pos: &mut LedMap, config: &Config) -> Result<(), String> {
+    if led_pos.metadata.units != "px" {
+        return Err(format!(
+            "Positions are in {}, perspective can only be corrected once on camera pixels",
+            led_pos.metadata.units
+        ));
     }
 
-    // Apply perspective distortion adjustment
+    let setup = perspective::CameraSetup::from_config(config)
+        .map_err(|e| format!("Invalid [advanced.perspective] config: {e}"))?;
 
-    let x_transform = config.advanced.transform.x_perspect_distort_adjust;
-    let y_transform = config.advanced.transform.y_perspect_distort_adjust;
-    let z_transform = config.advanced.transform.z_perspect_distort_adjust;
+    perspective::correct_perspective(led_pos, &setup);
 
-    if x_transform.is_some() || y_transform.is_some() || z_transform.is_some() {
-        for position in led_pos.iter_mut() {
-            if let Some(z_transform_amount) = z_transform {
-                // Calculates reduction based on distance from front
-                let mut reduction_factor = ((position.2.0 as f32 / z_max as f32)
-                    * z_transform_amount as f32)
-                    .round() as i32;
-
-                // Reduces reduction based on distance from center
-                let x_mid = (x_max as f32 / 2.0).round() as i32;
-                reduction_factor *= (position.1.0 - x_mid).abs() / x_mid;
+    Ok(())
+}
 
-                if position.1.0 > x_max / 2 {
-                    position.1.0 = (position.1.0 - reduction_factor).max(x_max / 2);
-                } else if position.1.0 < x_max / 2 {
-                    position.1.0 = (position.1.0 + reduction_factor).min(x_max / 2);
-                }
-            }
+/// The first or second camera from the config as a frame source
+pub fn open_camera(
+    config: &Config,
+    second_cam: bool,
+) -> Result<Box<dyn FrameSource>, Box<dyn Error>> {
+    let index_or_address = if second_cam {
+        config
+            .camera
+            .camera_index_2
+            .as_deref()
+            .ok_or("Set camera_index_2 to use a second camera")?
+    } else {
+        config.camera.camera_index_1.as_str()
+    };
 
-            // TODO: Implement for X and Y aswell
-            // if let Some(y_transform_amount) = y_transform {
-            //     let reduction_factor = ((position.1 .1 as f32 / z_max as f32)
-            //         * y_transform_amount as f32)
-            //         .round() as i32;
-
-            //     if position.2 .0 > x_max / 2 {
-            //         position.2 .0 -= reduction_factor;
-            //     } else if position.2 .0 < x_max / 2 {
-            //         position.2 .0 += reduction_factor;
-            //     }
-            // }
-
-            // if let Some(x_transform_amount) = x_transform {
-            //     let reduction_factor = ((position.1 .0 as f32 / z_max as f32)
-            //         * x_transform_amount as f32)
-            //         .round() as i32;
-
-            //     if position.1 .1 > x_max / 2 {
-            //         position.1 .1 -= reduction_factor;
-            //     } else if position.1 .1 < x_max / 2 {
-            //         position.1 .1 += reduction_factor;
-            //     }
-            // }
-        }
-    }
+    Ok(Box::new(get_cam(config, index_or_address)?))
 }
 
 pub fn get_cam(config: &Config, index_or_address: &str) -> Result<VideoCapture, Box<dyn Error>> {
@@ -0,0 +1,80 @@
+use opencv::{core::Mat, highgui};
+
+/// Key code of space, which moves scans on past their prompts
+pub const SPACE: i32 = 32;
+
+/// How a scan shows its progress and waits for the person running it
+pub trait ScanUi {
+    /// Whether someone is there to crop, pick colors and place LEDs by hand
+    fn interactive(&self) -> bool;
+
+    fn named_window(&self, window: &str) -> opencv::Result<()>;
+
+    fn imshow(&self, window: &str, frame: &Mat) -> opencv::Result<()>;
+
+    fn set_window_title(&self, window: &str, title: &str) -> opencv::Result<()>;
+
+    /// Waits up to `delay` ms for a key, returning its code or -1 when none was pressed
+    fn wait_key(&self, delay: i32) -> opencv::Result<i32>;
+
+    fn destroy_all_windows(&self) -> opencv::Result<()>;
+}
+
+/// OpenCV windows on the desktop
+pub struct HighguiUi;
+
+impl ScanUi for HighguiUi {
+    fn interactive(&self) -> bool {
+        true
+    }
+
+    fn named_window(&self, window: &str) -> opencv::Result<()> {
+        highgui::named_window(window, highgui::WINDOW_AUTOSIZE)
+    }
+
+    fn imshow(&self, window: &str, frame: &Mat) -> opencv::Result<()> {
+        highgui::imshow(window, frame)
+    }
+
+    fn set_window_title(&self, window: &str, title: &str) -> opencv::Result<()> {
+        highgui::set_window_title(window, title)
+    }
+
+    fn wait_key(&self, delay: i32) -> opencv::Result<i32> {
+        highgui::wait_key(delay)
+    }
+
+    fn destroy_all_windows(&self) -> opencv::Result<()> {
+        highgui::destroy_all_windows()
+    }
+}
+
+/// No windows at all, for CI and machines without a display. Every prompt is answered straight away as if space
+/// was pressed, so only streamlined scans can run headless.
+pub struct HeadlessUi;
+
+impl ScanUi for HeadlessUi {
+    fn interactive(&self) -> bool {
+        false
+    }
+
+    fn named_window(&self, _window: &str) -> opencv::Result<()> {
+        Ok(())
+    }
+
+    fn imshow(&self, _window: &str, _frame: &Mat) -> opencv::Result<()> {
+        Ok(())
+    }
+
+    fn set_window_title(&self, _window: &str, _title: &str) -> opencv::Result<()> {
+        Ok(())
+    }
+
+    fn wait_key(&self, _delay: i32) -> opencv::Result<i32> {
+        Ok(SPACE)
+    }
+
+    fn destroy_all_windows(&self) -> opencv::Result<()> {
+        Ok(())
+    }
+}
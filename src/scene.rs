@@ -0,0 +1,297 @@
+use std::{
+    error::Error,
+    fs,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    Config,
+    ledmap::{self, LedMap},
+    remap,
+    transform::{self, TransformStep},
+    vled::{Vled, VledEventKind},
+};
+
+/// Unity port of the first container when the scene doesn't set one, later containers count up from it
+const DEFAULT_UNITY_PORT: u32 = 5001;
+
+/// Every container in an installation and where it stands, so effects can run across all of them at once.
+/// Read from a TOML file with one `[[container]]` table per container.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Scene {
+    #[serde(rename = "container", default)]
+    pub containers: Vec<SceneContainer>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SceneContainer {
+    pub name: Option<String>,
+    /// Relative paths are from the scene file
+    pub position_file: PathBuf,
+    /// Offset added to the container's positions in the world, after scaling and rotating
+    #[serde(default)]
+    pub translate: [f64; 3],
+    /// Degrees to turn the container around the x, y and z axes through its middle, in that order
+    #[serde(default)]
+    pub rotate: [f64; 3],
+    /// Converts the position file's units to the world's
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    pub unity_port: Option<u32>,
+    /// Order file the container's LEDs are addressed in, `index_map` from `[advanced.remap]` when left out
+    pub index_map: Option<PathBuf>,
+    /// Overrides `[communication]` for this container's controller
+    #[serde(default)]
+    pub controller: ControllerBinding,
+}
+
+/// The controller a container's LEDs are sent to. Anything left out is taken from `[communication]`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ControllerBinding {
+    pub communication_mode: Option<i8>,
+    pub host: Option<Ipv4Addr>,
+    pub port: Option<i32>,
+    pub serial_port_paths: Option<Vec<String>>,
+    pub baud_rate: Option<u32>,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+impl SceneContainer {
+    /// The name from the scene, or the position file's name
+    pub fn display_name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| self.position_file.display().to_string())
+    }
+
+    /// Scaling, then rotating, then moving, as `svled transform-pos` steps
+    pub fn steps(&self) -> Vec<TransformStep> {
+        let mut steps = Vec::new();
+        if self.scale != 1.0 {
+            steps.push(TransformStep::Scale([self.scale; 3]));
+        }
+        for (axis, degrees) in self.rotate.into_iter().enumerate() {
+            if degrees != 0.0 {
+                steps.push(TransformStep::Rotate { axis, degrees });
+            }
+        }
+        if self.translate != [0.0; 3] {
+            steps.push(TransformStep::Translate(self.translate));
+        }
+        steps
+    }
+}
+
+impl Scene {
+    pub fn read(path: &Path) -> Result<Scene, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+        let mut scene: Scene = toml::from_str(&contents)
+            .map_err(|e| format!("{} is not a valid scene: {e}", path.display()))?;
+
+        if scene.containers.is_empty() {
+            return Err(format!("{} has no [[container]] entries", path.display()).into());
+        }
+        if scene.containers.len() > u8::MAX as usize {
+            return Err(format!("{} has more than {} containers", path.display(), u8::MAX).into());
+        }
+
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for container in scene.containers.iter_mut() {
+            container.position_file = directory.join(&container.position_file);
+            container.index_map = container
+                .index_map
+                .as_ref()
+                .map(|index_map| directory.join(index_map));
+        }
+
+        Ok(scene)
+    }
+
+    /// The scene set in `[unity_options]`, or one built from `unity_position_files` and `unity_ports` with every
+    /// container left where its position file puts it
+    pub fn from_config(config: &Config) -> Result<Scene, Box<dyn Error>> {
+        let unity = &config.unity_options;
+        let index_map = &config.advanced.remap.index_map;
+
+        let mut scene = match unity.scene {
+            Some(ref path) => Scene::read(path)?,
+            None => Scene {
+                containers: unity
+                    .unity_position_files
+                    .iter()
+                    .take(unity.num_container as usize)
+                    .enumerate()
+                    .map(|(i, position_file)| SceneContainer {
+                        name: None,
+                        position_file: position_file.clone(),
+                        translate: [0.0; 3],
+                        rotate: [0.0; 3],
+                        scale: 1.0,
+                        unity_port: unity.unity_ports.get(i).copied(),
+                        index_map: None,
+                        controller: ControllerBinding::default(),
+                    })
+                    .collect(),
+            },
+        };
+
+        for container in scene.containers.iter_mut() {
+            if container.index_map.is_none() {
+                container.index_map = index_map.clone();
+            }
+        }
+
+        Ok(scene)
+    }
+
+    /// Unity port of each container
+    pub fn unity_ports(&self) -> Vec<u32> {
+        self.containers
+            .iter()
+            .enumerate()
+            .map(|(i, container)| {
+                container
+                    .unity_port
+                    .unwrap_or(DEFAULT_UNITY_PORT + i as u32)
+            })
+            .collect()
+    }
+
+    /// Reads every position file, puts it in the order its LEDs are addressed in and places it in the world
+    pub fn world(&self) -> Result<World, Box<dyn Error>> {
+        let mut containers: Vec<LedMap> = Vec::with_capacity(self.containers.len());
+
+        for container in &self.containers {
+            let mut led_pos = ledmap::read_led_map(&container.position_file)?;
+
+            if let Some(first) = containers.first()
+                && container.scale == 1.0
+                && first.metadata.units != led_pos.metadata.units
+            {
+                return Err(format!(
+                    "{} is in {} while the first container is in {}, set scale to convert it",
+                    container.display_name(),
+                    led_pos.metadata.units,
+                    first.metadata.units
+                )
+                .into());
+            }
+
+            if let Some(ref path) = container.index_map {
+                led_pos = remap::read_index_map(path)?.reorder(&led_pos);
+            }
+
+            let steps = container.steps();
+            if !steps.is_empty() {
+                transform::transform_positions(&mut led_pos, &steps);
+            }
+            containers.push(led_pos);
+        }
+
+        Ok(World { containers })
+    }
+}
+
+/// The position files of a scene in world coordinates. Combined, the LEDs of every container are numbered
+/// back to back in scene order, so effects can treat the whole installation as one container.
+#[derive(Debug, Clone, Default)]
+pub struct World {
+    pub containers: Vec<LedMap>,
+}
+
+impl World {
+    /// Every container's LEDs in one position file
+    pub fn combined(&self) -> LedMap {
+        let mut combined = LedMap {
+            leds: self
+                .containers
+                .iter()
+                .flat_map(|container| container.leds.iter().cloned())
+                .collect(),
+            ..self
+                .containers
+                .first()
+                .cloned()
+                .unwrap_or(LedMap::new(0, Default::default()))
+        };
+        combined.metadata.transforms.clear();
+        combined.metadata.bounds = combined.bounds();
+        combined
+    }
+
+    /// Index of each container's first LED in `combined`
+    pub fn offsets(&self) -> Vec<usize> {
+        self.containers
+            .iter()
+            .scan(0, |offset, container| {
+                let start = *offset;
+                *offset += container.len();
+                Some(start)
+            })
+            .collect()
+    }
+
+    /// The container and strip index of an LED in `combined`
+    pub fn locate(&self, index: usize) -> Option<(u8, u16)> {
+        let offsets = self.offsets();
+        let container = offsets
+            .partition_point(|offset| *offset <= index)
+            .checked_sub(1)?;
+        let local = index - offsets[container];
+
+        (local < self.containers[container].len()).then_some((container as u8, local as u16))
+    }
+
+    /// Turns a recording made against `combined` into a multi-track recording, one track per container
+    pub fn split(&self, vled: &Vled) -> Vled {
+        let mut split = vled.clone();
+        split.events.retain_mut(|event| match &mut event.kind {
+            VledEventKind::Color { track, index, .. } => match self.locate(*index as usize) {
+                Some((container, local)) => {
+                    *track = Some(container);
+                    *index = local;
+                    true
+                }
+                None => false,
+            },
+            VledEventKind::Mark(_) => true,
+        });
+        split
+    }
+
+    /// Turns a multi-track recording into one against `combined`. Events without a track are taken to be for the
+    /// first container. Fails when an event lands past the 16 bit indices a recording can hold.
+    pub fn join(&self, vled: &Vled) -> Result<Vled, Box<dyn Error>> {
+        let offsets = self.offsets();
+        let mut joined = vled.clone();
+        let mut events = Vec::with_capacity(vled.events.len());
+        for mut event in joined.events.drain(..) {
+            if let VledEventKind::Color { track, index, .. } = &mut event.kind {
+                let container = track.unwrap_or(0) as usize;
+                match self.containers.get(container) {
+                    Some(led_pos) if (*index as usize) < led_pos.len() => {
+                        let combined = offsets[container] + *index as usize;
+                        *index = u16::try_from(combined).map_err(|_| {
+                            format!(
+                                "LED {index} of container {container} is LED {combined} of the scene, recordings can only address {} LEDs",
+                                u16::MAX as usize + 1
+                            )
+                        })?;
+                        *track = None;
+                    }
+                    _ => continue,
+                }
+            }
+            events.push(event);
+        }
+        joined.events = events;
+        Ok(joined)
+    }
+}
@@ -0,0 +1,435 @@
+use std::sync::Arc;
+
+use opencv::{
+    core::{self, CV_8UC3, CV_32FC3, Point, Scalar, Size},
+    imgproc::{self, FILLED, LINE_AA},
+    prelude::*,
+};
+
+use crate::{
+    frame_source::FrameSource,
+    led_manager::VirtualStrip,
+    ledmap::LedMap,
+    merge,
+    spatial::{self, SpatialIndex},
+    transform,
+    vled::Rgb,
+};
+
+/// How much of a lit LED's brightness spills into the glow around it
+const BLOOM_GAIN: f64 = 3.0;
+
+/// Share of the picture the LEDs take up when the cameras are placed automatically
+const FILL: f64 = 0.8;
+
+/// A pinhole camera looking at a simulated container, along with the ways its picture falls short of a perfect one
+#[derive(Debug, Clone, PartialEq)]
+pub struct VirtualCamera {
+    /// Where the lens is, in the units of the layout
+    pub position: spatial::Point,
+    /// The point in the middle of the picture
+    pub target: spatial::Point,
+    /// Which way is up in the picture, it doesn't need to be at a right angle to the view
+    pub up: spatial::Point,
+    /// Horizontal field of view in degrees
+    pub fov: f64,
+    /// Width and height of the frames in pixels
+    pub size: (i32, i32),
+    /// Standard deviation of the sensor noise added to every pixel, out of 255
+    pub noise: f64,
+    /// Radius in pixels of the glow around lit LEDs, 0 for none
+    pub bloom: f64,
+    /// Brightness of the room with every LED off, out of 255
+    pub ambient: f64,
+}
+
+impl VirtualCamera {
+    pub fn focal_length(&self) -> f64 {
+        self.size.0 as f64 / 2.0 / (self.fov.to_radians() / 2.0).tan()
+    }
+
+    /// Where `point` lands in the picture and how far in front of the lens it is, None when it's behind the lens
+    pub fn project(&self, point: spatial::Point) -> Option<(f64, f64, f64)> {
+        let (right, up, forward) = self.axes();
+        let offset = sub(point, self.position);
+        let depth = dot(offset, forward);
+        if depth <= f64::EPSILON {
+            return None;
+        }
+
+        let focal_length = self.focal_length();
+        Some((
+            self.size.0 as f64 / 2.0 + focal_length * dot(offset, right) / depth,
+            self.size.1 as f64 / 2.0 - focal_length * dot(offset, up) / depth,
+            depth,
+        ))
+    }
+
+    /// Right, up and forward as seen from the camera
+    fn axes(&self) -> (spatial::Point, spatial::Point, spatial::Point) {
+        let forward = normalize(sub(self.target, self.position));
+        let right = normalize(cross(forward, self.up));
+        (right, cross(right, forward), forward)
+    }
+}
+
+/// Places two cameras around `layout` the way the scanner expects them: the first one in front, looking down -z,
+/// and the second one on the right, looking down -x, or above when `overhead` is set. `flip` turns the overhead
+/// camera upside down, like `cam2_overhead_flip`. Both are `distance` from the middle of the LEDs, or far enough
+/// away to see all of them when it's None.
+pub fn camera_rig(
+    layout: &LedMap,
+    fov: f64,
+    size: (i32, i32),
+    distance: Option<f64>,
+    overhead: bool,
+    flip: bool,
+) -> Result<[VirtualCamera; 2], String> {
+    let Some(bounds) = layout.bounds() else {
+        return Err("The layout has no located LEDs to place cameras around".to_string());
+    };
+    if !(0.0..180.0).contains(&fov) || fov == 0.0 {
+        return Err(format!("A field of view of {fov} degrees is not possible"));
+    }
+
+    let center = bounds.center();
+    let extent = bounds.size();
+    let distance = distance.unwrap_or_else(|| {
+        // The narrower of the two fields of view has to fit the largest side, seen from its closest face
+        let half_fov = (fov.to_radians() / 2.0).tan();
+        let narrowest = half_fov.min(half_fov * size.1 as f64 / size.0 as f64);
+        let largest = extent.iter().copied().fold(0.0, f64::max);
+        largest / 2.0 / FILL / narrowest + largest / 2.0
+    });
+
+    let camera = |direction: spatial::Point, up: spatial::Point| VirtualCamera {
+        position: [0, 1, 2].map(|axis| center[axis] + direction[axis] * distance),
+        target: center,
+        up,
+        fov,
+        size,
+        noise: 0.0,
+        bloom: 0.0,
+        ambient: 0.0,
+    };
+
+    let second = match (overhead, flip) {
+        (false, _) => camera([1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+        (true, false) => camera([0.0, 1.0, 0.0], [0.0, 0.0, -1.0]),
+        (true, true) => camera([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+    };
+
+    Ok([camera([0.0, 0.0, 1.0], [0.0, 1.0, 0.0]), second])
+}
+
+/// Renders what `camera` sees of a container laid out like `layout` while `strip` lights some of its LEDs, so scans
+/// can be checked against where the LEDs really are. LEDs are discs `led_size` across facing the camera, and with
+/// `occlusion` they hide the LEDs behind them. LEDs the layout hasn't located are treated as broken and stay dark.
+pub struct Simulator {
+    strip: Arc<VirtualStrip>,
+    camera: VirtualCamera,
+    /// Where each LED shows up and its radius in pixels, None for LEDs the camera can't see
+    spots: Vec<Option<(f64, f64, f64)>>,
+    /// The last frame before noise and the LEDs it was made for, as noise is the only thing that changes between reads
+    shown: Option<(Vec<(usize, Rgb)>, Mat)>,
+}
+
+impl Simulator {
+    pub fn new(
+        strip: Arc<VirtualStrip>,
+        layout: &LedMap,
+        camera: VirtualCamera,
+        led_size: f64,
+        occlusion: bool,
+    ) -> Simulator {
+        let radius = led_size.max(f64::EPSILON) / 2.0;
+        let index = SpatialIndex::located(layout);
+        let focal_length = camera.focal_length();
+
+        let spots = layout
+            .leds
+            .iter()
+            .enumerate()
+            .map(|(led_index, led)| {
+                if !led.status.is_located() {
+                    return None;
+                }
+
+                let point = [led.x, led.y, led.z];
+                let (x, y, depth) = camera.project(point)?;
+                let spot_radius = radius * focal_length / depth;
+                if x + spot_radius < 0.0
+                    || y + spot_radius < 0.0
+                    || x - spot_radius > camera.size.0 as f64
+                    || y - spot_radius > camera.size.1 as f64
+                {
+                    return None;
+                }
+
+                // Anything on the way to the lens closer than the front of this LED is in front of it
+                let ray = sub(point, camera.position);
+                let front = dot(ray, ray).sqrt() - radius;
+                let hidden = occlusion
+                    && index
+                        .along_ray(camera.position, ray, radius)
+                        .into_iter()
+                        .any(|(other, along)| other != led_index && along < front);
+
+                (!hidden).then_some((x, y, spot_radius))
+            })
+            .collect();
+
+        Simulator {
+            strip,
+            camera,
+            spots,
+            shown: None,
+        }
+    }
+
+    /// LEDs the camera can see, which a perfect scan would find
+    pub fn visible(&self) -> impl Iterator<Item = usize> + '_ {
+        self.spots
+            .iter()
+            .enumerate()
+            .filter(|(_, spot)| spot.is_some())
+            .map(|(index, _)| index)
+    }
+
+    /// The picture without noise, in floating point so glow and ambient light can add up past white
+    fn render(&self, lit: &[(usize, Rgb)]) -> opencv::Result<Mat> {
+        let (width, height) = self.camera.size;
+        let mut leds = Mat::new_rows_cols_with_default(height, width, CV_32FC3, Scalar::all(0.0))?;
+
+        for (index, (r, g, b)) in lit {
+            if let Some(Some((x, y, radius))) = self.spots.get(*index) {
+                imgproc::circle(
+                    &mut leds,
+                    Point::new(x.round() as i32, y.round() as i32),
+                    (radius.round() as i32).max(1),
+                    Scalar::new(*b as f64, *g as f64, *r as f64, 0.0),
+                    FILLED,
+                    LINE_AA,
+                    0,
+                )?;
+            }
+        }
+
+        let mut glow = Mat::default();
+        let glow_gain = if self.camera.bloom > 0.0 {
+            imgproc::gaussian_blur(
+                &leds,
+                &mut glow,
+                Size::new(0, 0),
+                self.camera.bloom,
+                self.camera.bloom,
+                core::BORDER_DEFAULT,
+                core::get_default_algorithm_hint()?,
+            )?;
+            BLOOM_GAIN
+        } else {
+            glow = leds.try_clone()?;
+            0.0
+        };
+
+        let mut frame = Mat::default();
+        core::add_weighted(
+            &leds,
+            1.0,
+            &glow,
+            glow_gain,
+            self.camera.ambient,
+            &mut frame,
+            -1,
+        )?;
+        Ok(frame)
+    }
+}
+
+impl FrameSource for Simulator {
+    fn read(&mut self, frame: &mut Mat) -> opencv::Result<bool> {
+        let lit = self.strip.lit();
+        if !matches!(self.shown, Some((ref shown, _)) if *shown == lit) {
+            let rendered = self.render(&lit)?;
+            self.shown = Some((lit, rendered));
+        }
+        let Some((_, ref clean)) = self.shown else {
+            return Ok(false);
+        };
+
+        if self.camera.noise > 0.0 {
+            let mut noise = Mat::new_rows_cols_with_default(
+                clean.rows(),
+                clean.cols(),
+                CV_32FC3,
+                Scalar::all(0.0),
+            )?;
+            core::randn(
+                &mut noise,
+                &Scalar::all(0.0),
+                &Scalar::all(self.camera.noise),
+            )?;
+            let mut noisy = Mat::default();
+            core::add(clean, &noise, &mut noisy, &core::no_array(), -1)?;
+            noisy.convert_to(frame, CV_8UC3, 1.0, 0.0)?;
+        } else {
+            clean.convert_to(frame, CV_8UC3, 1.0, 0.0)?;
+        }
+
+        Ok(true)
+    }
+
+    fn is_opened(&self) -> opencv::Result<bool> {
+        Ok(true)
+    }
+
+    fn frame_size(&self) -> opencv::Result<(i32, i32)> {
+        Ok(self.camera.size)
+    }
+}
+
+/// How close a scan came to the layout it was simulated from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accuracy {
+    /// LEDs the layout has located, which a perfect scan would find
+    pub expected: usize,
+    /// LEDs the layout has located that the scan didn't find in all three dimensions
+    pub missed: Vec<usize>,
+    /// LEDs the scan found that the layout doesn't have, so it picked up noise or a reflection
+    pub phantom: Vec<usize>,
+    /// What the scan was multiplied by before lining it up, as scans in pixels have no real size
+    pub scale: f64,
+    /// Distance from each LED found in both to where it really is, in the units of the layout, once lined up
+    pub errors: Vec<(usize, f64)>,
+}
+
+impl Accuracy {
+    pub fn rms_error(&self) -> f64 {
+        if self.errors.is_empty() {
+            return 0.0;
+        }
+        (self
+            .errors
+            .iter()
+            .map(|(_, error)| error * error)
+            .sum::<f64>()
+            / self.errors.len() as f64)
+            .sqrt()
+    }
+
+    pub fn mean_error(&self) -> f64 {
+        if self.errors.is_empty() {
+            return 0.0;
+        }
+        self.errors.iter().map(|(_, error)| error).sum::<f64>() / self.errors.len() as f64
+    }
+
+    pub fn max_error(&self) -> f64 {
+        self.errors
+            .iter()
+            .map(|(_, error)| *error)
+            .fold(0.0, f64::max)
+    }
+
+    /// The `count` LEDs furthest from where they really are, furthest first
+    pub fn worst(&self, count: usize) -> Vec<(usize, f64)> {
+        let mut errors = self.errors.clone();
+        errors.sort_by(|a, b| b.1.total_cmp(&a.1));
+        errors.truncate(count);
+        errors
+    }
+}
+
+/// Compares a scan with the layout it was simulated from. The scan is scaled to the size of the layout, then
+/// rotated and moved onto it the same way scans are merged, so only the shape of the scan counts.
+pub fn measure_accuracy(scan: &LedMap, layout: &LedMap) -> Result<Accuracy, String> {
+    if scan.len() != layout.len() {
+        return Err(format!(
+            "The scan has {} LEDs, but the layout has {}",
+            scan.len(),
+            layout.len()
+        ));
+    }
+
+    let shared: Vec<usize> = (0..scan.len())
+        .filter(|index| scan.leds[*index].status.has_z() && layout.leds[*index].status.is_located())
+        .collect();
+
+    let spread = |map: &LedMap| {
+        let points: Vec<spatial::Point> = shared
+            .iter()
+            .map(|index| {
+                let led = &map.leds[*index];
+                [led.x, led.y, led.z]
+            })
+            .collect();
+        let middle = [0, 1, 2]
+            .map(|axis| points.iter().map(|point| point[axis]).sum::<f64>() / points.len() as f64);
+        points
+            .iter()
+            .map(|point| dot(sub(*point, middle), sub(*point, middle)))
+            .sum::<f64>()
+            .sqrt()
+    };
+    let scale = if shared.len() >= 2 && spread(scan) > f64::EPSILON {
+        spread(layout) / spread(scan)
+    } else {
+        1.0
+    };
+
+    let mut scaled = scan.clone();
+    for led in scaled.leds.iter_mut() {
+        led.x *= scale;
+        led.y *= scale;
+        led.z *= scale;
+    }
+    let alignment = merge::align(&scaled, layout);
+
+    let errors = shared
+        .iter()
+        .map(|index| {
+            let led = &scaled.leds[*index];
+            let truth = &layout.leds[*index];
+            let moved = transform::apply(&alignment.matrix, [led.x, led.y, led.z]);
+            let offset = sub(moved, [truth.x, truth.y, truth.z]);
+            (*index, dot(offset, offset).sqrt())
+        })
+        .collect();
+
+    Ok(Accuracy {
+        expected: layout.located().count(),
+        missed: layout
+            .located()
+            .map(|(index, _)| index)
+            .filter(|index| !scan.leds[*index].status.has_z())
+            .collect(),
+        phantom: scan
+            .located()
+            .map(|(index, _)| index)
+            .filter(|index| !layout.leds[*index].status.is_located())
+            .collect(),
+        scale,
+        errors,
+    })
+}
+
+fn sub(a: spatial::Point, b: spatial::Point) -> spatial::Point {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: spatial::Point, b: spatial::Point) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: spatial::Point, b: spatial::Point) -> spatial::Point {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn normalize(a: spatial::Point) -> spatial::Point {
+    let length = dot(a, a).sqrt().max(f64::EPSILON);
+    a.map(|value| value / length)
+}
@@ -0,0 +1,373 @@
+use crate::ledmap::LedMap;
+
+/// A point in the same units as the position file
+pub type Point = [f64; 3];
+
+/// k-d tree over LED positions, for effects that need to find LEDs near a point, in a region or along a line
+/// without checking every LED on every step. Queries return strip indices.
+#[derive(Debug, Clone, Default)]
+pub struct SpatialIndex {
+    /// Stored as an implicit tree, the middle of every range splits it on the axis of its depth
+    nodes: Vec<(usize, Point)>,
+    bounds: Option<(Point, Point)>,
+}
+
+impl SpatialIndex {
+    /// Indexes every LED, including the placeholder positions of LEDs that need recalibrating, the same way
+    /// effects light them
+    pub fn new(led_pos: &LedMap) -> SpatialIndex {
+        SpatialIndex::from_points(
+            led_pos
+                .leds
+                .iter()
+                .enumerate()
+                .map(|(index, led)| (index, [led.x, led.y, led.z])),
+        )
+    }
+
+    /// Only indexes LEDs with all three coordinates
+    pub fn located(led_pos: &LedMap) -> SpatialIndex {
+        SpatialIndex::from_points(
+            led_pos
+                .leds
+                .iter()
+                .enumerate()
+                .filter(|(_, led)| led.status.is_located())
+                .map(|(index, led)| (index, [led.x, led.y, led.z])),
+        )
+    }
+
+    pub fn from_points(points: impl IntoIterator<Item = (usize, Point)>) -> SpatialIndex {
+        let mut nodes: Vec<(usize, Point)> = points.into_iter().collect();
+
+        let bounds = nodes.iter().fold(None, |bounds, (_, point)| {
+            let (min, max) = bounds.unwrap_or((*point, *point));
+            Some((
+                [0, 1, 2].map(|axis| f64::min(min[axis], point[axis])),
+                [0, 1, 2].map(|axis| f64::max(max[axis], point[axis])),
+            ))
+        });
+
+        build(&mut nodes, 0);
+
+        SpatialIndex { nodes, bounds }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Smallest and largest position on each axis
+    pub fn bounds(&self) -> Option<(Point, Point)> {
+        self.bounds
+    }
+
+    /// LEDs no further than `radius` from `center`
+    pub fn within_sphere(&self, center: Point, radius: f64) -> Vec<usize> {
+        self.search(
+            |min, max| distance_to_box(center, min, max) <= radius,
+            |point| distance(center, point) <= radius,
+        )
+    }
+
+    /// LEDs inside the box from `min` to `max`, edges included
+    pub fn within_box(&self, min: Point, max: Point) -> Vec<usize> {
+        let inside = move |point: Point| {
+            (0..3).all(|axis| min[axis] <= point[axis] && point[axis] <= max[axis])
+        };
+
+        self.search(
+            |cell_min, cell_max| {
+                (0..3).all(|axis| cell_min[axis] <= max[axis] && min[axis] <= cell_max[axis])
+            },
+            inside,
+        )
+    }
+
+    /// The `k` LEDs closest to `point` with their distance, closest first
+    pub fn nearest(&self, point: Point, k: usize) -> Vec<(usize, f64)> {
+        let mut found = Vec::with_capacity(k + 1);
+        if k > 0
+            && let Some((min, max)) = self.bounds
+        {
+            self.nearest_in(0..self.nodes.len(), 0, min, max, point, k, &mut found);
+        }
+
+        found
+    }
+
+    /// LEDs no further than `radius` from the ray starting at `origin` and going towards `direction`, with how
+    /// far along the ray they are, in order
+    pub fn along_ray(&self, origin: Point, direction: Point, radius: f64) -> Vec<(usize, f64)> {
+        let length = distance([0.0; 3], direction);
+        if length == 0.0 {
+            return Vec::new();
+        }
+        let direction = direction.map(|value| value / length);
+
+        // Position along the ray and distance from it, for points in front of the origin
+        let project = |point: Point| {
+            let offset = [0, 1, 2].map(|axis| point[axis] - origin[axis]);
+            let along = dot(offset, direction);
+            let closest = [0, 1, 2].map(|axis| origin[axis] + direction[axis] * along.max(0.0));
+            (along, distance(point, closest))
+        };
+
+        let mut found: Vec<(usize, f64)> = self
+            .search_points(
+                |min, max| {
+                    ray_hits_box(
+                        origin,
+                        direction,
+                        min.map(|value| value - radius),
+                        max.map(|value| value + radius),
+                    )
+                },
+                |point| project(point).1 <= radius,
+            )
+            .into_iter()
+            .map(|(index, point)| (index, project(point).0.max(0.0)))
+            .collect();
+
+        found.sort_by(|a, b| a.1.total_cmp(&b.1));
+        found
+    }
+
+    /// LEDs no further than `thickness / 2` from the plane through `point` facing `normal`
+    pub fn slice(&self, point: Point, normal: Point, thickness: f64) -> Vec<usize> {
+        let length = distance([0.0; 3], normal);
+        if length == 0.0 {
+            return Vec::new();
+        }
+        let normal = normal.map(|value| value / length);
+        let half = thickness / 2.0;
+        let offset = dot(point, normal);
+
+        self.search(
+            |min, max| {
+                let center = [0, 1, 2].map(|axis| (min[axis] + max[axis]) / 2.0);
+                let extent: f64 = (0..3)
+                    .map(|axis| (max[axis] - min[axis]) / 2.0 * normal[axis].abs())
+                    .sum();
+                (dot(center, normal) - offset).abs() <= half + extent
+            },
+            |position| (dot(position, normal) - offset).abs() <= half,
+        )
+    }
+
+    fn search(
+        &self,
+        overlaps: impl Fn(Point, Point) -> bool,
+        matches: impl Fn(Point) -> bool,
+    ) -> Vec<usize> {
+        self.search_points(overlaps, matches)
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Visits every cell `overlaps` might contain points for, returning the points `matches` accepts in strip
+    /// order
+    fn search_points(
+        &self,
+        overlaps: impl Fn(Point, Point) -> bool,
+        matches: impl Fn(Point) -> bool,
+    ) -> Vec<(usize, Point)> {
+        let mut found = Vec::new();
+        if let Some((min, max)) = self.bounds {
+            self.search_in(
+                0..self.nodes.len(),
+                0,
+                min,
+                max,
+                &overlaps,
+                &matches,
+                &mut found,
+            );
+        }
+
+        found.sort_unstable_by_key(|(index, _)| *index);
+        found
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn search_in(
+        &self,
+        range: std::ops::Range<usize>,
+        depth: usize,
+        min: Point,
+        max: Point,
+        overlaps: &impl Fn(Point, Point) -> bool,
+        matches: &impl Fn(Point) -> bool,
+        found: &mut Vec<(usize, Point)>,
+    ) {
+        if range.is_empty() || !overlaps(min, max) {
+            return;
+        }
+
+        let middle = (range.start + range.end) / 2;
+        let (index, point) = self.nodes[middle];
+        if matches(point) {
+            found.push((index, point));
+        }
+
+        let axis = depth % 3;
+        let (mut left_max, mut right_min) = (max, min);
+        left_max[axis] = point[axis];
+        right_min[axis] = point[axis];
+
+        self.search_in(
+            range.start..middle,
+            depth + 1,
+            min,
+            left_max,
+            overlaps,
+            matches,
+            found,
+        );
+        self.search_in(
+            middle + 1..range.end,
+            depth + 1,
+            right_min,
+            max,
+            overlaps,
+            matches,
+            found,
+        );
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn nearest_in(
+        &self,
+        range: std::ops::Range<usize>,
+        depth: usize,
+        min: Point,
+        max: Point,
+        target: Point,
+        k: usize,
+        found: &mut Vec<(usize, f64)>,
+    ) {
+        if range.is_empty() {
+            return;
+        }
+        // Nothing in this cell can beat the furthest of the closest points so far
+        if found.len() == k
+            && distance_to_box(target, min, max) > found.last().map_or(f64::MAX, |(_, d)| *d)
+        {
+            return;
+        }
+
+        let middle = (range.start + range.end) / 2;
+        let (index, point) = self.nodes[middle];
+
+        let point_distance = distance(target, point);
+        let position = found.partition_point(|(_, d)| *d <= point_distance);
+        if position < k {
+            found.insert(position, (index, point_distance));
+            found.truncate(k);
+        }
+
+        let axis = depth % 3;
+        let (mut left_max, mut right_min) = (max, min);
+        left_max[axis] = point[axis];
+        right_min[axis] = point[axis];
+
+        // Search the side the target is on first, so the other side can usually be skipped
+        if target[axis] < point[axis] {
+            self.nearest_in(
+                range.start..middle,
+                depth + 1,
+                min,
+                left_max,
+                target,
+                k,
+                found,
+            );
+            self.nearest_in(
+                middle + 1..range.end,
+                depth + 1,
+                right_min,
+                max,
+                target,
+                k,
+                found,
+            );
+        } else {
+            self.nearest_in(
+                middle + 1..range.end,
+                depth + 1,
+                right_min,
+                max,
+                target,
+                k,
+                found,
+            );
+            self.nearest_in(
+                range.start..middle,
+                depth + 1,
+                min,
+                left_max,
+                target,
+                k,
+                found,
+            );
+        }
+    }
+}
+
+/// Orders `nodes` so the middle of every range is its median on the axis of its depth
+fn build(nodes: &mut [(usize, Point)], depth: usize) {
+    if nodes.len() <= 1 {
+        return;
+    }
+
+    let axis = depth % 3;
+    let middle = nodes.len() / 2;
+    nodes.select_nth_unstable_by(middle, |a, b| a.1[axis].total_cmp(&b.1[axis]));
+
+    let (left, right) = nodes.split_at_mut(middle);
+    build(left, depth + 1);
+    build(&mut right[1..], depth + 1);
+}
+
+fn dot(a: Point, b: Point) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn distance(a: Point, b: Point) -> f64 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+fn distance_to_box(point: Point, min: Point, max: Point) -> f64 {
+    let closest = [0, 1, 2].map(|axis| point[axis].clamp(min[axis], max[axis]));
+    distance(point, closest)
+}
+
+/// Slab test for a ray with a normalised direction, only counting hits in front of the origin
+fn ray_hits_box(origin: Point, direction: Point, min: Point, max: Point) -> bool {
+    let mut near = 0.0_f64;
+    let mut far = f64::MAX;
+
+    for axis in 0..3 {
+        if direction[axis] == 0.0 {
+            if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                return false;
+            }
+            continue;
+        }
+
+        let a = (min[axis] - origin[axis]) / direction[axis];
+        let b = (max[axis] - origin[axis]) / direction[axis];
+        near = near.max(a.min(b));
+        far = far.min(a.max(b));
+        if near > far {
+            return false;
+        }
+    }
+
+    true
+}
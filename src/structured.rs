@@ -0,0 +1,332 @@
+use opencv::{
+    core::{self, Mat, Size},
+    imgproc::{self, COLOR_BGR2GRAY},
+    prelude::*,
+};
+
+/// How much brighter a pixel has to be in a pattern than in its inverse, or the other way around, relative to the
+/// brighter of the two, for its bit to count. Pixels lit by two LEDs with different codes fall below this.
+const MIN_CONTRAST: f64 = 0.2;
+
+/// Smallest group of pixels taken for an LED, anything smaller is noise
+const MIN_AREA: usize = 3;
+
+/// Bit planes needed to give each of `num_led` LEDs its own code
+pub fn bit_planes(num_led: usize) -> u32 {
+    (usize::BITS - num_led.saturating_sub(1).leading_zeros()).max(1)
+}
+
+/// Gray code of `index`, so LEDs next to each other on the strip only differ in one pattern
+pub fn gray_code(index: usize) -> usize {
+    index ^ (index >> 1)
+}
+
+pub fn gray_decode(code: usize) -> usize {
+    let mut index = code;
+    let mut shift = code >> 1;
+    while shift != 0 {
+        index ^= shift;
+        shift >>= 1;
+    }
+    index
+}
+
+/// Whether LED `index` is lit in the pattern for `bit`, or in its inverse when `inverse` is set. Every LED is lit in
+/// exactly one of the two, so each pair of captures also shows every LED once.
+pub fn is_lit(index: usize, bit: u32, inverse: bool) -> bool {
+    ((gray_code(index) >> bit) & 1 == 1) != inverse
+}
+
+/// Where an LED showed up in the patterns
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blob {
+    /// Middle of the blob, weighted by brightness, in pixels
+    pub x: f64,
+    pub y: f64,
+    /// Brightest pixel of the blob
+    pub peak: f64,
+    /// Pixels in the blob
+    pub area: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Decoded {
+    /// One per LED, None for LEDs that weren't seen or were ambiguous
+    pub blobs: Vec<Option<Blob>>,
+    /// LEDs whose code showed up in more than one place, usually a reflection or two LEDs overlapping
+    pub ambiguous: Vec<usize>,
+}
+
+impl Decoded {
+    pub fn found(&self) -> usize {
+        self.blobs.iter().flatten().count()
+    }
+}
+
+/// Blurs away sensor noise and turns a capture grey, ready for `decode`. Far lighter than the blur used when
+/// looking for a single LED, as LEDs close to each other have to stay apart.
+pub fn prepare(frame: &Mat) -> opencv::Result<Mat> {
+    let mut blurred = Mat::default();
+    imgproc::gaussian_blur(
+        frame,
+        &mut blurred,
+        Size::new(5, 5),
+        0.0,
+        0.0,
+        core::BORDER_DEFAULT,
+        core::get_default_algorithm_hint()?,
+    )?;
+
+    let mut grey = Mat::default();
+    imgproc::cvt_color(
+        &blurred,
+        &mut grey,
+        COLOR_BGR2GRAY,
+        0,
+        core::get_default_algorithm_hint()?,
+    )?;
+    Ok(grey)
+}
+
+/// Reads the index of every LED from `prepare`d captures of each pattern followed by its inverse, lowest bit first.
+/// Every pixel at least `threshold` bright is decoded on its own, then pixels next to each other with the same
+/// code are grouped into blobs, so LEDs whose glow runs together are still told apart.
+pub fn decode(captures: &[Mat], threshold: f64, num_led: usize) -> opencv::Result<Decoded> {
+    let Some(first) = captures.first() else {
+        return Ok(Decoded {
+            blobs: vec![None; num_led],
+            ambiguous: Vec::new(),
+        });
+    };
+    let (width, height) = (first.cols() as usize, first.rows() as usize);
+
+    let planes: Vec<&[u8]> = captures
+        .iter()
+        .map(|capture| capture.data_bytes())
+        .collect::<opencv::Result<_>>()?;
+    if !planes.len().is_multiple_of(2) || planes.iter().any(|plane| plane.len() != width * height) {
+        return Err(opencv::Error::new(
+            core::StsBadArg,
+            "Every pattern needs an inverse of the same size".to_string(),
+        ));
+    }
+
+    // The LED each pixel belongs to
+    let codes: Vec<Option<usize>> = (0..width * height)
+        .map(|pixel| {
+            let mut code = 0;
+            for (bit, pair) in planes.chunks_exact(2).enumerate() {
+                let (lit, inverse) = (pair[0][pixel] as f64, pair[1][pixel] as f64);
+                let brighter = lit.max(inverse);
+                if brighter < threshold || (lit - inverse).abs() < brighter * MIN_CONTRAST {
+                    return None;
+                }
+                if lit > inverse {
+                    code |= 1 << bit;
+                }
+            }
+            Some(gray_decode(code)).filter(|index| *index < num_led)
+        })
+        .collect();
+
+    // Brightness with every LED on, from the first pattern and its inverse
+    let brightness = |pixel: usize| planes[0][pixel].max(planes[1][pixel]) as f64;
+
+    let mut groups: Vec<Vec<Blob>> = vec![Vec::new(); num_led];
+    let mut visited = vec![false; width * height];
+    let mut stack = Vec::new();
+    for (start, code) in codes.iter().enumerate() {
+        let Some(index) = *code else {
+            continue;
+        };
+        if visited[start] {
+            continue;
+        }
+
+        let (mut weight, mut x, mut y, mut peak, mut area) = (0.0, 0.0, 0.0, 0.0_f64, 0);
+        visited[start] = true;
+        stack.push(start);
+        while let Some(pixel) = stack.pop() {
+            let value = brightness(pixel);
+            let (column, row) = (pixel % width, pixel / width);
+            weight += value;
+            x += value * column as f64;
+            y += value * row as f64;
+            peak = peak.max(value);
+            area += 1;
+
+            let neighbours = [
+                (column > 0).then(|| pixel - 1),
+                (column + 1 < width).then(|| pixel + 1),
+                (row > 0).then(|| pixel - width),
+                (row + 1 < height).then(|| pixel + width),
+            ];
+            for neighbour in neighbours.into_iter().flatten() {
+                if !visited[neighbour] && codes[neighbour] == Some(index) {
+                    visited[neighbour] = true;
+                    stack.push(neighbour);
+                }
+            }
+        }
+
+        if area >= MIN_AREA {
+            let weight = weight.max(f64::EPSILON);
+            groups[index].push(Blob {
+                x: x / weight,
+                y: y / weight,
+                peak,
+                area,
+            });
+        }
+    }
+
+    let mut ambiguous = Vec::new();
+    let blobs = groups
+        .into_iter()
+        .enumerate()
+        .map(|(index, mut group)| {
+            if group.len() > 1 {
+                ambiguous.push(index);
+                None
+            } else {
+                group.pop()
+            }
+        })
+        .collect();
+
+    Ok(Decoded { blobs, ambiguous })
+}
+
+#[cfg(test)]
+mod tests {
+    use opencv::core::{CV_8UC1, Scalar};
+
+    use super::*;
+
+    const SIZE: (i32, i32) = (64, 48);
+
+    /// Square spot of `brightness` for LED `index`, as (index, x, y, half width, brightness)
+    type Spot = (usize, usize, usize, usize, u8);
+
+    /// Captures of every pattern and its inverse for `num_led` LEDs drawn as `spots`, where LEDs overlap the
+    /// brighter one shows
+    fn captures(spots: &[Spot], num_led: usize) -> Vec<Mat> {
+        let mut captures = Vec::new();
+        for bit in 0..bit_planes(num_led) {
+            for inverse in [false, true] {
+                let mut capture =
+                    Mat::new_rows_cols_with_default(SIZE.1, SIZE.0, CV_8UC1, Scalar::all(0.0))
+                        .unwrap();
+                let pixels = capture.data_bytes_mut().unwrap();
+                for &(index, x, y, half, brightness) in spots {
+                    if !is_lit(index, bit, inverse) {
+                        continue;
+                    }
+                    for row in y - half..=y + half {
+                        for column in x - half..=x + half {
+                            let pixel = &mut pixels[row * SIZE.0 as usize + column];
+                            *pixel = (*pixel).max(brightness);
+                        }
+                    }
+                }
+                captures.push(capture);
+            }
+        }
+        captures
+    }
+
+    #[test]
+    fn gray_code_round_trips() {
+        for index in 0..4096 {
+            assert_eq!(gray_decode(gray_code(index)), index);
+            // Neighbours on the strip only differ in one pattern
+            assert_eq!((gray_code(index) ^ gray_code(index + 1)).count_ones(), 1);
+        }
+    }
+
+    #[test]
+    fn every_led_is_lit_in_a_pattern_or_its_inverse() {
+        assert_eq!(bit_planes(1), 1);
+        assert_eq!(bit_planes(2), 1);
+        assert_eq!(bit_planes(3), 2);
+        assert_eq!(bit_planes(256), 8);
+        assert_eq!(bit_planes(257), 9);
+
+        for index in 0..300 {
+            for bit in 0..bit_planes(300) {
+                assert_ne!(is_lit(index, bit, false), is_lit(index, bit, true));
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_separate_leds() {
+        let spots = [
+            (0, 10, 10, 2, 200),
+            (3, 30, 20, 2, 200),
+            (7, 50, 40, 2, 200),
+        ];
+        let decoded = decode(&captures(&spots, 8), 100.0, 8).unwrap();
+
+        assert!(decoded.ambiguous.is_empty());
+        assert_eq!(decoded.found(), 3);
+        for (index, x, y, _, _) in spots {
+            let blob = decoded.blobs[index].as_ref().unwrap();
+            assert!((blob.x - x as f64).abs() < 1e-9 && (blob.y - y as f64).abs() < 1e-9);
+            assert_eq!(blob.area, 25);
+            assert_eq!(blob.peak, 200.0);
+        }
+    }
+
+    #[test]
+    fn skips_dim_and_tiny_spots() {
+        let spots = [(1, 10, 10, 2, 50), (2, 30, 20, 0, 200)];
+        let decoded = decode(&captures(&spots, 8), 100.0, 8).unwrap();
+
+        assert_eq!(decoded.found(), 0);
+        assert!(decoded.ambiguous.is_empty());
+    }
+
+    #[test]
+    fn marks_leds_seen_twice_as_ambiguous() {
+        // A reflection of LED 2 next to the LED itself
+        let spots = [
+            (2, 10, 10, 2, 200),
+            (2, 40, 30, 2, 200),
+            (5, 50, 10, 2, 200),
+        ];
+        let decoded = decode(&captures(&spots, 8), 100.0, 8).unwrap();
+
+        assert_eq!(decoded.ambiguous, vec![2]);
+        assert_eq!(decoded.blobs[2], None);
+        assert!(decoded.blobs[5].is_some());
+        assert_eq!(decoded.found(), 1);
+    }
+
+    #[test]
+    fn keeps_overlapping_leds_apart() {
+        // LEDs 1 and 2 differ in one pattern, where their overlap is as bright as its inverse
+        let spots = [(1, 20, 20, 3, 200), (2, 24, 20, 3, 200)];
+        let decoded = decode(&captures(&spots, 8), 100.0, 8).unwrap();
+
+        assert!(decoded.ambiguous.is_empty());
+        assert_eq!(decoded.found(), 2);
+        let (first, second) = (
+            decoded.blobs[1].as_ref().unwrap(),
+            decoded.blobs[2].as_ref().unwrap(),
+        );
+        // Only the parts that aren't shared are kept, so each blob moves away from the other
+        assert_eq!(first.area, 4 * 7);
+        assert_eq!(second.area, 4 * 7);
+        assert!((first.x - 18.5).abs() < 1e-9 && (first.y - 20.0).abs() < 1e-9);
+        assert!((second.x - 25.5).abs() < 1e-9 && (second.y - 20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn needs_an_inverse_for_every_pattern() {
+        let mut captures = captures(&[(0, 10, 10, 2, 200)], 8);
+        captures.pop();
+
+        assert!(decode(&captures, 100.0, 8).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ledmap::LedMap;
+
+/// Affine transform as a row-major 4x4 matrix, applied to column vectors
+pub type Matrix = [[f64; 4]; 4];
+
+pub const IDENTITY: Matrix = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+/// One change to every position in a file. Rotations and mirrors happen around the middle of the located LEDs,
+/// so the layout stays where it is. Scaling is from the origin, so units convert cleanly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransformStep {
+    /// Move the middle of the located LEDs to the origin
+    Recenter,
+    /// Swap two axes
+    Swap(usize, usize),
+    /// Flip an axis around the middle
+    Mirror(usize),
+    /// Rotate counter-clockwise around an axis through the middle, looking down the axis towards the origin
+    Rotate {
+        axis: usize,
+        degrees: f64,
+    },
+    Scale([f64; 3]),
+    Translate([f64; 3]),
+}
+
+impl TransformStep {
+    /// A short description, as recorded in the file's metadata
+    pub fn describe(&self) -> String {
+        let names = ["x", "y", "z"];
+        match *self {
+            TransformStep::Recenter => "recenter".to_string(),
+            TransformStep::Swap(a, b) => format!("swap {} {}", names[a], names[b]),
+            TransformStep::Mirror(axis) => format!("mirror {}", names[axis]),
+            TransformStep::Rotate { axis, degrees } => format!("rotate {} {degrees}", names[axis]),
+            TransformStep::Scale([x, y, z]) => format!("scale {x} {y} {z}"),
+            TransformStep::Translate([x, y, z]) => format!("translate {x} {y} {z}"),
+        }
+    }
+
+    /// The matrix for this step, with `middle` as the middle of the LEDs before it
+    pub fn matrix(&self, middle: [f64; 3]) -> Matrix {
+        let around_middle = |matrix: Matrix| {
+            multiply(
+                &translation(middle),
+                &multiply(&matrix, &translation(middle.map(|value| -value))),
+            )
+        };
+
+        match *self {
+            TransformStep::Recenter => translation(middle.map(|value| -value)),
+            TransformStep::Swap(a, b) => {
+                let mut matrix = IDENTITY;
+                matrix.swap(a, b);
+                matrix
+            }
+            TransformStep::Mirror(axis) => {
+                let mut matrix = IDENTITY;
+                matrix[axis][axis] = -1.0;
+                around_middle(matrix)
+            }
+            TransformStep::Rotate { axis, degrees } => {
+                // Right quarter turns are exact, so rotating by 90 doesn't leave 1e-16 everywhere
+                let (sin, cos) = match degrees.rem_euclid(360.0) {
+                    0.0 => (0.0, 1.0),
+                    90.0 => (1.0, 0.0),
+                    180.0 => (0.0, -1.0),
+                    270.0 => (-1.0, 0.0),
+                    _ => degrees.to_radians().sin_cos(),
+                };
+                let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+
+                let mut matrix = IDENTITY;
+                matrix[a][a] = cos;
+                matrix[a][b] = -sin;
+                matrix[b][a] = sin;
+                matrix[b][b] = cos;
+                around_middle(matrix)
+            }
+            TransformStep::Scale(factors) => {
+                let mut matrix = IDENTITY;
+                for (axis, factor) in factors.into_iter().enumerate() {
+                    matrix[axis][axis] = factor;
+                }
+                matrix
+            }
+            TransformStep::Translate(offset) => translation(offset),
+        }
+    }
+}
+
+/// What `transform_positions` did, kept in the file's metadata
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TransformRecord {
+    pub steps: Vec<String>,
+    /// Every step combined
+    pub matrix: Matrix,
+}
+
+/// Parses `x`, `y` or `z`
+pub fn parse_axis(axis: &str) -> Result<usize, String> {
+    match axis.trim().to_lowercase().as_str() {
+        "x" => Ok(0),
+        "y" => Ok(1),
+        "z" => Ok(2),
+        axis => Err(format!("Unknown axis {axis}, expected x, y or z")),
+    }
+}
+
+/// Parses a rotation like `x:90`
+pub fn parse_rotation(rotation: &str) -> Result<TransformStep, String> {
+    let Some((axis, degrees)) = rotation.split_once(':') else {
+        return Err(format!(
+            "Invalid rotation {rotation}, expected AXIS:DEGREES, e.g. x:90"
+        ));
+    };
+
+    Ok(TransformStep::Rotate {
+        axis: parse_axis(axis)?,
+        degrees: degrees
+            .trim()
+            .parse()
+            .map_err(|e| format!("Invalid rotation {rotation}: {e}"))?,
+    })
+}
+
+/// Parses a pair of axes like `y,z`
+pub fn parse_swap(swap: &str) -> Result<TransformStep, String> {
+    let Some((a, b)) = swap.split_once(',') else {
+        return Err(format!("Invalid swap {swap}, expected two axes, e.g. y,z"));
+    };
+
+    let (a, b) = (parse_axis(a)?, parse_axis(b)?);
+    if a == b {
+        return Err(format!("Invalid swap {swap}, the axes have to differ"));
+    }
+
+    Ok(TransformStep::Swap(a, b))
+}
+
+/// Parses a scale like `2` or `1,1,-1`. A factor of 0 would flatten the LEDs, so it is refused.
+pub fn parse_scale(scale: &str) -> Result<TransformStep, String> {
+    let factors = parse_vector(scale, true)?;
+    if factors.contains(&0.0) {
+        return Err(format!("Invalid scale {scale}, factors can't be 0"));
+    }
+
+    Ok(TransformStep::Scale(factors))
+}
+
+/// Parses `x,y,z`, or a single value used for every axis when `uniform` is allowed
+pub fn parse_vector(vector: &str, uniform: bool) -> Result<[f64; 3], String> {
+    let values = vector
+        .split(',')
+        .map(|value| value.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|e| format!("Invalid value {vector}: {e}"))?;
+
+    match values[..] {
+        [x, y, z] => Ok([x, y, z]),
+        [value] if uniform => Ok([value; 3]),
+        _ if uniform => Err(format!(
+            "Invalid value {vector}, expected one number or X,Y,Z"
+        )),
+        _ => Err(format!("Invalid value {vector}, expected X,Y,Z")),
+    }
+}
+
+/// Applies `steps` in order to every LED and records them in the metadata. The middle for each step is the
+/// middle of the bounds of the located LEDs after the steps before it, or the origin when there are none.
+pub fn transform_positions(led_pos: &mut LedMap, steps: &[TransformStep]) -> TransformRecord {
+    let mut combined = IDENTITY;
+
+    for step in steps {
+        let middle = led_pos
+            .bounds()
+            .map(|bounds| [0, 1, 2].map(|axis| (bounds.min[axis] + bounds.max[axis]) / 2.0))
+            .unwrap_or_default();
+        let matrix = step.matrix(middle);
+
+        for led in led_pos.leds.iter_mut() {
+            [led.x, led.y, led.z] = apply(&matrix, [led.x, led.y, led.z]);
+        }
+        combined = multiply(&matrix, &combined);
+    }
+
+    let record = TransformRecord {
+        steps: steps.iter().map(TransformStep::describe).collect(),
+        matrix: combined,
+    };
+    led_pos.metadata.transforms.push(record.clone());
+    led_pos.metadata.bounds = led_pos.bounds();
+
+    record
+}
+
+pub fn apply(matrix: &Matrix, point: [f64; 3]) -> [f64; 3] {
+    [0, 1, 2].map(|row| {
+        matrix[row][0] * point[0]
+            + matrix[row][1] * point[1]
+            + matrix[row][2] * point[2]
+            + matrix[row][3]
+    })
+}
+
+pub fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    std::array::from_fn(|row| {
+        std::array::from_fn(|column| (0..4).map(|i| a[row][i] * b[i][column]).sum())
+    })
+}
+
+fn translation(offset: [f64; 3]) -> Matrix {
+    let mut matrix = IDENTITY;
+    for (axis, value) in offset.into_iter().enumerate() {
+        matrix[axis][3] = value;
+    }
+    matrix
+}
@@ -1,8 +1,6 @@
 use std::{
-    cmp::max,
     collections::HashMap,
     error::Error,
-    fs::File,
     io::prelude::*,
     net::{Ipv4Addr, TcpStream, UdpSocket},
     str,
@@ -11,7 +9,7 @@ use std::{
         atomic::{AtomicBool, Ordering},
     },
     thread::{self, JoinHandle},
-    time::{Duration, SystemTime},
+    time::Duration,
 };
 
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
@@ -26,8 +24,14 @@ use opencv::{
 };
 
 use crate::{
-    Config, GetEventsFrameBuffer, IOHandles, LedState, ManagerData, ManagerState, PosEntry,
-    RuntimeConfig, UnityOptions, VisionData, led_manager, scan::get_cam,
+    Config, GetEventsFrameBuffer, IOHandles, LedState, ManagerData, ManagerState, RuntimeConfig,
+    UnityOptions, VisionData, led_manager,
+    ledmap::{self, LedMap},
+    load_index_map,
+    recording::{self, RecordingFormat, RecordingSlot, container_recording_path},
+    scan::get_cam,
+    scene::Scene,
+    spatial::SpatialIndex,
 };
 
 pub fn signal_restart(unity_ip: Ipv4Addr, unity_port: u32) {
@@ -49,45 +53,19 @@ pub fn signal_restart(unity_ip: Ipv4Addr, unity_port: u32) {
     };
 }
 
-pub fn send_pos(unity: UnityOptions) -> std::io::Result<()> {
-    for mut i in 1..=unity.num_container {
-        i -= 1; // TODO: There is def a better way
-        debug!(
-            "sending pos file {:?}",
-            unity.unity_position_files[i as usize]
-        );
-        let mut pos_file = match File::open(unity.unity_position_files[i as usize].clone()) {
-            Ok(file) => file,
-            Err(e) => {
-                panic!(
-                    "Could not read {:?}: {}",
-                    unity.unity_position_files[i as usize], e
-                )
-            }
-        };
+pub fn send_pos(config: &Config) -> std::io::Result<()> {
+    let unity = &config.unity_options;
 
-        let mut file_contents = String::new();
-        match pos_file.read_to_string(&mut file_contents) {
-            Ok(_) => {}
-            Err(e) => {
-                panic!(
-                    "Could not read position file {}: {}",
-                    unity.unity_position_files[i as usize].display(),
-                    e
-                )
-            }
-        };
+    // Containers are sent where the scene places them, so Unity sees them in one world
+    let world = match Scene::from_config(config).and_then(|scene| scene.world()) {
+        Ok(world) => world,
+        Err(e) => {
+            panic!("{e}")
+        }
+    };
 
-        let json: PosEntry = match serde_json::from_str(&file_contents) {
-            Ok(json) => json,
-            Err(e) => {
-                panic!(
-                    "{} contains invalid or incomplete calibration data: {}",
-                    unity.unity_position_files[i as usize].display(),
-                    e
-                )
-            }
-        };
+    for (i, json) in world.containers.iter().enumerate() {
+        debug!("sending pos file {:?}", unity.unity_position_files[i]);
 
         let pb = ProgressBar::new(json.len().try_into().unwrap());
         pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos:>3}/{len:3} ({eta})")
@@ -100,21 +78,21 @@ pub fn send_pos(unity: UnityOptions) -> std::io::Result<()> {
         let mut stream = TcpStream::connect(format!(
             "{}:{}",
             unity.unity_ip.clone(),
-            unity.unity_ports.clone()[i as usize]
+            unity.unity_ports.clone()[i]
         ))?;
         stream.set_read_timeout(Some(Duration::new(1, 0))).unwrap();
         stream.set_write_timeout(Some(Duration::new(1, 0))).unwrap();
         debug!("sending positions to connection");
-        for led in json.iter() {
+        for led in json.leds.iter() {
             pb_count += 1;
             pb.set_position(pb_count);
             stream
                 .write_all(
                     format!(
                         "{},{},{}",
-                        led.1.0 as f32 * unity.scale,
-                        led.1.1 as f32 * unity.scale,
-                        led.2.0 as f32 * unity.scale
+                        led.x as f32 * unity.scale,
+                        led.y as f32 * unity.scale,
+                        led.z as f32 * unity.scale
                     )
                     .as_bytes(),
                 )
@@ -154,7 +132,7 @@ pub fn get_events(
     let socket = UdpSocket::bind(format!("{ip}:{port}"))?;
 
     // load positions if we are streaming video with widgets
-    let mut json: PosEntry;
+    let mut json: LedMap;
     let mut json_hashmap: Arc<Mutex<JsonHashmap>> = Arc::new(Mutex::new(Default::default()));
 
     let mut frame_cam_1: Mat = Default::default();
@@ -176,68 +154,45 @@ pub fn get_events(
             .get_events_widgets_pos_index
             .unwrap_or(0);
 
-        let mut pos_file = match File::open(unity.unity_position_files[pos_index as usize].clone())
-        {
-            Ok(file) => file,
-            Err(e) => {
-                panic!(
-                    "Could not read {:?}: {}",
-                    unity.unity_position_files[pos_index as usize], e
-                )
-            }
-        };
-
-        let mut file_contents = String::new();
-        match pos_file.read_to_string(&mut file_contents) {
-            Ok(_) => {}
-            Err(e) => {
-                panic!(
-                    "Could not read position file {}: {}",
-                    unity.unity_position_files[pos_index as usize].display(),
-                    e
-                )
-            }
-        };
-
-        json = match serde_json::from_str(&file_contents) {
+        json = match ledmap::read_led_map(&unity.unity_position_files[pos_index as usize]) {
             Ok(json) => json,
             Err(e) => {
-                panic!(
-                    "{} contains invalid or incomplete calibration data: {}",
-                    unity.unity_position_files[pos_index as usize].display(),
-                    e
-                )
+                panic!("{e}")
             }
         };
 
-        let led_count = json.len();
-        let mut y_max = i32::MIN;
-
-        for item in json.iter().take(led_count) {
-            // Get max and min values in led_pos
-            y_max = max(item.1.1, y_max);
+        // Colors arrive by logical index, so widgets have to be in the same order
+        let index_map = Scene::from_config(config)
+            .ok()
+            .and_then(|scene| scene.containers.get(pos_index as usize).cloned())
+            .and_then(|container| container.index_map);
+        if let Some(path) = index_map {
+            json = load_index_map(&path, json.len() as u32).reorder(&json);
         }
 
-        for item in json.iter_mut().take(led_count) {
-            let y_mid: f32 = (y_max / 2) as f32;
-            let current_y: f32 = item.1.1 as f32;
+        let y_max = json.leds.iter().map(|led| led.y).fold(f64::MIN, f64::max);
+
+        for led in json.leds.iter_mut() {
+            // Undo the flip from post processing so widgets line up with the camera image
+            let y_mid = (y_max / 2.0).trunc();
 
-            item.1.1 = match current_y {
-                y if y > y_mid => (y_mid - (y - y_mid)).round() as i32,
-                y if y < y_mid => (y_mid + (y_mid - y)).round() as i32,
-                _ => item.1.1,
+            led.y = match led.y {
+                y if y > y_mid => (y_mid - (y - y_mid)).round(),
+                y if y < y_mid => (y_mid + (y_mid - y)).round(),
+                y => y,
             };
         }
 
         json_hashmap = Arc::new(Mutex::new(
-            json.into_iter()
+            json.leds
+                .into_iter()
                 .enumerate()
-                .map(|(i, (_key, val1, val2))| {
+                .map(|(i, led)| {
                     (
                         i,
                         (
-                            (val1.0 as f32, val1.1 as f32),
-                            (val2.0 as f32, val2.1 as f32),
+                            (led.x as f32, led.y as f32),
+                            (led.z as f32, led.y as f32),
                             (0u8, 0u8, 0u8),
                             false,
                         ),
@@ -381,6 +336,21 @@ pub fn get_events(
             .unwrap();
     }
 
+    // Positions of this container in the world, for packets that light every LED in an area
+    let spatial_index = unity
+        .unity_ports
+        .iter()
+        .position(|container_port| container_port == port)
+        .and_then(
+            |container| match Scene::from_config(config).and_then(|scene| scene.world()) {
+                Ok(world) => world.containers.get(container).map(SpatialIndex::located),
+                Err(e) => {
+                    warn!("SPHERE packets are unavailable on port {port}: {e}");
+                    None
+                }
+            },
+        );
+
     socket.set_nonblocking(true)?;
     let mut buf = [0; 4096];
 
@@ -405,7 +375,74 @@ pub fn get_events(
                         continue;
                     }
 
-                    if line.contains("CLEAR") {
+                    if let Some(command) = line.strip_prefix("RECORD") {
+                        // RECORD START [VLED|BVLED|ALL], RECORD STOP [VLED|BVLED|ALL], RECORD MARK <label>
+                        let command = command.trim();
+                        let (action, argument) = command.split_once(' ').unwrap_or((command, ""));
+
+                        match action.to_uppercase().as_str() {
+                            "START" | "STOP" => {
+                                let formats = if argument.is_empty() {
+                                    if action.eq_ignore_ascii_case("START") {
+                                        vec![RecordingFormat::Vled]
+                                    } else {
+                                        vec![RecordingFormat::Vled, RecordingFormat::Bvled]
+                                    }
+                                } else {
+                                    match recording::parse_recording_formats(argument) {
+                                        Some(formats) => formats,
+                                        None => {
+                                            error!(
+                                                "Unknown recording format {argument} in packet: {line}"
+                                            );
+                                            continue;
+                                        }
+                                    }
+                                };
+
+                                if action.eq_ignore_ascii_case("START") {
+                                    recording::start_recording(&manager, &formats);
+                                } else {
+                                    recording::stop_recording(&manager, &formats);
+                                }
+                            }
+                            "MARK" => recording::mark_recording(&manager, argument.trim()),
+                            _ => error!("Unknown recording command! Packet: {line}"),
+                        }
+                    } else if let Some(arguments) = line.strip_prefix("SPHERE") {
+                        // SPHERE x y z radius r g b, in the same units as the positions sent to Unity
+                        let Some(spatial_index) = &spatial_index else {
+                            error!(
+                                "No positions loaded for this container, ignoring packet: {line}"
+                            );
+                            continue;
+                        };
+                        let values: Vec<f64> = match arguments
+                            .split_whitespace()
+                            .map(str::parse::<f64>)
+                            .collect::<Result<Vec<f64>, _>>()
+                        {
+                            Ok(values) if values.len() == 7 => values,
+                            _ => {
+                                error!(
+                                    "Unity packet was malformed, expected SPHERE x y z radius r g b! Packet: {line}"
+                                );
+                                continue;
+                            }
+                        };
+
+                        let scale = unity.scale as f64;
+                        let center = [values[0] / scale, values[1] / scale, values[2] / scale];
+                        let (r, g, b) = (values[4] as u8, values[5] as u8, values[6] as u8);
+                        for index in spatial_index.within_sphere(center, values[3] / scale) {
+                            led_manager::set_color(&manager, index as u16, r, g, b);
+
+                            if let Some(value) = hashmap_lock.get_mut(&index) {
+                                value.3 = r != 0 || g != 0 || b != 0;
+                                value.2 = (r, g, b);
+                            }
+                        }
+                    } else if line.contains("CLEAR") {
                         for i in 0..config.num_led {
                             led_manager::set_color(&manager, i.try_into().unwrap(), 0, 0, 0);
                         }
@@ -471,6 +508,9 @@ pub fn get_events(
                 if e.kind() == std::io::ErrorKind::WouldBlock
                     || e.kind() == std::io::ErrorKind::TimedOut =>
             {
+                // Another container may have stopped recording while we were idle
+                recording::finish_stopped_recordings(&mut manager.lock().unwrap());
+
                 thread::sleep(Duration::from_millis(1));
                 continue;
             }
@@ -490,6 +530,19 @@ pub fn get_events(
         }
     }
 
+    // Each container has its own ManagerData, so its takes are finished here rather than by the caller. A multi-track
+    // take is shared with the other containers, start_listeners finishes it once all of them are done.
+    {
+        let mut manager = manager.lock().unwrap();
+        if manager.config.recording_track.is_some() {
+            if let Some(session) = manager.io.esp_data_recording.lock().unwrap().take() {
+                recording::finish_recording(session);
+            }
+        } else {
+            recording::finish_recordings(&mut manager);
+        }
+    }
+
     Ok(())
 }
 
@@ -507,9 +560,66 @@ pub fn start_listeners(
 
     let mut children = Vec::new();
 
-    for i in 0..config_holder.unity_options.num_container {
+    let num_container = config_holder.unity_options.num_container;
+    let multi_track = num_container > 1
+        && config_holder
+            .recording
+            .multi_track_recording
+            .unwrap_or(false);
+
+    // Each container can have its own controller in the scene
+    let scene = match Scene::from_config(config_holder) {
+        Ok(scene) => scene,
+        Err(e) => {
+            panic!("{e}")
+        }
+    };
+
+    // When recording a multi-track file, every container writes into the same take
+    let shared_data_recording: RecordingSlot = Default::default();
+
+    if multi_track {
+        info!("All containers will be recorded into one multi-track vled file.");
+    }
+
+    for i in 0..num_container {
         debug!("Spawning listening thread.");
 
+        let (record_data_file, data_recording, recording_track) = if multi_track {
+            (
+                container_recording_path(&config_holder.recording.record_data_file, "all", 1),
+                Arc::clone(&shared_data_recording),
+                Some(i),
+            )
+        } else {
+            (
+                container_recording_path(
+                    &config_holder.recording.record_data_file,
+                    &i.to_string(),
+                    num_container,
+                ),
+                Default::default(),
+                None,
+            )
+        };
+        let record_esp_data_file = container_recording_path(
+            &config_holder.recording.record_esp_data_file,
+            &i.to_string(),
+            num_container,
+        );
+
+        let controller = scene
+            .containers
+            .get(i as usize)
+            .map(|container| container.controller.clone())
+            .unwrap_or_default();
+        let index_map = scene
+            .containers
+            .get(i as usize)
+            .and_then(|container| container.index_map.as_deref())
+            .map(|path| Arc::new(load_index_map(path, config_holder.num_led)));
+        let communication = &config_holder.communication;
+
         let owned_manager;
 
         {
@@ -518,17 +628,23 @@ pub fn start_listeners(
                 config: RuntimeConfig {
                     num_led: config_holder.num_led,
                     num_strips: config_holder.num_strips,
-                    communication_mode: config_holder.communication.communication_mode,
-                    host: config_holder.communication.host,
-                    port: config_holder.communication.port,
-                    serial_port_paths: manager.config.serial_port_paths.clone(),
-                    baud_rate: config_holder.communication.baud_rate,
+                    communication_mode: controller
+                        .communication_mode
+                        .unwrap_or(communication.communication_mode),
+                    host: controller.host.unwrap_or(communication.host),
+                    port: controller.port.unwrap_or(communication.port),
+                    serial_port_paths: controller
+                        .serial_port_paths
+                        .unwrap_or_else(|| manager.config.serial_port_paths.clone()),
+                    baud_rate: controller.baud_rate.unwrap_or(communication.baud_rate),
                     serial_read_timeout: manager.config.serial_read_timeout,
                     record_data: manager.config.record_data,
-                    record_data_file: manager.config.record_data_file.clone(),
+                    record_data_file,
                     record_esp_data: manager.config.record_esp_data,
                     unity_controls_recording: manager.config.unity_controls_recording,
-                    record_esp_data_file: manager.config.record_esp_data_file.clone(),
+                    record_esp_data_file,
+                    timestamp_recordings: manager.config.timestamp_recordings,
+                    recording_track,
                     print_send_back: config_holder.advanced.misc.print_send_back,
                     udp_read_timeout: config_holder.advanced.communication.udp_read_timeout,
                     con_fail_limit: config_holder.advanced.communication.con_fail_limit,
@@ -555,11 +671,11 @@ pub fn start_listeners(
                     skip_confirmation: config_holder.advanced.communication.skip_confirmation,
                     use_queue: config_holder.advanced.communication.use_queue,
                     queue_size: config_holder.advanced.communication.queue_size,
+                    index_map: index_map.clone(),
                     led_config: None, // This will be constructed as needed by led_manager
                 },
                 state: ManagerState {
-                    first_run: true,
-                    call_time: SystemTime::now(),
+                    recording_control: Arc::clone(&manager.state.recording_control),
                     keepalive_get_events: true,
                     keepalive: Arc::new(AtomicBool::new(true)),
                     led_state: LedState {
@@ -570,10 +686,11 @@ pub fn start_listeners(
                     all_thread_handles: Vec::new(),
                 },
                 io: IOHandles {
-                    data_file_buf: None,
-                    esp_data_file_buf: None,
+                    data_recording,
+                    esp_data_recording: Default::default(),
                     udp_socket: None,
                     serial_port: Vec::new(),
+                    led_sink: None,
                 },
                 vision: VisionData {
                     frame_cam_1: Default::default(),
@@ -611,5 +728,28 @@ pub fn start_listeners(
                 .unwrap(),
         )
     }
+
+    if multi_track {
+        // Containers stop at different times, the shared take is only finished once none of them can write to it
+        return vec![
+            thread::Builder::new()
+                .name("multi_track_recording".to_string())
+                .spawn(move || {
+                    let results: Vec<_> = children.into_iter().map(JoinHandle::join).collect();
+
+                    if let Some(session) = shared_data_recording.lock().unwrap().take() {
+                        recording::finish_recording(session);
+                    }
+
+                    for result in results {
+                        if let Err(e) = result {
+                            std::panic::resume_unwind(e);
+                        }
+                    }
+                })
+                .unwrap(),
+        ];
+    }
+
     children
 }
@@ -1,20 +1,26 @@
 use std::{
-    error::Error,
-    fs::{File, remove_file},
-    io::{BufWriter, Read, Write},
+    fs::File,
+    io::Read,
     net::{Ipv4Addr, UdpSocket},
     path::{Path, PathBuf},
     sync::{Arc, Mutex, atomic::AtomicBool},
     thread::JoinHandle,
-    time::SystemTime,
+    time::Duration,
 };
 
 use crossbeam_channel::Sender;
-use log::{debug, error, info, warn}; // TODO: Depreceate unity export byte data
+use log::{info, warn}; // TODO: Depreceate unity export byte data
 use opencv::prelude::*;
 use serde::Deserialize;
 use serialport::SerialPort;
 
+use crate::{
+    led_manager::LedSink,
+    recording::{self, RecordingControl, RecordingSlot, container_recording_path},
+    remap::{self, IndexMap},
+    scene::Scene,
+};
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub num_led: u32,
@@ -29,7 +35,8 @@ pub struct Config {
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct ScanConfig {
-    /// 0 is default, 1 filters by color first (Useful when you aren't scanning in perfect conditions)
+    /// 0 is default, 1 filters by color first (Useful when you aren't scanning in perfect conditions), 2 finds
+    /// every LED at once from Gray code patterns and only lights the ones it couldn't tell apart one at a time
     pub scan_mode: u32,
     /// 0 for red, 1 for green, 2 for blue
     pub filter_color: Option<u32>,
@@ -55,6 +62,10 @@ pub struct RecordingConfig {
     pub unity_controls_recording: bool,
     pub record_data_file: PathBuf,
     pub record_esp_data_file: PathBuf,
+    /// Name takes after the time they were started instead of numbering them. Existing files are never overwritten either way.
+    pub timestamp_recordings: Option<bool>,
+    /// When using multiple containers, record all of them into record_data_file as one multi-track vled instead of one file each
+    pub multi_track_recording: Option<bool>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -116,16 +127,33 @@ pub struct HsvOverrideConfig {
 pub struct ImageTransformConfig {
     /// When true, transform adjustments will be auto applied after scan.
     pub adjust_after_scan: Option<bool>,
+    /// When true, LEDs that weren't found are estimated from their neighbours on the strip after scan.
+    pub interpolate_after_scan: Option<bool>,
     /// When set, cropping will be skipped.
     pub crop_override: Option<Vec<i32>>,
-    /// Offsets distant LEDs toward the center to reduce perspective distortion, making the layout appear more orthographic.
-    pub x_perspect_distort_adjust: Option<i32>,
-    pub y_perspect_distort_adjust: Option<i32>,
-    pub z_perspect_distort_adjust: Option<i32>,
-    /// Amount to stretch (or shrink) from center on a specified axis
-    pub x_stretch_adjust: Option<i32>,
-    pub y_stretch_adjust: Option<i32>,
-    pub z_stretch_adjust: Option<i32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct PerspectiveConfig {
+    /// Focal length of the cameras in pixels. Takes priority over `fov`.
+    pub focal_length: Option<f64>,
+    /// Horizontal field of view of the cameras in degrees, used with `video_width` to find the focal length
+    pub fov: Option<f64>,
+    /// Focal length and field of view of the second camera, when it differs from the first
+    pub camera2_focal_length: Option<f64>,
+    pub camera2_fov: Option<f64>,
+    /// Distance from the front camera's lens to the front of the container, in mm
+    pub camera_distance: Option<f64>,
+    /// Distance from the second camera's lens to the side (or top) of the container, in mm
+    pub camera2_distance: Option<f64>,
+    /// Width, height and depth of the container in mm
+    pub container_size: Option<Vec<f64>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RemapConfig {
+    /// Order file giving the physical index of each logical LED, see `remap::IndexMap`
+    pub index_map: Option<PathBuf>,
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -145,16 +173,26 @@ pub struct AdvancedConfig {
     #[serde(default)]
     pub transform: ImageTransformConfig,
     #[serde(default)]
+    pub perspective: PerspectiveConfig,
+    #[serde(default)]
+    pub remap: RemapConfig,
+    #[serde(default)]
     pub misc: MiscConfig,
 }
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct UnityOptions {
+    /// Filled in from `scene` when it is set
+    #[serde(default)]
     pub num_container: u8,
     pub unity_ip: Ipv4Addr,
+    #[serde(default)]
     pub unity_ports: Vec<u32>,
+    #[serde(default)]
     pub unity_position_files: Vec<PathBuf>,
     pub scale: f32,
+    /// Scene file placing every container in one world, see `scene::Scene`
+    pub scene: Option<PathBuf>,
 }
 pub struct ManagerData {
     pub config: RuntimeConfig,
@@ -178,6 +216,9 @@ pub struct RuntimeConfig {
     pub unity_controls_recording: bool,
     pub record_data_file: PathBuf,
     pub record_esp_data_file: PathBuf,
+    pub timestamp_recordings: bool,
+    /// Container to tag commands with when several containers share one multi-track recording
+    pub recording_track: Option<u8>,
     pub print_send_back: Option<bool>,
     pub udp_read_timeout: Option<u32>,
     pub con_fail_limit: Option<u32>,
@@ -192,12 +233,14 @@ pub struct RuntimeConfig {
     pub skip_confirmation: Option<bool>,
     pub use_queue: Option<bool>,
     pub queue_size: Option<usize>,
+    /// Translates the logical indices set_color is called with to physical ones on the strip
+    pub index_map: Option<Arc<IndexMap>>,
     pub led_config: Option<LedConfig>, // Exists so that we don't have to create a new struct every time we call set_color. Acts just as a holder for other items from RuntimeConfig
 }
 
 pub struct ManagerState {
-    pub first_run: bool,
-    pub call_time: SystemTime,
+    /// Shared between containers, see recording::RecordingControl
+    pub recording_control: Arc<RecordingControl>,
     pub keepalive: Arc<AtomicBool>,
     pub keepalive_get_events: bool,
     pub led_state: LedState,
@@ -209,8 +252,10 @@ pub struct ManagerState {
 pub struct IOHandles {
     pub udp_socket: Option<UdpSocket>,
     pub serial_port: Vec<Box<dyn SerialPort>>,
-    pub data_file_buf: Option<BufWriter<File>>,
-    pub esp_data_file_buf: Option<BufWriter<File>>,
+    pub data_recording: RecordingSlot,
+    pub esp_data_recording: RecordingSlot,
+    /// Takes the place of the controller, see led_manager::LedSink
+    pub led_sink: Option<Arc<dyn LedSink>>,
 }
 
 #[derive(Debug)]
@@ -278,6 +323,7 @@ pub struct Task {
     pub controller_queue_length: Option<u8>,
 }
 
+/// Legacy position file format of (status, (x, y), (z, y)), see `ledmap::LedMap` for the current one
 pub type PosEntry = Vec<(String, (i32, i32), (i32, i32))>;
 
 pub fn load_validate_conf(config_path: &Path) -> (ManagerData, UnityOptions, Config) {
@@ -296,9 +342,27 @@ pub fn load_validate_conf(config_path: &Path) -> (ManagerData, UnityOptions, Con
         .expect(
             "The config file contains non UTF-8 characters, what in the world did you put in it??",
         );
-    let config_holder: Config = toml::from_str(&config_file_contents)
+    let mut config_holder: Config = toml::from_str(&config_file_contents)
         .expect("The config file was not formatted properly and could not be read.");
 
+    // Code that only needs one container at a time keeps using the lists, so fill them in from the scene
+    if let Some(ref scene_path) = config_holder.unity_options.scene {
+        let scene = Scene::read(scene_path).unwrap_or_else(|e| panic!("{e}"));
+        info!(
+            "Using {} containers from {}",
+            scene.containers.len(),
+            scene_path.display()
+        );
+
+        config_holder.unity_options.num_container = scene.containers.len() as u8;
+        config_holder.unity_options.unity_ports = scene.unity_ports();
+        config_holder.unity_options.unity_position_files = scene
+            .containers
+            .iter()
+            .map(|container| container.position_file.clone())
+            .collect();
+    }
+
     // Validate config and inform user of settings
 
     if let Some(no_controller) = config_holder.advanced.misc.no_controller
@@ -327,16 +391,16 @@ pub fn load_validate_conf(config_path: &Path) -> (ManagerData, UnityOptions, Con
         if Path::new(&config_holder.recording.record_data_file).exists()
             && config_holder.recording.record_data
         {
-            warn!(
-                "{} will be overwritten once you start recording!",
+            info!(
+                "{} already exists, new recordings will be saved next to it.",
                 config_holder.recording.record_data_file.display()
             );
         }
         if Path::new(&config_holder.recording.record_esp_data_file).exists()
             && config_holder.recording.record_esp_data
         {
-            warn!(
-                "{} will be overwritten once you start recording!",
+            info!(
+                "{} already exists, new recordings will be saved next to it.",
                 config_holder.recording.record_esp_data_file.display()
             )
         }
@@ -361,6 +425,13 @@ pub fn load_validate_conf(config_path: &Path) -> (ManagerData, UnityOptions, Con
         info!("Using multiple cameras!");
     }
 
+    let index_map = config_holder
+        .advanced
+        .remap
+        .index_map
+        .as_deref()
+        .map(|path| Arc::new(load_index_map(path, config_holder.num_led)));
+
     (
         ManagerData {
             config: RuntimeConfig {
@@ -373,10 +444,23 @@ pub fn load_validate_conf(config_path: &Path) -> (ManagerData, UnityOptions, Con
                 baud_rate: config_holder.communication.baud_rate,
                 serial_read_timeout: config_holder.advanced.communication.serial_read_timeout,
                 record_data: config_holder.recording.record_data,
-                record_data_file: config_holder.recording.record_data_file.clone(),
+                record_data_file: container_recording_path(
+                    &config_holder.recording.record_data_file,
+                    "0",
+                    1,
+                ),
                 record_esp_data: config_holder.recording.record_esp_data,
                 unity_controls_recording: config_holder.recording.unity_controls_recording,
-                record_esp_data_file: config_holder.recording.record_esp_data_file.clone(),
+                record_esp_data_file: container_recording_path(
+                    &config_holder.recording.record_esp_data_file,
+                    "0",
+                    1,
+                ),
+                timestamp_recordings: config_holder
+                    .recording
+                    .timestamp_recordings
+                    .unwrap_or(false),
+                recording_track: None,
                 print_send_back: config_holder.advanced.misc.print_send_back,
                 udp_read_timeout: config_holder.advanced.communication.udp_read_timeout,
                 con_fail_limit: config_holder.advanced.communication.con_fail_limit,
@@ -403,11 +487,21 @@ pub fn load_validate_conf(config_path: &Path) -> (ManagerData, UnityOptions, Con
                 skip_confirmation: config_holder.advanced.communication.skip_confirmation,
                 use_queue: config_holder.advanced.communication.use_queue,
                 queue_size: config_holder.advanced.communication.queue_size,
+                index_map,
                 led_config: None,
             },
             state: ManagerState {
-                first_run: true,
-                call_time: SystemTime::now(),
+                recording_control: Arc::new(RecordingControl {
+                    // When Unity controls recording, nothing is recorded until it says so
+                    record_data: AtomicBool::new(
+                        config_holder.recording.record_data
+                            && !config_holder.recording.unity_controls_recording,
+                    ),
+                    record_esp_data: AtomicBool::new(
+                        config_holder.recording.record_esp_data
+                            && !config_holder.recording.unity_controls_recording,
+                    ),
+                }),
                 keepalive_get_events: true,
                 keepalive: Arc::new(AtomicBool::new(true)),
                 led_state: {
@@ -420,10 +514,11 @@ pub fn load_validate_conf(config_path: &Path) -> (ManagerData, UnityOptions, Con
                 all_thread_handles: Vec::new(),
             },
             io: IOHandles {
-                data_file_buf: None,
-                esp_data_file_buf: None,
+                data_recording: Default::default(),
+                esp_data_recording: Default::default(),
                 udp_socket: None,
                 serial_port: Vec::new(),
+                led_sink: None,
             },
             vision: VisionData {
                 frame_cam_1: Default::default(),
@@ -435,54 +530,50 @@ pub fn load_validate_conf(config_path: &Path) -> (ManagerData, UnityOptions, Con
     )
 }
 
-pub fn check_and_create_file(file: &PathBuf) -> Result<File, Box<dyn Error>> {
-    if Path::new(&file).exists() {
-        let remove_file_result = remove_file(file);
-        match remove_file_result {
-            Ok(()) => debug!("Removed {}", &file.display()),
-            Err(error) => error!("Could not remove {}: {}.", &file.display(), error),
-        }
-        match File::create(file) {
-            Ok(_) => {}
-            Err(e) => {
-                panic!("Could not create {}: {}", file.display(), e);
-            }
+/// Reads the order file LEDs are addressed in, warning when it doesn't cover every LED
+pub fn load_index_map(path: &Path, num_led: u32) -> IndexMap {
+    let index_map = match remap::read_index_map(path) {
+        Ok(index_map) => index_map,
+        Err(e) => {
+            panic!("{e}")
         }
-    }
-    let data_file = match File::create(file.clone()) {
-        Ok(file) => file,
-        Err(e) => panic!("Could not open {}: {}", file.display(), e),
     };
 
-    Ok(data_file)
+    if index_map.len() != num_led as usize {
+        warn!(
+            "{} orders {} LEDs, but there are {num_led}. LEDs past the end keep their index.",
+            path.display(),
+            index_map.len()
+        );
+    }
+    info!("Addressing LEDs in the order from {}", path.display());
+
+    index_map
 }
 
 pub fn flush_data(manager_guard: Arc<Mutex<ManagerData>>) {
     let mut manager = manager_guard.lock().unwrap();
-    // Flush our BufWriters
-    if manager.io.data_file_buf.is_some()
-        && let Some(data_file_buf) = manager.io.data_file_buf.as_mut()
-        && let Err(e) = data_file_buf.flush()
-    {
-        error!(
-            "Could not flush {}! It may be incomplete or corrupted. Error: {}",
-            manager.config.record_data_file.display(),
-            e
-        )
-    };
+    // Flush our recordings and move them to their final names
+    recording::finish_recordings(&mut manager);
+}
 
-    if manager.io.esp_data_file_buf.is_some()
-        && let Some(esp_data_file_buf) = manager.io.esp_data_file_buf.as_mut()
-    {
-        match esp_data_file_buf.flush() {
-            Ok(_) => {}
-            Err(e) => {
-                error!(
-                    "Could not flush {}! It may be incomplete or corrupted. Error: {}",
-                    manager.config.record_esp_data_file.display(),
-                    e
-                )
-            }
-        }
+/// Parses a human readable duration such as `30s`, `1500ms` or `2m`. Plain numbers are seconds.
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    let (number, multiplier) = if let Some(number) = input.strip_suffix("ms") {
+        (number, 0.001)
+    } else if let Some(number) = input.strip_suffix("s") {
+        (number, 1.0)
+    } else if let Some(number) = input.strip_suffix("m") {
+        (number, 60.0)
+    } else {
+        (input, 1.0)
     };
+
+    match number.trim().parse::<f64>() {
+        // Negative, infinite and far too long durations don't fit
+        Ok(value) => Duration::try_from_secs_f64(value * multiplier)
+            .map_err(|_| format!("{input} is not a valid duration")),
+        Err(e) => Err(format!("Could not parse duration {input}: {e}")),
+    }
 }
@@ -0,0 +1,610 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    error::Error,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    iter::Peekable,
+    path::Path,
+    slice,
+    time::Duration,
+};
+
+/// An LED within a recording, the container it was recorded from (multi-track recordings only) and its index
+pub type LedKey = (Option<u8>, u16);
+pub type Rgb = (u8, u8, u8);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VledEventKind {
+    Color {
+        track: Option<u8>,
+        index: u16,
+        r: u8,
+        g: u8,
+        b: u8,
+    },
+    Mark(String),
+}
+
+/// Something that happens `time` after the start of a recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct VledEvent {
+    pub time: Duration,
+    pub kind: VledEventKind,
+}
+
+/// A vled recording with every event placed on an absolute timeline, rather than the
+/// relative `T:n` delays used on disk. This makes it possible to edit recordings without replaying them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Vled {
+    /// Always sorted by time
+    pub events: Vec<VledEvent>,
+    /// Length of the recording, including any delay after the last event
+    pub duration: Duration,
+}
+
+impl Vled {
+    pub fn parse(contents: &str) -> Result<Vled, Box<dyn Error>> {
+        let mut vled = Vled::default();
+        let mut time = Duration::ZERO;
+        let mut track = None;
+
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            let malformed = |reason: String| -> Box<dyn Error> {
+                format!("line {}: {reason}", line_number + 1).into()
+            };
+
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(delay) = line.strip_prefix("T:") {
+                let millis = match delay.trim().parse::<f64>() {
+                    Ok(millis) if millis.is_finite() && millis >= 0.0 => millis,
+                    Ok(millis) => return Err(malformed(format!("{millis} is not a valid delay"))),
+                    Err(e) => return Err(malformed(format!("invalid delay {delay}: {e}"))),
+                };
+                time += Duration::from_micros((millis * 1000.0).round() as u64);
+            } else if let Some(container) = line.strip_prefix("C:") {
+                track = Some(
+                    container
+                        .trim()
+                        .parse::<u8>()
+                        .map_err(|e| malformed(format!("invalid container {container}: {e}")))?,
+                );
+            } else if let Some(label) = line.strip_prefix("M:") {
+                vled.events.push(VledEvent {
+                    time,
+                    kind: VledEventKind::Mark(label.to_string()),
+                });
+            } else if let Some(index) = line.strip_prefix("E") {
+                let index = index
+                    .trim()
+                    .parse::<u16>()
+                    .map_err(|e| malformed(format!("invalid index {index}: {e}")))?;
+                vled.events.push(VledEvent {
+                    time,
+                    kind: VledEventKind::Color {
+                        track,
+                        index,
+                        r: 0,
+                        g: 0,
+                        b: 0,
+                    },
+                });
+            } else if line.contains("|") {
+                let parts: Vec<&str> = line.split("|").map(str::trim).collect();
+                if parts.len() != 4 {
+                    return Err(malformed(format!("expected index|r|g|b, got {line}")));
+                }
+
+                let index = parts[0]
+                    .parse::<u16>()
+                    .map_err(|e| malformed(format!("invalid index {}: {e}", parts[0])))?;
+                let mut rgb = [0u8; 3];
+                for (value, part) in rgb.iter_mut().zip(&parts[1..]) {
+                    *value = part
+                        .parse::<u8>()
+                        .map_err(|e| malformed(format!("invalid color value {part}: {e}")))?;
+                }
+
+                vled.events.push(VledEvent {
+                    time,
+                    kind: VledEventKind::Color {
+                        track,
+                        index,
+                        r: rgb[0],
+                        g: rgb[1],
+                        b: rgb[2],
+                    },
+                });
+            } else {
+                return Err(malformed(format!("unrecognised line {line}")));
+            }
+        }
+
+        vled.duration = time;
+        Ok(vled)
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut written = Duration::ZERO;
+        let mut current_track = None;
+
+        for event in &self.events {
+            write_delay(writer, event.time, &mut written)?;
+
+            match &event.kind {
+                VledEventKind::Color {
+                    track,
+                    index,
+                    r,
+                    g,
+                    b,
+                } => {
+                    if track.is_some() && *track != current_track {
+                        writeln!(writer, "C:{}", track.unwrap())?;
+                        current_track = *track;
+                    }
+                    writeln!(writer, "{index}|{r}|{g}|{b}")?;
+                }
+                VledEventKind::Mark(label) => writeln!(writer, "M:{label}")?,
+            }
+        }
+
+        write_delay(writer, self.duration, &mut written)
+    }
+
+    /// Writes the recording in the bvled format played by the ESP. bvled has no room for containers or marks,
+    /// so those are dropped, and delays are rounded down to whole milliseconds with the remainder carried over.
+    pub fn write_bvled(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut written = Duration::ZERO;
+
+        for event in &self.events {
+            if let VledEventKind::Color { index, r, g, b, .. } = event.kind {
+                write_bvled_delay(writer, event.time, &mut written)?;
+
+                let n_bytes = index.to_le_bytes();
+                write!(
+                    writer,
+                    "0x{0:02X}, 0x{1:02X}, 0x{2:02X}, 0x{3:02X}, 0x{4:02X}, ",
+                    n_bytes[0], n_bytes[1], r, g, b
+                )?;
+            }
+        }
+
+        write_bvled_delay(writer, self.duration, &mut written)
+    }
+
+    /// Restores time order after events were moved around. The sort is stable, so events sharing
+    /// a timestamp keep their order and the last color written for an LED still wins.
+    pub fn sort(&mut self) {
+        self.events.sort_by_key(|event| event.time);
+        if let Some(last) = self.events.last() {
+            self.duration = self.duration.max(last.time);
+        }
+    }
+
+    /// Samples the recording every `frame_interval`, each frame holding the color of the first `num_led` LEDs
+    /// after every event up to and including that point. When `track` is set, other containers are skipped.
+    pub fn to_frames(
+        &self,
+        num_led: usize,
+        frame_interval: Duration,
+        track: Option<u8>,
+    ) -> Vec<Vec<Rgb>> {
+        self.frames(num_led, frame_interval, track).collect()
+    }
+
+    /// Same as `to_frames`, but works out each frame only when it is asked for
+    pub fn frames(
+        &self,
+        num_led: usize,
+        frame_interval: Duration,
+        track: Option<u8>,
+    ) -> VledFrames<'_> {
+        let frame_count = self
+            .duration
+            .as_nanos()
+            .div_ceil(frame_interval.as_nanos().max(1))
+            .max(1) as u32;
+
+        VledFrames {
+            events: self.events.iter().peekable(),
+            state: vec![(0, 0, 0); num_led],
+            frame_interval,
+            track,
+            frame: 0,
+            frame_count,
+        }
+    }
+
+    /// Plays `parts` back to back.
+    pub fn concat(parts: &[Vled]) -> Vled {
+        let mut vled = Vled::default();
+
+        for part in parts {
+            let offset = vled.duration;
+            vled.events
+                .extend(part.events.iter().cloned().map(|mut event| {
+                    event.time += offset;
+                    event
+                }));
+            vled.duration = offset + part.duration;
+        }
+
+        vled
+    }
+
+    /// Keeps everything between `start` and `end`. LEDs that were already lit at `start` are set at the
+    /// beginning of the result, so the trimmed recording looks the same as the original did at that point.
+    pub fn trim(&self, start: Duration, end: Option<Duration>) -> Vled {
+        let end = end.unwrap_or(self.duration).min(self.duration);
+        let mut state: BTreeMap<LedKey, Rgb> = BTreeMap::new();
+        let mut kept = Vec::new();
+
+        for event in &self.events {
+            if event.time < start {
+                if let VledEventKind::Color {
+                    track,
+                    index,
+                    r,
+                    g,
+                    b,
+                } = event.kind
+                {
+                    state.insert((track, index), (r, g, b));
+                }
+            } else if event.time < end || (event.time == end && end == self.duration) {
+                kept.push(VledEvent {
+                    time: event.time - start,
+                    kind: event.kind.clone(),
+                });
+            }
+        }
+
+        let mut events = state_events(&state, Duration::ZERO);
+        events.extend(kept);
+
+        Vled {
+            events,
+            duration: end.saturating_sub(start),
+        }
+    }
+
+    /// Speeds up (factor < 1) or slows down (factor > 1) the recording.
+    pub fn scale(&self, factor: f64) -> Vled {
+        Vled {
+            events: self
+                .events
+                .iter()
+                .map(|event| VledEvent {
+                    time: event.time.mul_f64(factor),
+                    kind: event.kind.clone(),
+                })
+                .collect(),
+            duration: self.duration.mul_f64(factor),
+        }
+    }
+
+    /// Plays the recording backwards. Each LED shows at reversed time `t` what it showed at `duration - t`
+    /// in the original, which means every color is set when the color after it was set originally.
+    pub fn reverse(&self) -> Vled {
+        let mut history: BTreeMap<LedKey, Vec<(Duration, Rgb)>> = BTreeMap::new();
+        let mut events = Vec::new();
+
+        for event in &self.events {
+            match &event.kind {
+                VledEventKind::Color {
+                    track,
+                    index,
+                    r,
+                    g,
+                    b,
+                } => history
+                    .entry((*track, *index))
+                    .or_default()
+                    .push((event.time, (*r, *g, *b))),
+                VledEventKind::Mark(label) => events.push(VledEvent {
+                    time: self.duration - event.time,
+                    kind: VledEventKind::Mark(label.clone()),
+                }),
+            }
+        }
+
+        for ((track, index), changes) in history {
+            let color_event = |time: Duration, (r, g, b): Rgb| VledEvent {
+                time,
+                kind: VledEventKind::Color {
+                    track,
+                    index,
+                    r,
+                    g,
+                    b,
+                },
+            };
+
+            // Whatever the LED ended on is where the reversed recording starts
+            events.push(color_event(Duration::ZERO, changes.last().unwrap().1));
+
+            // Walk backwards so that, for changes sharing a timestamp, the earlier original color is applied last
+            for k in (0..changes.len()).rev() {
+                let previous = if k > 0 { changes[k - 1].1 } else { (0, 0, 0) };
+                events.push(color_event(self.duration - changes[k].0, previous));
+            }
+        }
+
+        let mut vled = Vled {
+            events,
+            duration: self.duration,
+        };
+        vled.sort();
+        vled
+    }
+
+    /// Repeats the recording `count` times.
+    pub fn unroll(&self, count: u32) -> Vled {
+        Vled::concat(&vec![self.clone(); count as usize])
+    }
+
+    /// Starts `next` `fade` before this recording ends, blending between the two at `fps` frames per second.
+    pub fn crossfade(&self, next: &Vled, fade: Duration, fps: u32) -> Vled {
+        let fade = fade.min(self.duration).min(next.duration);
+        let overlap_start = self.duration - fade;
+
+        let mut events = Vec::new();
+        let mut state_a: BTreeMap<LedKey, Rgb> = BTreeMap::new();
+        let mut state_b: BTreeMap<LedKey, Rgb> = BTreeMap::new();
+        let mut a = self.events.iter().peekable();
+        let mut b = next.events.iter().peekable();
+
+        // Until the fade starts, the first recording plays untouched
+        while let Some(event) = a.next_if(|event| event.time < overlap_start) {
+            apply_event(&mut state_a, event);
+            events.push(event.clone());
+        }
+
+        let mut shown = state_a.clone();
+        let frames = ((fade.as_secs_f64() * fps.max(1) as f64).ceil() as u32).max(1);
+
+        for frame in 0..=frames {
+            let offset = fade.mul_f64(frame as f64 / frames as f64);
+            let time = overlap_start + offset;
+
+            while let Some(event) = a.next_if(|event| event.time <= time) {
+                apply_event(&mut state_a, event);
+                if let VledEventKind::Mark(_) = event.kind {
+                    events.push(event.clone());
+                }
+            }
+            while let Some(event) = b.next_if(|event| event.time <= offset) {
+                apply_event(&mut state_b, event);
+                if let VledEventKind::Mark(_) = event.kind {
+                    events.push(VledEvent {
+                        time: overlap_start + event.time,
+                        kind: event.kind.clone(),
+                    });
+                }
+            }
+
+            let weight = if fade.is_zero() {
+                1.0
+            } else {
+                offset.as_secs_f64() / fade.as_secs_f64()
+            };
+            let keys: BTreeSet<LedKey> = state_a.keys().chain(state_b.keys()).copied().collect();
+
+            for key in keys {
+                let blended = blend(
+                    state_a.get(&key).copied().unwrap_or((0, 0, 0)),
+                    state_b.get(&key).copied().unwrap_or((0, 0, 0)),
+                    weight,
+                );
+
+                if shown.get(&key) != Some(&blended) {
+                    shown.insert(key, blended);
+                    events.push(color_event_for(key, time, blended));
+                }
+            }
+        }
+
+        // After the fade, the second recording carries on by itself
+        events.extend(b.map(|event| VledEvent {
+            time: overlap_start + event.time,
+            kind: event.kind.clone(),
+        }));
+
+        let mut vled = Vled {
+            events,
+            duration: overlap_start + next.duration,
+        };
+        vled.sort();
+        vled
+    }
+
+    /// Moves every LED index found in `mapping` to its new index. Indices without a mapping are left alone.
+    pub fn remap(&self, mapping: &HashMap<u16, u16>) -> Vled {
+        let mut vled = self.clone();
+
+        for event in vled.events.iter_mut() {
+            if let VledEventKind::Color { index, .. } = &mut event.kind
+                && let Some(new_index) = mapping.get(index)
+            {
+                *index = *new_index;
+            }
+        }
+
+        vled
+    }
+
+    /// Combines recordings of different containers into one multi-track recording, assigning `tracks[i]` to `parts[i]`.
+    pub fn merge(parts: &[Vled], tracks: &[u8]) -> Vled {
+        let mut vled = Vled::default();
+
+        for (part, part_track) in parts.iter().zip(tracks) {
+            vled.events
+                .extend(part.events.iter().cloned().map(|mut event| {
+                    if let VledEventKind::Color { track, .. } = &mut event.kind {
+                        *track = Some(*part_track);
+                    }
+                    event
+                }));
+            vled.duration = vled.duration.max(part.duration);
+        }
+
+        vled.sort();
+        vled
+    }
+}
+
+/// Frames of a recording, see `Vled::frames`
+pub struct VledFrames<'a> {
+    events: Peekable<slice::Iter<'a, VledEvent>>,
+    state: Vec<Rgb>,
+    frame_interval: Duration,
+    track: Option<u8>,
+    frame: u32,
+    frame_count: u32,
+}
+
+impl Iterator for VledFrames<'_> {
+    type Item = Vec<Rgb>;
+
+    fn next(&mut self) -> Option<Vec<Rgb>> {
+        if self.frame >= self.frame_count {
+            return None;
+        }
+        let time = self.frame_interval * self.frame;
+        self.frame += 1;
+
+        while let Some(event) = self.events.next_if(|event| event.time <= time) {
+            if let VledEventKind::Color {
+                track: event_track,
+                index,
+                r,
+                g,
+                b,
+            } = event.kind
+                && (self.track.is_none() || event_track.is_none() || event_track == self.track)
+                && (index as usize) < self.state.len()
+            {
+                self.state[index as usize] = (r, g, b);
+            }
+        }
+
+        Some(self.state.clone())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.frame_count - self.frame) as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for VledFrames<'_> {}
+
+pub fn read_vled_file(path: &Path) -> Result<Vled, Box<dyn Error>> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+
+    Vled::parse(&contents).map_err(|e| format!("{} is malformed: {e}", path.display()).into())
+}
+
+pub fn write_vled_file(vled: &Vled, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(
+        File::create(path).map_err(|e| format!("Could not create {}: {e}", path.display()))?,
+    );
+    vled.write(&mut writer)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+pub fn write_bvled_file(vled: &Vled, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(
+        File::create(path).map_err(|e| format!("Could not create {}: {e}", path.display()))?,
+    );
+    vled.write_bvled(&mut writer)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Reads a remapping file, a JSON object of `{"old index": new index}`
+pub fn read_mapping_file(path: &Path) -> Result<HashMap<u16, u16>, Box<dyn Error>> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+
+    serde_json::from_str(&contents)
+        .map_err(|e| format!("{} is not a valid mapping file: {e}", path.display()).into())
+}
+
+/// Writes `T:` delays until `written` reaches `time`. Delays are whole microseconds, anything below that is carried over.
+fn write_delay(writer: &mut impl Write, time: Duration, written: &mut Duration) -> io::Result<()> {
+    let micros = time.saturating_sub(*written).as_micros();
+
+    if micros > 0 {
+        writeln!(writer, "T:{}.{:03}", micros / 1000, micros % 1000)?;
+        *written += Duration::from_micros(micros as u64);
+    }
+
+    Ok(())
+}
+
+/// Writes `0xFE` delay markers until `written` reaches `time`, each holding at most 255ms
+pub(crate) fn write_bvled_delay(
+    writer: &mut impl Write,
+    time: Duration,
+    written: &mut Duration,
+) -> io::Result<()> {
+    let mut millis = time.saturating_sub(*written).as_millis();
+    *written += Duration::from_millis(millis as u64);
+
+    while millis > 255 {
+        write!(writer, "0xFE, 0xFF, ")?;
+        millis -= 255;
+    }
+    if millis > 0 {
+        write!(writer, "0xFE, {millis:#04X}, ")?;
+    }
+
+    Ok(())
+}
+
+fn apply_event(state: &mut BTreeMap<LedKey, Rgb>, event: &VledEvent) {
+    if let VledEventKind::Color {
+        track,
+        index,
+        r,
+        g,
+        b,
+    } = event.kind
+    {
+        state.insert((track, index), (r, g, b));
+    }
+}
+
+fn color_event_for((track, index): LedKey, time: Duration, (r, g, b): Rgb) -> VledEvent {
+    VledEvent {
+        time,
+        kind: VledEventKind::Color {
+            track,
+            index,
+            r,
+            g,
+            b,
+        },
+    }
+}
+
+fn state_events(state: &BTreeMap<LedKey, Rgb>, time: Duration) -> Vec<VledEvent> {
+    state
+        .iter()
+        .map(|(key, color)| color_event_for(*key, time, *color))
+        .collect()
+}
+
+fn blend(from: Rgb, to: Rgb, weight: f64) -> Rgb {
+    let mix = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * weight).round() as u8;
+    (mix(from.0, to.0), mix(from.1, to.1), mix(from.2, to.2))
+}
@@ -0,0 +1,294 @@
+use std::str::FromStr;
+
+use crate::{ledmap::LedMap, spatial::Point, vled::Rgb};
+
+/// How an LED between voxels reads from them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampling {
+    /// The closest voxel
+    Nearest,
+    /// The eight surrounding voxels, weighted by how close the LED is to each
+    Trilinear,
+}
+
+impl FromStr for Sampling {
+    type Err = String;
+
+    fn from_str(sampling: &str) -> Result<Sampling, String> {
+        match sampling.to_lowercase().as_str() {
+            "nearest" => Ok(Sampling::Nearest),
+            "trilinear" => Ok(Sampling::Trilinear),
+            sampling => Err(format!(
+                "Unknown sampling {sampling}, expected nearest or trilinear"
+            )),
+        }
+    }
+}
+
+/// Values that can be blended when sampling between voxels, or when several LEDs fall into one
+pub trait Voxel: Clone + Default {
+    /// Weighted average of `samples`, whose weights add up to 1
+    fn blend(samples: &[(&Self, f64)]) -> Self;
+}
+
+impl Voxel for f64 {
+    fn blend(samples: &[(&f64, f64)]) -> f64 {
+        samples.iter().map(|(value, weight)| *value * weight).sum()
+    }
+}
+
+impl Voxel for Rgb {
+    fn blend(samples: &[(&Rgb, f64)]) -> Rgb {
+        let channel = |channel: fn(&Rgb) -> u8| {
+            samples
+                .iter()
+                .map(|(color, weight)| channel(color) as f64 * weight)
+                .sum::<f64>()
+                .round()
+                .clamp(0.0, 255.0) as u8
+        };
+
+        (
+            channel(|color| color.0),
+            channel(|color| color.1),
+            channel(|color| color.2),
+        )
+    }
+}
+
+/// A dense `width x height x depth` grid, stored x fastest, then y, then z
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxelGrid<T> {
+    dims: [usize; 3],
+    cells: Vec<T>,
+}
+
+impl<T: Clone + Default> VoxelGrid<T> {
+    pub fn new(dims: [usize; 3]) -> VoxelGrid<T> {
+        VoxelGrid::from_fn(dims, |_| T::default())
+    }
+
+    /// A grid with every voxel set to `f(x, y, z)`
+    pub fn from_fn(dims: [usize; 3], mut f: impl FnMut([usize; 3]) -> T) -> VoxelGrid<T> {
+        let dims = dims.map(|dim| dim.max(1));
+        let mut cells = Vec::with_capacity(dims[0] * dims[1] * dims[2]);
+        for z in 0..dims[2] {
+            for y in 0..dims[1] {
+                for x in 0..dims[0] {
+                    cells.push(f([x, y, z]));
+                }
+            }
+        }
+
+        VoxelGrid { dims, cells }
+    }
+
+    pub fn dims(&self) -> [usize; 3] {
+        self.dims
+    }
+
+    /// Position of a voxel in `cells`
+    pub fn index(&self, [x, y, z]: [usize; 3]) -> usize {
+        (z * self.dims[1] + y) * self.dims[0] + x
+    }
+
+    pub fn get(&self, cell: [usize; 3]) -> Option<&T> {
+        self.contains(cell).then(|| &self.cells[self.index(cell)])
+    }
+
+    pub fn get_mut(&mut self, cell: [usize; 3]) -> Option<&mut T> {
+        if self.contains(cell) {
+            let index = self.index(cell);
+            Some(&mut self.cells[index])
+        } else {
+            None
+        }
+    }
+
+    /// Ignored outside of the grid, so effects can draw shapes that cross its edges
+    pub fn set(&mut self, cell: [usize; 3], value: T) {
+        if let Some(voxel) = self.get_mut(cell) {
+            *voxel = value;
+        }
+    }
+
+    pub fn fill(&mut self, value: T) {
+        self.cells.fill(value);
+    }
+
+    pub fn cells(&self) -> &[T] {
+        &self.cells
+    }
+
+    pub fn cells_mut(&mut self) -> &mut [T] {
+        &mut self.cells
+    }
+
+    fn contains(&self, cell: [usize; 3]) -> bool {
+        (0..3).all(|axis| cell[axis] < self.dims[axis])
+    }
+}
+
+/// Where every LED of a position file falls in a voxel grid stretched over the located LEDs, the 3D version of
+/// drawing an image onto the front of a container. Effects can draw into a `VoxelGrid` of the same size and
+/// `sample` it to get each LED's color.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxelMapping {
+    dims: [usize; 3],
+    min: Point,
+    max: Point,
+    /// Voxels each LED reads from, with weights adding up to 1
+    weights: Vec<Vec<(usize, f64)>>,
+}
+
+impl VoxelMapping {
+    /// Maps every LED, the same way effects light them. LEDs outside the located ones are clamped to the edges
+    /// of the grid.
+    pub fn new(led_pos: &LedMap, dims: [usize; 3], sampling: Sampling) -> VoxelMapping {
+        let dims = dims.map(|dim| dim.max(1));
+        let (min, max) = led_pos
+            .bounds()
+            .map_or(([0.0; 3], [0.0; 3]), |bounds| (bounds.min, bounds.max));
+
+        let mut mapping = VoxelMapping {
+            dims,
+            min,
+            max,
+            weights: Vec::with_capacity(led_pos.len()),
+        };
+
+        for led in &led_pos.leds {
+            let coordinate = mapping.grid_coordinate([led.x, led.y, led.z]);
+            let weights = match sampling {
+                Sampling::Nearest => vec![(
+                    mapping.index(coordinate.map(|value| value.round() as usize)),
+                    1.0,
+                )],
+                Sampling::Trilinear => mapping.trilinear(coordinate),
+            };
+            mapping.weights.push(weights);
+        }
+
+        mapping
+    }
+
+    pub fn dims(&self) -> [usize; 3] {
+        self.dims
+    }
+
+    /// A grid of the mapped size
+    pub fn grid<T: Clone + Default>(&self) -> VoxelGrid<T> {
+        VoxelGrid::new(self.dims)
+    }
+
+    /// Where `point` is in the grid, from 0 to one less than the size on each axis. The first and last voxel
+    /// on each axis sit on the edges of the located LEDs.
+    pub fn grid_coordinate(&self, point: Point) -> [f64; 3] {
+        [0, 1, 2].map(|axis| {
+            let size = self.max[axis] - self.min[axis];
+            let last = (self.dims[axis] - 1) as f64;
+            if size > 0.0 {
+                ((point[axis] - self.min[axis]) / size * last).clamp(0.0, last)
+            } else {
+                0.0
+            }
+        })
+    }
+
+    /// Position of the middle of a voxel, in the units of the position file
+    pub fn voxel_position(&self, cell: [usize; 3]) -> Point {
+        [0, 1, 2].map(|axis| {
+            let last = (self.dims[axis] - 1) as f64;
+            if last > 0.0 {
+                self.min[axis] + (self.max[axis] - self.min[axis]) * cell[axis] as f64 / last
+            } else {
+                (self.min[axis] + self.max[axis]) / 2.0
+            }
+        })
+    }
+
+    /// Each LED's value from `grid`, which has to be of the mapped size
+    pub fn sample<T: Voxel>(&self, grid: &VoxelGrid<T>) -> Vec<T> {
+        assert_eq!(
+            grid.dims(),
+            self.dims,
+            "The grid has to be the size it was mapped for"
+        );
+
+        self.weights
+            .iter()
+            .map(|weights| {
+                let samples: Vec<(&T, f64)> = weights
+                    .iter()
+                    .map(|(cell, weight)| (&grid.cells[*cell], *weight))
+                    .collect();
+                T::blend(&samples)
+            })
+            .collect()
+    }
+
+    /// The opposite of `sample`: every voxel becomes the weighted average of the LEDs that read from it, or
+    /// the default when none do. Useful for seeding an effect from what the LEDs currently show.
+    pub fn splat<T: Voxel>(&self, values: &[T]) -> VoxelGrid<T> {
+        let mut contributions: Vec<Vec<(&T, f64)>> = vec![Vec::new(); self.cells()];
+        for (value, weights) in values.iter().zip(&self.weights) {
+            for (cell, weight) in weights {
+                if *weight > 0.0 {
+                    contributions[*cell].push((value, *weight));
+                }
+            }
+        }
+
+        let mut grid = self.grid();
+        for (voxel, mut samples) in grid.cells.iter_mut().zip(contributions) {
+            let total: f64 = samples.iter().map(|(_, weight)| weight).sum();
+            if total > 0.0 {
+                for (_, weight) in samples.iter_mut() {
+                    *weight /= total;
+                }
+                *voxel = T::blend(&samples);
+            }
+        }
+
+        grid
+    }
+
+    fn cells(&self) -> usize {
+        self.dims[0] * self.dims[1] * self.dims[2]
+    }
+
+    fn index(&self, [x, y, z]: [usize; 3]) -> usize {
+        (z * self.dims[1] + y) * self.dims[0] + x
+    }
+
+    /// The eight voxels around `coordinate` and their weights, skipping ones that don't count
+    fn trilinear(&self, coordinate: [f64; 3]) -> Vec<(usize, f64)> {
+        let low = [0, 1, 2]
+            .map(|axis| (coordinate[axis].floor() as usize).min(self.dims[axis].saturating_sub(2)));
+        let fraction = [0, 1, 2].map(|axis| coordinate[axis] - low[axis] as f64);
+
+        let mut weights = Vec::with_capacity(8);
+        for corner in 0..8 {
+            let mut cell = low;
+            let mut weight = 1.0;
+            for axis in 0..3 {
+                if corner & (1 << axis) == 0 {
+                    weight *= 1.0 - fraction[axis];
+                } else {
+                    cell[axis] += 1;
+                    weight *= fraction[axis];
+                }
+            }
+
+            if weight > 0.0
+                && cell[0] < self.dims[0]
+                && cell[1] < self.dims[1]
+                && cell[2] < self.dims[2]
+            {
+                weights.push((self.index(cell), weight));
+            }
+        }
+
+        weights
+    }
+}
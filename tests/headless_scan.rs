@@ -0,0 +1,323 @@
+//! Runs whole scans against virtual strips and cameras, with no controller or display attached
+
+#![cfg(feature = "scan")]
+
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+};
+
+use image::{Rgb, RgbImage};
+use opencv::core::Mat;
+use svled::{
+    Config, ManagerData,
+    frame_source::{FrameSource, ImageDirectory, SyntheticFrames},
+    import::Layout,
+    led_manager::VirtualStrip,
+    ledmap::{LedMap, LedMapMetadata, LedPosition, LedStatus},
+    scan,
+    scan_ui::HeadlessUi,
+    simulate::{self, Simulator},
+    utils,
+};
+
+/// Just enough of svled.toml for a streamlined scan with two cameras and nothing to send commands to
+const CONFIG: &str = r#"
+num_led = 8
+num_strips = 1
+
+[communication]
+communication_mode = 2
+host = "127.0.0.1"
+port = 8888
+baud_rate = 921600
+serial_port_paths = []
+
+[recording]
+record_data = false
+record_esp_data = false
+unity_controls_recording = false
+record_data_file = "record_data.vled"
+record_esp_data_file = "esp_data.bvled"
+
+[camera]
+multi_camera = true
+camera_index_1 = "0"
+camera_index_2 = "1"
+
+[scan]
+scan_mode = 0
+color_bright = 255
+
+[unity_options]
+unity_ip = "127.0.0.1"
+scale = 1.0
+
+[advanced.camera]
+no_video = true
+no_background_frame_consumer = true
+
+[advanced.misc]
+no_controller = true
+"#;
+
+const FRAME_SIZE: (i32, i32) = (160, 120);
+
+/// An empty directory for one test, so tests running at the same time don't share files
+fn scratch_dir(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("svled-{name}-{}", process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+/// Loads `CONFIG` for `num_led` LEDs, with every command going to the returned strip
+fn setup(directory: &Path, num_led: usize) -> (Config, Arc<Mutex<ManagerData>>, Arc<VirtualStrip>) {
+    let config_path = directory.join("svled.toml");
+    fs::write(&config_path, CONFIG).unwrap();
+
+    let (mut manager, _, mut config) = utils::load_validate_conf(&config_path);
+    config.num_led = num_led as u32;
+    manager.config.num_led = num_led as u32;
+
+    let strip = Arc::new(VirtualStrip::new(num_led));
+    manager.io.led_sink = Some(strip.clone());
+
+    (config, Arc::new(Mutex::new(manager)), strip)
+}
+
+/// A raw scan of a strip running diagonally through the container, in pixels of both cameras. Neighbours are
+/// evenly spaced so the continuity check leaves them alone.
+fn pixel_layout() -> LedMap {
+    let mut layout = LedMap::new(8, LedMapMetadata::default());
+    for (index, led) in layout.leds.iter_mut().enumerate() {
+        let step = index as f64;
+        *led = LedPosition {
+            x: 30.0 + step * 12.0,
+            y: 30.0 + step * 8.0,
+            z: 100.0 - step * 10.0,
+            status: LedStatus::SuccessZ,
+            confidence: 1.0,
+        };
+    }
+    layout
+}
+
+/// Checks that `scan` found every LED of `layout`, once both have been through post processing
+fn assert_found(scan: &LedMap, layout: &LedMap) {
+    let mut expected = layout.clone();
+    scan::post_process(&mut expected, layout.len() as u32);
+
+    assert_eq!(scan.len(), expected.len());
+    for (index, (found, truth)) in scan.leds.iter().zip(&expected.leds).enumerate() {
+        assert_eq!(
+            found.status,
+            LedStatus::SuccessZ,
+            "LED {index} was not found"
+        );
+        for (axis, found, truth) in [
+            ("x", found.x, truth.x),
+            ("y", found.y, truth.y),
+            ("z", found.z, truth.z),
+        ] {
+            assert!(
+                (found - truth).abs() <= 1.0,
+                "LED {index} was found at {axis} = {found}, expected {truth}"
+            );
+        }
+    }
+}
+
+#[test]
+fn scans_synthetic_frames() {
+    let directory = scratch_dir("synthetic");
+    let layout = pixel_layout();
+    let (config, manager, strip) = setup(&directory, layout.len());
+
+    let opener = |second_cam: bool| -> Result<Box<dyn FrameSource>, Box<dyn Error>> {
+        Ok(Box::new(SyntheticFrames::from_scan(
+            Arc::clone(&strip),
+            &layout,
+            FRAME_SIZE,
+            second_cam,
+        )))
+    };
+    let scan = scan::scan_with(config, &manager, true, None, &opener, &HeadlessUi).unwrap();
+
+    assert_found(&scan, &layout);
+    // Everything is switched off again once the scan is done
+    assert!(strip.lit().is_empty());
+
+    fs::remove_dir_all(directory).unwrap();
+}
+
+/// Passes frames through, noting which LEDs were lit whenever one was read
+struct Watched {
+    frames: Box<dyn FrameSource>,
+    strip: Arc<VirtualStrip>,
+    lit: Arc<Mutex<Vec<Vec<usize>>>>,
+}
+
+impl FrameSource for Watched {
+    fn read(&mut self, frame: &mut Mat) -> opencv::Result<bool> {
+        let lit = self
+            .strip
+            .lit()
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        self.lit.lock().unwrap().push(lit);
+        self.frames.read(frame)
+    }
+
+    fn is_opened(&self) -> opencv::Result<bool> {
+        self.frames.is_opened()
+    }
+
+    fn frame_size(&self) -> opencv::Result<(i32, i32)> {
+        self.frames.frame_size()
+    }
+}
+
+#[test]
+fn scans_structured_patterns() {
+    let directory = scratch_dir("structured");
+    let layout = pixel_layout();
+    let (mut config, manager, strip) = setup(&directory, layout.len());
+    config.scan.scan_mode = 2;
+    manager.lock().unwrap().config.scan_mode = 2;
+
+    let lit = Arc::new(Mutex::new(Vec::new()));
+    let opener = |second_cam: bool| -> Result<Box<dyn FrameSource>, Box<dyn Error>> {
+        Ok(Box::new(Watched {
+            frames: Box::new(SyntheticFrames::from_scan(
+                Arc::clone(&strip),
+                &layout,
+                FRAME_SIZE,
+                second_cam,
+            )),
+            strip: Arc::clone(&strip),
+            lit: Arc::clone(&lit),
+        }))
+    };
+    let scan = scan::scan_with(config, &manager, true, None, &opener, &HeadlessUi).unwrap();
+
+    assert_found(&scan, &layout);
+    let lit = lit.lock().unwrap();
+    // The patterns light several LEDs at once
+    assert!(
+        lit.iter()
+            .any(|leds| leds.len() > 1 && leds.len() < layout.len())
+    );
+    // and find every LED, so none are lit on their own afterwards, apart from the one used to measure brightness
+    let alone: HashSet<usize> = lit
+        .iter()
+        .filter(|leds| leds.len() == 1)
+        .map(|leds| leds[0])
+        .collect();
+    assert!(
+        alone.len() <= 1,
+        "LEDs {alone:?} were scanned one at a time"
+    );
+
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn scans_image_directory() {
+    let directory = scratch_dir("images");
+    let layout = pixel_layout();
+    let (config, manager, strip) = setup(&directory, layout.len());
+
+    // One frame per LED for each camera, the second camera seeing z and y
+    let (width, height) = (FRAME_SIZE.0 as u32, FRAME_SIZE.1 as u32);
+    for (camera, second_cam) in [("camera1", false), ("camera2", true)] {
+        let frames = directory.join(camera);
+        fs::create_dir_all(&frames).unwrap();
+        RgbImage::new(width, height)
+            .save(frames.join("dark.png"))
+            .unwrap();
+
+        for (index, led) in layout.leds.iter().enumerate() {
+            let (x, y) = if second_cam {
+                (led.z, led.y)
+            } else {
+                (led.x, led.y)
+            };
+            let frame = RgbImage::from_fn(width, height, |px, py| {
+                let distance = (px as f64 - x).hypot(py as f64 - y);
+                let value = (255.0 * (1.0 - distance / 6.0)).max(0.0) as u8;
+                Rgb([value, value, value])
+            });
+            frame.save(frames.join(format!("{index}.png"))).unwrap();
+        }
+    }
+
+    let opener = |second_cam: bool| -> Result<Box<dyn FrameSource>, Box<dyn Error>> {
+        let camera = if second_cam { "camera2" } else { "camera1" };
+        Ok(Box::new(ImageDirectory::new(
+            &directory.join(camera),
+            Arc::clone(&strip),
+        )?))
+    };
+    let scan = scan::scan_with(config, &manager, true, None, &opener, &HeadlessUi).unwrap();
+
+    assert_found(&scan, &layout);
+
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn simulated_scan_matches_layout() {
+    let directory = scratch_dir("simulated");
+    let layout = Layout::Helix {
+        num_led: 24,
+        radius: 60.0,
+        leds_per_turn: 12.0,
+        pitch: 60.0,
+    }
+    .to_led_map("mm");
+    let (mut config, manager, strip) = setup(&directory, layout.len());
+
+    let cameras = simulate::camera_rig(&layout, 60.0, (640, 480), None, false, false).unwrap();
+
+    // Describe the virtual cameras, so the scan comes back in mm and can be compared without any scaling
+    let bounds = layout.bounds().unwrap();
+    let perspective = &mut config.advanced.perspective;
+    perspective.focal_length = Some(cameras[0].focal_length());
+    perspective.camera_distance = Some(cameras[0].position[2] - bounds.max[2]);
+    perspective.camera2_distance = Some(cameras[1].position[0] - bounds.max[0]);
+    perspective.container_size = Some(bounds.size().to_vec());
+    config.advanced.transform.adjust_after_scan = Some(true);
+
+    let opener = |second_cam: bool| -> Result<Box<dyn FrameSource>, Box<dyn Error>> {
+        Ok(Box::new(Simulator::new(
+            Arc::clone(&strip),
+            &layout,
+            cameras[second_cam as usize].clone(),
+            5.0,
+            false,
+        )))
+    };
+    let scan = scan::scan_with(config, &manager, true, None, &opener, &HeadlessUi).unwrap();
+
+    assert_eq!(scan.metadata.units, "mm");
+    let accuracy = simulate::measure_accuracy(&scan, &layout).unwrap();
+    assert!(
+        accuracy.missed.is_empty(),
+        "Missed LEDs {:?}",
+        accuracy.missed
+    );
+    assert!(
+        accuracy.rms_error() < 2.0,
+        "RMS error of {:.2} mm, the worst LEDs are {:?}",
+        accuracy.rms_error(),
+        accuracy.worst(3)
+    );
+
+    fs::remove_dir_all(directory).unwrap();
+}